@@ -1,20 +1,45 @@
+use std::{
+    collections::VecDeque,
+    env,
+    error::Error,
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::{ Arc, Mutex, RwLock },
+    thread::{ sleep, spawn },
+    time::{ Duration, Instant },
+};
 use solana_sdk::{
-    signature::{ Keypair, read_keypair_file, Signer, Signature },
+    signature::{ Keypair, keypair_from_seed, read_keypair_file, Signer, Signature },
+    bs58,
     commitment_config::CommitmentConfig,
     pubkey::{ Pubkey },
+    account::Account as SdkAccount,
     transaction::{ Transaction },
+    packet::PACKET_DATA_SIZE,
+    system_instruction,
     hash::Hash
 };
 use solana_client::{
     rpc_client::RpcClient,
     rpc_config::{
         RpcSendTransactionConfig,
+        RpcSimulateTransactionConfig,
+        RpcSimulateTransactionAccountsConfig,
     },
     client_error::{ Result as ClientResult, ClientError, ClientErrorKind },
+    rpc_request::{ RpcError, RpcRequest },
+    rpc_response::RpcSimulateTransactionResult,
 };
 use solana_account_decoder::{
     parse_token::UiTokenAmount,
+    UiAccountEncoding,
 };
+use solana_transaction_status::TransactionStatus;
+use serde::Deserialize;
+use log::{ info, warn, error };
+use serde_json::json;
+use rayon::{ prelude::*, ThreadPoolBuilder };
 use spl_token::{
     solana_program::{
         instruction::{ Instruction },
@@ -23,64 +48,318 @@ use spl_token::{
 
 use crate::*;
 
+// ms between two signature status polls
+const CONFIRM_POLL_INTERVAL: u64 = 400;
+// seconds a webhook may take to accept a notification
+const NOTIFY_TIMEOUT: u64 = 10;
+// accounts a legacy transaction may reference
+const MAX_TX_ACCOUNTS: usize = 64;
+// pyth price account: magic number, account type of price accounts, trading status
+const PYTH_MAGIC: u32 = 0xa1b2c3d4;
+const PYTH_PRICE_ACCOUNT: u32 = 3;
+const PYTH_STATUS_TRADING: u32 = 1;
+
 // Structs
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PrioritizationFee {
+    prioritization_fee: u64,
+}
+
+/* Outcomes of the recent requests to the active read endpoint, to fail over to the next one
+ * once too many of them failed */
+struct RpcHealth {
+    urls:        Vec<String>, // the configured endpoint first, then the backups
+    active:      usize,
+    outcomes:    VecDeque<bool>, // most recent last, false if the request failed
+    consecutive: u32, // failures in a row
+    latency:     f64, // ms, moving average over the successful requests
+    failovers:   usize,
+}
+
 pub struct Communication {
-    pub rpc_client: RpcClient,
+    rpc:            RwLock<Arc<RpcClient>>, // read endpoint, replaced on failover
+    health:         Mutex<RpcHealth>,
+    failover_error_rate: f64,
+    failover_window:     usize,
     pub wallet:     Keypair,
+    pub fee_payer:  Option<Keypair>, // pays fees and tips if set, the wallet otherwise
+    pub bundle_client: Option<RpcClient>, // jito block engine, if sending bundles
+    pub webhook:    Option<( reqwest::blocking::Client, String )>, // client and url
+
+    pub retry_attempts: u32,
+    pub retry_delay:    u64, // ms, doubled after each failed attempt
+    pub concurrency:    usize, // bulk account queries in parallel
+    pub skip_preflight: bool,
+
+    pub commitment:     Option<CommitmentConfig>, // as configured, None means the defaults
 }
 
 impl Communication {
-    pub fn init( cluster_url: &String, wallet_path: &String ) -> Self {
+    /* The backup endpoints only apply to the read endpoint, cluster_url */
+    pub fn init( cluster_url: &String, crcy_cfg: &CurrencyConfig, config: &Config ) -> Self {
         let rpc = RpcClient::new_with_commitment(
-            cluster_url.to_string( ), CommitmentConfig::confirmed( ) );
-        let wallet = read_keypair_file( &*shellexpand::tilde( wallet_path ) )
-            .expect( "Need keypair file to print money." );
+            cluster_url.to_string( ), config.commitment.unwrap_or( CommitmentConfig::confirmed( ) ) );
+        let mut urls = vec![ cluster_url.to_string( ) ];
+        if *cluster_url == config.cluster_url {
+            urls.extend( config.backup_cluster_urls.iter( ).cloned( ) );
+        }
+        let wallet = Self::read_wallet( crcy_cfg ).expect( "Need keypair to print money." );
+        let fee_payer = crcy_cfg.fee_payer_path.as_ref( ).map( |path| {
+            let path = shellexpand::tilde( path );
+            read_keypair_file( &*path ).unwrap_or_else( |err| {
+                error!( "Cannot read fee payer keypair file {}: {}", path, err );
+                std::process::exit( 1 )
+            } )
+        } );
+
+        let bundle_client = match config.send_mode {
+            SendMode::Jito => { config.jito_url.as_ref( ).map( |url| RpcClient::new( url.to_string( ) ) ) },
+            SendMode::Rpc => { None }
+        };
 
         Self {
-            rpc_client: rpc,
-            wallet:     wallet
+            rpc:            RwLock::new( Arc::new( rpc ) ),
+            health:         Mutex::new( RpcHealth {
+                urls:        urls,
+                active:      0,
+                outcomes:    VecDeque::new( ),
+                consecutive: 0,
+                latency:     0.0,
+                failovers:   0,
+            } ),
+            failover_error_rate: config.failover_error_rate,
+            failover_window:     config.failover_window,
+            wallet:         wallet,
+            fee_payer:      fee_payer,
+            bundle_client:  bundle_client,
+            webhook:        config.webhook_url.as_ref( ).map( |url| {
+                ( reqwest::blocking::Client::new( ), url.to_string( ) )
+            } ),
+            retry_attempts: config.rpc_retry_attempts,
+            retry_delay:    config.rpc_retry_delay,
+            concurrency:    config.rpc_concurrency,
+            skip_preflight: config.skip_preflight,
+            commitment:     config.commitment,
         }
     }
 
-    pub fn get_blockhash( &self ) -> Hash {
+    /* Reads the wallet from the configured environment variable if it is populated, from the
+     * configured keypair file otherwise. */
+    fn read_wallet( crcy_cfg: &CurrencyConfig ) -> Result<Keypair, Box<dyn Error>> {
+        if let Some( var ) = &crcy_cfg.wallet_env {
+            match env::var( var ) {
+                Ok( val ) if !val.trim( ).is_empty( ) => {
+                    let val = val.trim( );
+                    let bytes: Vec<u8> = if val.starts_with( '[' ) {
+                        serde_json::from_str( val )?
+                    } else {
+                        bs58::decode( val ).into_vec( )?
+                    };
+                    return Ok( Keypair::from_bytes( &bytes )? );
+                },
+                _ => {
+                    if crcy_cfg.wallet_path.is_empty( ) {
+                        return Err( format!( "Environment variable {} holds no keypair and no wallet_path is configured",
+                                             var ).into( ) );
+                    }
+                }
+            }
+        }
+
+        let path = shellexpand::tilde( &crcy_cfg.wallet_path );
+        read_keypair_file( &*path ).map_err( |err| {
+            format!( "Cannot read keypair file {}: {}", path, err ).into( )
+        } )
+    }
+
+    /* The account paying for transactions */
+    pub fn payer( &self ) -> &Keypair {
+        self.fee_payer.as_ref( ).unwrap_or( &self.wallet )
+    }
+
+    /* The given signers plus the fee payer, if it's not among them already */
+    fn with_payer<'a>( &'a self, signers: &Vec<&'a Keypair> ) -> Vec<&'a Keypair> {
+        let mut res = signers.clone( );
+        let payer = self.payer( );
+        if !res.iter( ).any( |s| s.pubkey( ) == payer.pubkey( ) ) {
+            res.push( payer );
+        }
+        res
+    }
+
+    fn is_transient( err: &ClientError ) -> bool {
+        match &err.kind {
+            ClientErrorKind::Io( _ )
+            | ClientErrorKind::Reqwest( _ )
+            | ClientErrorKind::RpcError( RpcError::RpcRequestError( _ ) ) => { true },
+            // node is behind / unhealthy
+            ClientErrorKind::RpcError( RpcError::RpcResponseError{ code: -32005, .. } ) => { true },
+            _ => { false }
+        }
+    }
+
+    /* The client of the active read endpoint */
+    pub fn rpc_client( &self ) -> Arc<RpcClient> {
+        self.rpc.read( ).unwrap( ).clone( )
+    }
+
+    /* The url of the active read endpoint */
+    pub fn cluster_url( &self ) -> String {
+        let health = self.health.lock( ).unwrap( );
+        health.urls[ health.active ].clone( )
+    }
+
+    /* How often the read endpoint was switched so far */
+    pub fn failovers( &self ) -> usize {
+        self.health.lock( ).unwrap( ).failovers
+    }
+
+    /* Tracks the outcome of a request to the active endpoint and switches to the next one
+     * once more than failover_error_rate of the last failover_window requests failed. */
+    fn record_outcome( &self, ok: bool, elapsed: Duration ) {
+        let mut health = self.health.lock( ).unwrap( );
+        if ok {
+            health.consecutive = 0;
+            health.latency = 0.9 * health.latency + 0.1 * elapsed.as_millis( ) as f64;
+        } else {
+            health.consecutive = health.consecutive + 1;
+        }
+        health.outcomes.push_back( ok );
+        if health.outcomes.len( ) > self.failover_window {
+            health.outcomes.pop_front( );
+        }
+
+        if health.urls.len( ) < 2 || health.outcomes.len( ) < self.failover_window {
+            return;
+        }
+        let failures = health.outcomes.iter( ).filter( |ok| !**ok ).count( );
+        if failures as f64 <= self.failover_error_rate * self.failover_window as f64 {
+            return;
+        }
+
+        let from = health.urls[ health.active ].clone( );
+        health.active = ( health.active + 1 ) % health.urls.len( );
+        let to = health.urls[ health.active ].clone( );
+        warn!( "RPC endpoint {} failed {} of the last {} requests ({} in a row, {:.0} ms average latency), failing over to {}.",
+               from, failures, self.failover_window, health.consecutive, health.latency, to );
+        *self.rpc.write( ).unwrap( ) = Arc::new( RpcClient::new_with_commitment(
+            to, self.commitment.unwrap_or( CommitmentConfig::confirmed( ) ) ) );
+        health.outcomes.clear( );
+        health.consecutive = 0;
+        health.latency = 0.0;
+        health.failovers = health.failovers + 1;
+    }
+
+    /* Runs the given rpc request, retrying with exponential backoff on transient errors */
+    pub fn with_retries<T, F>( &self, request: F ) -> ClientResult<T>
+        where F: Fn( ) -> ClientResult<T> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        loop {
+            let start = Instant::now( );
+            let res = request( );
+            // only transient errors tell something about the endpoint
+            self.record_outcome( res.as_ref( ).map_or_else( |err| !Self::is_transient( err ), |_| true ),
+                                 start.elapsed( ) );
+            match res {
+                Ok( res ) => { return Ok( res ); },
+                Err( err ) => {
+                    if attempt >= self.retry_attempts || !Self::is_transient( &err ) {
+                        return Err( err );
+                    }
+                    warn!( "RPC request failed (attempt {}/{}): {:?}", attempt,
+                           self.retry_attempts, err );
+                    sleep( Duration::from_millis( delay ) );
+                    delay = delay * 2;
+                    attempt = attempt + 1;
+                }
+            }
+        }
+    }
+
+    pub fn get_blockhash( &self ) -> ClientResult<Hash> {
         // let ( hash, _ ) = self.rpc_client.get_recent_blockhash_with_commitment(
         //    CommitmentConfig::finalized( ) )?.value;
-        let ( hash, _ ) = self.rpc_client.get_latest_blockhash_with_commitment(
-            CommitmentConfig::finalized( ) ).unwrap( );
-        hash
+        let ( hash, _ ) = self.with_retries( || {
+            self.rpc_client( ).get_latest_blockhash_with_commitment(
+                self.commitment.unwrap_or( CommitmentConfig::finalized( ) ) )
+        } )?;
+        Ok( hash )
+    }
+
+    /* Returns the given percentile of the compute unit prices recently paid to lock the given
+     * accounts. The rpc client doesn't know this request yet, hence it is sent manually. */
+    pub fn estimate_priority_fee( &self, accounts: &Vec<Pubkey>, percentile: f64 ) -> ClientResult<u64> {
+        let keys: Vec<String> = accounts.iter( ).map( |a| a.to_string( ) ).collect( );
+        let mut fees: Vec<u64> = self.with_retries( || {
+            self.rpc_client( ).send::<Vec<PrioritizationFee>>(
+                RpcRequest::Custom{ method: "getRecentPrioritizationFees" }, json!( [ keys ] ) )
+        } )?.iter( ).map( |f| f.prioritization_fee ).collect( );
+
+        if fees.is_empty( ) {
+            return Ok( 0 );
+        }
+        fees.sort( );
+        let idx = ( ( fees.len( ) - 1 ) as f64 * percentile.max( 0.0 ).min( 1.0 ) ).round( ) as usize;
+        Ok( fees[ idx ] )
+    }
+
+    /* Checks that a transaction built from the given instructions fits into a packet and
+     * doesn't reference too many accounts. */
+    pub fn check_transaction_limits( &self, instructions: &Vec<Instruction> ) -> Result<(), String> {
+        let tx = Transaction::new_with_payer( instructions, Some( &self.payer( ).pubkey( ) ) );
+
+        let accounts = tx.message.account_keys.len( );
+        if accounts > MAX_TX_ACCOUNTS {
+            return Err( format!( "{} accounts exceed the limit of {}", accounts, MAX_TX_ACCOUNTS ) );
+        }
+
+        // signatures are prefixed by their (short vec encoded) count
+        let sigs = tx.signatures.len( );
+        let size = if sigs < 0x80 { 1 } else { 2 } + 64 * sigs + tx.message_data( ).len( );
+        if size > PACKET_DATA_SIZE {
+            return Err( format!( "{} bytes exceed the limit of {}", size, PACKET_DATA_SIZE ) );
+        }
+        Ok( ( ) )
     }
 
     pub fn send_transaction( &self,
                          instructions: &Vec<Instruction>,
                          signers: &Vec<&Keypair>,
                          simulate: bool,
-                         recent_blockhash: Hash ) -> ClientResult<Signature> {
+                         recent_blockhash: Hash,
+                         log_path: Option<&Path> ) -> ClientResult<Signature> {
         // create transaction
         let tx = Transaction::new_signed_with_payer(
             instructions,
-            Some( &self.wallet.pubkey( ) ), // payer
-            signers,
+            Some( &self.payer( ).pubkey( ) ), // payer
+            &self.with_payer( signers ),
             recent_blockhash
         );
 
         let trans_config = RpcSendTransactionConfig {
-            skip_preflight: true,
-            // skip_preflight: false, // desquid
+            skip_preflight: self.skip_preflight,
             .. RpcSendTransactionConfig::default( )
         };
 
         if simulate {
-            println!( "Simulating transaction." );
-            let res = self.rpc_client.simulate_transaction( &tx )?;
+            info!( "Simulating transaction." );
+            let res = self.rpc_client( ).simulate_transaction( &tx )?;
 
             if let Some( logs ) = res.value.logs {
-                for l in logs {
-                    println!( "{}", l );
+                match log_path {
+                    Some( path ) => { write_logs( path, &logs ); },
+                    None => {
+                        for l in logs {
+                            info!( "{}", l );
+                        }
+                    }
                 }
             }
 
             if let Some( err ) = res.value.err {
-                println!( "{:?}", err );
+                warn!( "{:?}", err );
                 return Err( ClientError::from( err ) );
             }
 
@@ -88,67 +367,343 @@ impl Communication {
                 request: None } );
         }
 
-        let signature = self.rpc_client.send_transaction_with_config( &tx, trans_config )?;
+        let signature = self.rpc_client( ).send_transaction_with_config( &tx, trans_config )?;
         // let now = SystemTime::now( ).duration_since( UNIX_EPOCH ).unwrap( );
         // println!( "{:?}: TX sent, signature: {:?}", now, signature );
-        eprintln!( "TX sent, signature: {:?}", signature );
+        info!( "TX sent, signature: {:?}", signature );
 
         Ok( signature )
     }
 
-    pub fn get_current_balance_for_currency( &self, currency: &Currency ) -> u64 {
+    /* Sends the transaction as a single transaction bundle to the jito block engine, tipping
+     * the given account from the fee payer within the same transaction. */
+    pub fn send_bundle( &self,
+                        instructions: &Vec<Instruction>,
+                        signers: &Vec<&Keypair>,
+                        recent_blockhash: Hash,
+                        tip_account: &Pubkey,
+                        tip: u64 ) -> ClientResult<Signature> {
+        let bundle_client = match &self.bundle_client {
+            Some( client ) => { client },
+            None => {
+                return Err( ClientError{ kind: ClientErrorKind::Custom( "No block engine configured".to_string( ) ),
+                    request: None } );
+            }
+        };
+
+        let mut instructions = instructions.clone( );
+        instructions.push( system_instruction::transfer( &self.payer( ).pubkey( ), tip_account, tip ) );
+        let tx = Transaction::new_signed_with_payer(
+            &instructions,
+            Some( &self.payer( ).pubkey( ) ), // payer
+            &self.with_payer( signers ),
+            recent_blockhash
+        );
+        let serialized = bincode::serialize( &tx ).map_err( |err| {
+            ClientError{ kind: ClientErrorKind::Custom( err.to_string( ) ), request: None }
+        } )?;
+
+        let bundle_id: String = bundle_client.send(
+            RpcRequest::Custom{ method: "sendBundle" }, json!( [ [ bs58::encode( serialized ).into_string( ) ] ] ) )?;
+        info!( "Bundle {} sent, signature: {:?}", bundle_id, tx.signatures[ 0 ] );
+
+        Ok( tx.signatures[ 0 ] )
+    }
+
+    /* Posts the message to the webhook, if any, without waiting for it; failures are only
+     * logged. */
+    pub fn notify( &self, message: &serde_json::Value ) {
+        let ( client, url ) = match &self.webhook {
+            Some( ( client, url ) ) => { ( client.clone( ), url.clone( ) ) },
+            None => { return; }
+        };
+        let message = message.clone( );
+        spawn( move || {
+            let res = client.post( &url ).json( &message ).timeout( Duration::from_secs( NOTIFY_TIMEOUT ) )
+                .send( ).and_then( |r| r.error_for_status( ) );
+            if let Err( err ) = res {
+                warn!( "Notifying webhook failed: {:?}", err );
+            }
+        } );
+    }
+
+    /* Round-trip times of fetching a blockhash, sorted ascending */
+    pub fn measure_latency( &self, url: &str, samples: usize ) -> ClientResult<Vec<Duration>> {
+        // a client of its own, so failover doesn't switch endpoints mid measurement
+        let rpc = RpcClient::new( url.to_string( ) );
+        let commitment = self.commitment.unwrap_or( CommitmentConfig::finalized( ) );
+        let mut res = Vec::with_capacity( samples );
+        for _ in 0 .. samples {
+            let start = Instant::now( );
+            rpc.get_latest_blockhash_with_commitment( commitment )?;
+            res.push( start.elapsed( ) );
+        }
+        res.sort( );
+        Ok( res )
+    }
+
+    /* The configured read endpoint followed by its backups */
+    pub fn cluster_urls( &self ) -> Vec<String> {
+        self.health.lock( ).unwrap( ).urls.clone( )
+    }
+
+    /* Polls the status of the given signature until it is confirmed or the timeout passes.
+     * Returns None if the transaction did not land in time. */
+    pub fn confirm_signature( &self, signature: &Signature,
+                              timeout: Duration ) -> ClientResult<Option<TransactionStatus>> {
+        let start = Instant::now( );
+        loop {
+            let statuses = self.with_retries( || {
+                self.rpc_client( ).get_signature_statuses( &[ *signature ] )
+            } )?.value;
+
+            if let Some( Some( status ) ) = statuses.into_iter( ).next( ) {
+                if status.err.is_some( )
+                    || status.satisfies_commitment( CommitmentConfig::confirmed( ) ) {
+                    return Ok( Some( status ) );
+                }
+            }
+
+            if start.elapsed( ) >= timeout {
+                return Ok( None );
+            }
+            sleep( Duration::from_millis( CONFIRM_POLL_INTERVAL ) );
+        }
+    }
+
+    pub fn simulate_transaction( &self,
+                                 instructions: &Vec<Instruction>,
+                                 signers: &Vec<&Keypair>,
+                                 recent_blockhash: Hash ) -> ClientResult<RpcSimulateTransactionResult> {
+        let tx = Transaction::new_signed_with_payer(
+            instructions,
+            Some( &self.payer( ).pubkey( ) ), // payer
+            &self.with_payer( signers ),
+            recent_blockhash
+        );
+
+        // the hash may have expired during a long run of simulations, e.g. by profile
+        let sim_config = RpcSimulateTransactionConfig {
+            commitment: self.commitment,
+            replace_recent_blockhash: true,
+            .. RpcSimulateTransactionConfig::default( )
+        };
+
+        Ok( self.rpc_client( ).simulate_transaction_with_config( &tx, sim_config )?.value )
+    }
+
+    /* Simulates the transaction and additionally returns the state of the given accounts
+     * after it, in the same order. */
+    pub fn simulate_transaction_with_accounts( &self,
+                                               instructions: &Vec<Instruction>,
+                                               signers: &Vec<&Keypair>,
+                                               recent_blockhash: Hash,
+                                               accounts: &Vec<Pubkey> ) -> ClientResult<RpcSimulateTransactionResult> {
+        let tx = Transaction::new_signed_with_payer(
+            instructions,
+            Some( &self.payer( ).pubkey( ) ), // payer
+            &self.with_payer( signers ),
+            recent_blockhash
+        );
+
+        let sim_config = RpcSimulateTransactionConfig {
+            commitment: self.commitment,
+            accounts: Some( RpcSimulateTransactionAccountsConfig {
+                encoding: Some( UiAccountEncoding::Base64 ),
+                addresses: accounts.iter( ).map( |a| a.to_string( ) ).collect( ),
+            } ),
+            .. RpcSimulateTransactionConfig::default( )
+        };
+
+        Ok( self.rpc_client( ).simulate_transaction_with_config( &tx, sim_config )?.value )
+    }
+
+    pub fn get_current_balance_for_currency( &self, currency: &Currency ) -> ClientResult<u64> {
         let (toys_in_ui, decs) =
-            self.get_current_balance_for_pubkey_with_commitment(
+            match self.get_current_balance_for_pubkey_with_commitment(
                 &currency.account,
-                CommitmentConfig::confirmed( ) );
+                CommitmentConfig::confirmed( ) ) {
+                Ok( balance ) => { balance },
+                Err( err ) => {
+                    // a token account that wasn't created yet holds nothing
+                    let acc = self.with_retries( || {
+                        self.rpc_client( ).get_account_with_commitment( &currency.account,
+                                                                     CommitmentConfig::confirmed( ) )
+                    } )?;
+                    if acc.value.is_none( ) {
+                        warn!( "Token account {} of {} does not exist, assuming a balance of 0.",
+                               currency.account, currency.name );
+                        return Ok( 0 );
+                    }
+                    return Err( err );
+                }
+            };
 
-        ( toys_in_ui * POWERS_OF_TEN[ decs as usize ] ) as u64
+        Ok( ( toys_in_ui * pow10( decs as usize ) ) as u64 )
 
         // return Self::get_current_balance_for_pubkey( &self.rpc_client, &self.wallet.pubkey( ) );
     }
 
-    pub fn get_current_balance( &self, config: &Config, currencies: &Vec<Currency> ) -> u64 {
-        self.get_current_balance_for_currency( &currencies[ config.start_currency ] )
+    /* Balance of the given start currency available for gambling */
+    pub fn get_current_balance( &self, config: &Config, currencies: &Vec<Currency>,
+                                start: usize ) -> ClientResult<u64> {
+        if config.wrap_sol && currencies[ start ].is_native( ) {
+            // wrapped on demand, so the lamports are what we can gamble
+            return self.get_current_balance_for_pubkey( &self.wallet.pubkey( ) );
+        }
+        self.get_current_balance_for_currency( &currencies[ start ] )
     }
 
-    pub fn get_current_balance_for_pubkey( &self, pubkey: &Pubkey ) -> u64 {
-        match self.rpc_client.get_balance( pubkey ) {
-            Err( err ) => {
-                eprintln!( "{:?}", err );
-                std::process::exit( 1 )
+    /* Prints the wallet's SOL balance and the balance of every configured currency */
+    pub fn dump_balances( &self, currencies: &Vec<Currency> ) {
+        match self.get_current_balance_for_pubkey( &self.wallet.pubkey( ) ) {
+            Ok( lamports ) => {
+                println!( "SOL (wallet): {} ({} lamports)", lamports as f64 / pow10( 9 ),
+                          lamports );
             },
-            Ok( balance ) => {
-                balance
+            Err( err ) => {
+                println!( "SOL (wallet): Error: {:?}", err );
             }
         }
+        if let Some( fee_payer ) = &self.fee_payer {
+            match self.get_current_balance_for_pubkey( &fee_payer.pubkey( ) ) {
+                Ok( lamports ) => {
+                    println!( "SOL (fee payer): {} ({} lamports)", lamports as f64 / pow10( 9 ),
+                              lamports );
+                },
+                Err( err ) => {
+                    println!( "SOL (fee payer): Error: {:?}", err );
+                }
+            }
+        }
+
+        for c in currencies {
+            match self.get_current_balance_for_pubkey_with_commitment( &c.account,
+                                                                       CommitmentConfig::confirmed( ) ) {
+                Ok( ( ui_amount, decs ) ) => {
+                    println!( "{}: {} ({} toys)", c.name, ui_amount,
+                              ( ui_amount * pow10( decs as usize ) ) as u64 );
+                },
+                Err( err ) => {
+                    println!( "{}: Error: {:?}", c.name, err );
+                }
+            }
+        }
+    }
+
+    /* Reads the aggregate price of a pyth price account */
+    pub fn get_oracle_price( &self, oracle: &Pubkey ) -> ClientResult<f64> {
+        let account = self.with_retries( || self.rpc_client( ).get_account( oracle ) )?;
+        parse_pyth_price( &account.data ).ok_or_else( || {
+            ClientError{ kind: ClientErrorKind::Custom( format!( "{} is no trading pyth price", oracle ) ),
+                request: None }
+        } )
+    }
+
+    /* Indices of the currencies whose token account doesn't exist (yet) */
+    pub fn missing_currency_accounts( &self, currencies: &Vec<Currency> ) -> ClientResult<Vec<usize>> {
+        let keys: Vec<Pubkey> = currencies.iter( ).map( |c| c.account ).collect( );
+        let accounts = self.accounts( &keys )?;
+        Ok( accounts.iter( ).enumerate( ).filter( |( _, a )| a.is_none( ) ).map( |( i, _ )| i ).collect( ) )
+    }
+
+    pub fn get_current_balance_for_pubkey( &self, pubkey: &Pubkey ) -> ClientResult<u64> {
+        self.with_retries( || self.rpc_client( ).get_balance( pubkey ) )
     }
 
     /* Returns SPL token balance */
     pub fn get_current_balance_for_pubkey_with_commitment(
-        &self, pubkey: &Pubkey, commitment: CommitmentConfig ) -> (f64, u8) {
-        match self.rpc_client.get_token_account_balance_with_commitment( pubkey, commitment ) {
-            Err( err ) => {
-                eprintln!( "{:?}", err );
-                std::process::exit( 1 )
+        &self, pubkey: &Pubkey, commitment: CommitmentConfig ) -> ClientResult<(f64, u8)> {
+        let val = self.with_retries( || {
+            self.rpc_client( ).get_token_account_balance_with_commitment( pubkey, commitment )
+        } )?;
+        match val.value {
+            UiTokenAmount{
+                ui_amount: Some( ui_amnt ),
+                decimals: decs,
+                ..
+            } => {
+                Ok( (ui_amnt, decs) )
             },
-            Ok( val ) => {
-                match val.value {
-                    UiTokenAmount{
-                        ui_amount: Some( ui_amnt ),
-                        decimals: decs,
-                        ..
-                    } => {
-                        (ui_amnt, decs)
-                    },
-                    _ => {
-                        eprintln!( "Reading token balance failed" );
-                        std::process::exit( 1 )
-                    }
-                }
+            _ => {
+                Err( ClientError{ kind: ClientErrorKind::Custom( "Reading token balance failed".to_string( ) ),
+                    request: None } )
             }
         }
     }
 
 }
 
+/* Writes the program logs of a simulation to the file, one per line */
+pub fn write_logs( path: &Path, logs: &Vec<String> ) {
+    let res = File::create( path ).and_then( |mut file| {
+        for l in logs {
+            writeln!( file, "{}", l )?;
+        }
+        Ok( ( ) )
+    } );
+    match res {
+        Ok( _ ) => { info!( "Wrote simulation logs to {}.", path.display( ) ); },
+        Err( err ) => { error!( "Writing simulation logs to {} failed: {:?}", path.display( ), err ); }
+    }
+}
+
+/* The extra signer some swap programs require for approvals; derived from the seed if
+ * given, so transactions can be reproduced byte by byte, a fresh key otherwise. */
+pub fn extra_signer( seed: Option<[ u8; 32 ]> ) -> Keypair {
+    match seed {
+        Some( seed ) => {
+            keypair_from_seed( &seed ).expect( "A 32 byte seed makes a keypair" )
+        },
+        None => { Keypair::new( ) }
+    }
+}
+
+/* Extracts the aggregate price from the data of a pyth (v2) price account, unless the
+ * price is not currently trading. */
+fn parse_pyth_price( data: &[ u8 ] ) -> Option<f64> {
+    let u32_at = |offset: usize| {
+        data.get( offset .. offset + 4 ).map( |b| u32::from_le_bytes( [ b[ 0 ], b[ 1 ], b[ 2 ], b[ 3 ] ] ) )
+    };
+    if u32_at( 0 )? != PYTH_MAGIC || u32_at( 8 )? != PYTH_PRICE_ACCOUNT
+        || u32_at( 224 )? != PYTH_STATUS_TRADING {
+        return None;
+    }
+
+    let expo = u32_at( 20 )? as i32;
+    let mut price = [ 0u8; 8 ];
+    price.copy_from_slice( data.get( 208 .. 216 )? );
+    Some( i64::from_le_bytes( price ) as f64 * 10f64.powi( expo ) )
+}
+
+impl PriceSource for Communication {
+    fn token_balance( &self, account: &Pubkey ) -> ClientResult<( f64, u8 )> {
+        self.get_current_balance_for_pubkey_with_commitment(
+            account, self.commitment.unwrap_or( CommitmentConfig::finalized( ) ) )
+    }
 
+    fn accounts( &self, keys: &[ Pubkey ] ) -> ClientResult<Vec<Option<SdkAccount>>> {
+        // get_multiple_accounts takes at most 100 keys; the chunks are fetched by up to
+        // concurrency requests at a time and reassembled in order
+        let chunks: Vec<&[ Pubkey ]> = keys.chunks( 100 ).collect( );
+        let fetch = |chunk: &&[ Pubkey ]| {
+            self.with_retries( || {
+                self.rpc_client( ).get_multiple_accounts_with_commitment(
+                    chunk, self.commitment.unwrap_or( CommitmentConfig::finalized( ) ) )
+            } ).map( |res| res.value )
+        };
+
+        let fetched: Vec<Vec<Option<SdkAccount>>> = if self.concurrency <= 1 || chunks.len( ) <= 1 {
+            chunks.iter( ).map( fetch ).collect::<ClientResult<_>>( )?
+        } else {
+            match ThreadPoolBuilder::new( ).num_threads( self.concurrency ).build( ) {
+                Ok( pool ) => { pool.install( || chunks.par_iter( ).map( fetch ).collect::<ClientResult<_>>( ) )? },
+                Err( err ) => {
+                    warn!( "Cannot fetch accounts concurrently: {:?}", err );
+                    chunks.iter( ).map( fetch ).collect::<ClientResult<_>>( )?
+                }
+            }
+        };
+        Ok( fetched.into_iter( ).flatten( ).collect( ) )
+    }
+}
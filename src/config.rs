@@ -1,6 +1,9 @@
 use solana_sdk::{
     pubkey::Pubkey,
-    instruction::{ AccountMeta, Instruction }
+    commitment_config::CommitmentConfig,
+    instruction::{ AccountMeta, Instruction },
+    system_instruction,
+    system_program,
 };
 use spl_token_swap::{
     curve::{
@@ -9,32 +12,127 @@ use spl_token_swap::{
         stable::StableCurve,
         calculator::{ TradeDirection },
     },
+    state::SwapVersion,
 };
-use serde::{ Serialize, Deserialize };
+use serde::{ Serialize, Deserialize, de::DeserializeOwned };
+use log::{ info, warn, error };
 use std::{
+    cmp::{ max, min, Ordering },
+    collections::{ HashMap, HashSet },
+    convert::TryFrom,
+    env,
+    fmt,
     str::FromStr,
     error::Error,
     fs::File,
-    io::BufReader,
+    io::{ self, BufReader, BufWriter, Write },
     path::Path,
 };
 
-use crate::*;
+use crate::{
+    *,
+    price::{ PoolPrice, PriceSource, RaydiumAccounts },
+    raydium_layout::CpmmPoolInfo,
+    whirlpool::WhirlpoolInfo,
+};
 
 macro_rules! pkey {
-    ($e:expr) => ( Pubkey::from_str( &$e ).unwrap( ) );
+    ($e:expr) => ( match Pubkey::from_str( &$e ) {
+        Ok( key ) => { Ok( key ) },
+        Err( _ ) => {
+            Err( ConfigError::Pubkey {
+                field: stringify!( $e ).rsplit( '.' ).next( ).unwrap_or( "" ).to_string( ),
+                value: $e.to_string( ),
+            } )
+        }
+    } );
+}
+
+/* Like pkey!, for optional fields; absent ones stay None */
+macro_rules! opt_pkey {
+    ($e:expr) => ( match &$e {
+        Some( value ) => {
+            match Pubkey::from_str( value ) {
+                Ok( key ) => { Ok( Some( key ) ) },
+                Err( _ ) => {
+                    Err( ConfigError::Pubkey {
+                        field: stringify!( $e ).rsplit( '.' ).next( ).unwrap_or( "" ).to_string( ),
+                        value: value.to_string( ),
+                    } )
+                }
+            }
+        },
+        None => { Ok( None ) }
+    } );
+}
+
+/* Why a config file could not be loaded */
+#[derive(Debug)]
+pub enum ConfigError {
+    Io( io::Error ),
+    Deserialize( String ),
+    Pubkey { field: String, value: String },
+    Invalid( String ),
 }
 
 pub const POWERS_OF_TEN: [f64; 13] = [ 1.0, 10.0, 100.0, 1_000.0, 10_000.0, 100_000.0, 1_000_000.0,
     10_000_000.0, 100_000_000.0, 1_000_000_000.0, 10_000_000_000.0, 100_000_000_000.0,
     1_000_000_000_000.0 ];
 
+/* 10^n as f64 for any n, e.g. a currency's decimals; too large exponents give infinity */
+pub fn pow10( n: usize ) -> f64 {
+    match POWERS_OF_TEN.get( n ) {
+        Some( p ) => { *p },
+        None => { 10f64.powi( n.min( i32::MAX as usize ) as i32 ) }
+    }
+}
+
+/* Renders an amount of toys in units of a currency with the given decimals, with thousands
+ * separators, e.g. 1234567890 with 6 decimals as 1,234.567890 */
+pub fn format_amount( raw: i128, decimals: u8 ) -> String {
+    let unit = match 10u128.checked_pow( decimals as u32 ) {
+        Some( unit ) => { unit },
+        None => { return raw.to_string( ); }
+    };
+    let abs = raw.unsigned_abs( );
+    let digits = ( abs / unit ).to_string( );
+
+    let mut res = String::new( );
+    if raw < 0 {
+        res.push( '-' );
+    }
+    for ( i, d ) in digits.chars( ).enumerate( ) {
+        if i > 0 && ( digits.len( ) - i ) % 3 == 0 {
+            res.push( ',' );
+        }
+        res.push( d );
+    }
+    if decimals > 0 {
+        res.push_str( &format!( ".{:0width$}", abs % unit, width = decimals as usize ) );
+    }
+    res
+}
+
+/* Converts an amount of toys from one number of decimals to another without going through
+ * f64; scaling up saturates, scaling down rounds towards zero. */
+pub fn rescale_toys( toys: u128, from_decs: usize, to_decs: usize ) -> u128 {
+    if to_decs >= from_decs {
+        toys.saturating_mul( 10u128.pow( ( to_decs - from_decs ) as u32 ) )
+    } else {
+        toys / 10u128.pow( ( from_decs - to_decs ) as u32 )
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CurrencySD {
     name:     String,
     mint:     String,
     decimals: u8,
     account:  String,
+    #[serde(default)]
+    usd_price: Option<f64>,
+    #[serde(default)]
+    oracle:   Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -43,8 +141,62 @@ pub struct Currency {
     pub mint:     Pubkey,
     pub decimals: u8,
     pub account:  Pubkey,
+    pub usd_price: Option<f64>,    // stable printer: value of one unit, 1 if unset
+    pub oracle:   Option<Pubkey>, // stable printer: pyth price account, preferred over usd_price
+}
+
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ExtraBudgetMode {
+    Fixed,
+    Dynamic( f64 ), // percentile (0 to 1) of the recent fees for the cycle's accounts
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum GambleOptimizer {
+    ClosedForm,    // optimum assuming every pool is constant product
+    Sampled( u32 ), // best of this many gamble sizes, spaced geometrically
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SendMode {
+    Rpc,
+    Jito, // as a bundle through the jito block engine, tipping the validator
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SubscriptionMode {
+    Websocket, // account_subscribe on cluster_url
+    Grpc,      // a yellowstone geyser stream
+}
+
+/* Index of the currency cycles start and end in, or a list of them to construct cycles from
+ * each. In the environment, a list is comma separated. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StartCurrencies {
+    One( usize ),
+    Many( Vec<usize> ),
+}
+
+/* An amount in toys of each start currency, in the order of start_currency. A single one only
+ * fits several start currencies if it is 0, which means the same in any unit. In the
+ * environment, a list is comma separated. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StartAmounts<T> {
+    One( T ),
+    Many( Vec<T> ),
+}
+
+/* The way a pool is traded; AtoB swaps its token 0 for token 1. A pool's tokens are also named
+ * by the direction they are the input of. Serialized as the index of the input token. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "usize", into = "usize")]
+pub enum Direction {
+    AtoB,
+    BtoA,
+}
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum CurveType {
@@ -56,6 +208,46 @@ fn default_orca_curve( ) -> String {
     "constant-product".to_string( )
 }
 
+fn default_whirlpool_program( ) -> String {
+    "whirLbMiicVdio4qvUfM5KAg6Ct8VwpYzGff3uctyCc".to_string( )
+}
+
+fn default_raydium_cpmm_program( ) -> String {
+    "CPMMoo8L3F4NbTegBCKVNunggL7H1ZpdTHKxQB5qKP1R".to_string( )
+}
+
+fn default_ev_min_samples( ) -> u64 {
+    10
+}
+
+fn default_rpc_retry_attempts( ) -> u32 {
+    3
+}
+
+fn default_rpc_retry_delay( ) -> u64 {
+    200
+}
+
+fn default_rpc_concurrency( ) -> usize {
+    4
+}
+
+fn default_skip_preflight( ) -> bool {
+    true
+}
+
+fn default_failover_error_rate( ) -> f64 {
+    0.5
+}
+
+fn default_failover_window( ) -> usize {
+    20
+}
+
+fn default_dedup_mirror_cycles( ) -> bool {
+    true
+}
+
 pub const DEFAULT_ORCA_FEES: Fees = Fees {
     trade_fee_numerator:            1 * 251,
     trade_fee_denominator:          100000,
@@ -133,6 +325,8 @@ struct SwapPoolSD {
     needs_approve:      bool,
     // #[serde(default = "default_orca_fees")]
     // fees:               Fees,
+    #[serde(default)]
+    disabled:           bool, // left out of the pools, and so of every cycle
 }
 
 #[derive(Debug, Clone)]
@@ -163,17 +357,27 @@ struct RaydiumPoolSD {
     account:            String, // amm
     authority:          String, // ammAuthority
     open_orders:        String, // ammOpenOrders
-    target_orders:      String,
+    // only needed to swap; pools lacking them can still be priced and listed
+    #[serde(default)]
+    target_orders:      Option<String>,
 
     serum_version:      u64,
 
     serum_market:       String,
-    serum_bids:         String,
-    serum_asks:         String,
-    serum_events:       String,
-    serum_signer:       String,
+    #[serde(default)]
+    serum_bids:         Option<String>,
+    #[serde(default)]
+    serum_asks:         Option<String>,
+    #[serde(default)]
+    serum_events:       Option<String>,
+    #[serde(default)]
+    serum_signer:       Option<String>,
+    #[serde(default)]
+    serum_vault_signer_nonce: Option<u64>, // derives serum_signer, or checks it if both are given
 
     tokens:             [ TokenSD; 2 ],
+    #[serde(default)]
+    disabled:           bool, // left out of the pools, and so of every cycle
 }
 
 #[derive(Debug, Clone)]
@@ -184,14 +388,14 @@ pub struct RaydiumPool {
     pub account:            Pubkey,
     pub authority:          Pubkey,
     pub open_orders:        Pubkey,
-    pub target_orders:      Pubkey,
+    pub target_orders:      Option<Pubkey>,
 
     pub serum_version:      u64,
     pub serum_market:       Pubkey,
-    pub serum_bids:         Pubkey,
-    pub serum_asks:         Pubkey,
-    pub serum_events:       Pubkey,
-    pub serum_signer:       Pubkey,
+    pub serum_bids:         Option<Pubkey>,
+    pub serum_asks:         Option<Pubkey>,
+    pub serum_events:       Option<Pubkey>,
+    pub serum_signer:       Option<Pubkey>,
 
     tokens:             [ Token; 2 ],
 
@@ -199,6 +403,65 @@ pub struct RaydiumPool {
     fees:               Fees,
 }
 
+/* Raydium's constant product pools without a serum market; the vault authority is derived
+ * from the program. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RaydiumCpmmPoolSD {
+    name:               String,
+    account:            String, // pool state
+    amm_config:         String,
+    observation:        String, // observation state
+
+    fee_rate:           u64, // trade fee rate of the amm config, in millionths
+
+    tokens:             [ TokenSD; 2 ], // vaults of token 0 and 1, in that order
+    #[serde(default)]
+    disabled:           bool, // left out of the pools, and so of every cycle
+}
+
+#[derive(Debug, Clone)]
+pub struct RaydiumCpmmPool {
+    name:               String,
+
+    pub account:            Pubkey,
+    pub authority:          Pubkey,
+    pub amm_config:         Pubkey,
+    pub observation:        Pubkey,
+    pub token_programs:     [ Pubkey; 2 ], // of token 0 and 1, read from the pool state
+
+    tokens:             [ Token; 2 ],
+
+    fees:               Fees,
+}
+
+/* Tick arrays are not derived from the current price; the configured ones have to cover
+ * the ticks a swap may cross, starting with the one holding the current tick. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WhirlpoolPoolSD {
+    name:               String,
+    account:            String, // whirlpool
+    oracle:             String,
+
+    #[serde(default)]
+    fee_rate:           Option<u64>, // hundredths of a basis point; the whirlpool's own rate wins
+
+    tokens:             [ TokenSD; 2 ], // vaults of token a and b, in that order
+    #[serde(default)]
+    disabled:           bool, // left out of the pools, and so of every cycle
+}
+
+#[derive(Debug, Clone)]
+pub struct WhirlpoolPool {
+    name:               String,
+
+    pub account:            Pubkey,
+    pub oracle:             Pubkey,
+
+    tokens:             [ Token; 2 ],
+
+    fees:               Fees,
+}
+
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum PoolSD {
@@ -207,23 +470,37 @@ enum PoolSD {
     OrcaV2( SwapPoolSD ),
     Swap( SwapPoolSD ),
     Step( SwapPoolSD ),
+    Whirlpool( WhirlpoolPoolSD ),
+    RaydiumCpmm( RaydiumCpmmPoolSD ),
 }
 
 #[derive(Debug, Clone)]
 pub enum Pool {
     Raydium( RaydiumPool ),
     Swap( SwapPool ),
+    Whirlpool( WhirlpoolPool ),
+    RaydiumCpmm( RaydiumCpmmPool ),
 }
 
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CurrencyConfigSD {
+    #[serde(default)]
     wallet_path: String,
+    #[serde(default)]
+    wallet_env:  Option<String>,
+    #[serde(default)]
+    fee_payer_path: Option<String>,
     currencies:  Vec<CurrencySD>,
 }
 #[derive(Debug, Clone)]
 pub struct CurrencyConfig {
     pub wallet_path: String,
+    // environment variable holding the keypair (base58 or json byte array); takes
+    // precedence over wallet_path if set and populated
+    pub wallet_env:  Option<String>,
+    // keypair paying the transaction fees instead of the wallet; the wallet still signs
+    pub fee_payer_path: Option<String>,
     pub currencies:  Vec<Currency>,
 }
 
@@ -236,27 +513,153 @@ pub struct PoolConfig {
     pools:      Vec<Pool>,
 }
 
+// base fee per signature, lamports
+pub const LAMPORTS_PER_SIGNATURE: u64 = 5000;
+// compute units assumed per transaction without estimated_compute_units or compute_unit_limit
+pub const DEFAULT_COMPUTE_UNITS: u64 = 200_000;
+
+// largest plausible amplification of a stable curve; stable swap programs cap it there
+pub const MAX_STABLE_AMP: u64 = 1_000_000;
+
+// Exchanges pools can be filtered by, as named by Pool::dex_name
+pub const DEX_NAMES: [ &str; 6 ] = [ "orca", "orcaV2", "swap", "step", "raydium", "whirlpool" ];
+
+/* Pools left out of a run, by name or by exchange */
+#[derive(Debug, Clone, Default)]
+pub struct PoolFilter {
+    pub excluded:     Vec<String>, // pool names
+    pub only_dex:     Vec<String>, // keep only pools of these exchanges, unless empty
+    pub excluded_dex: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GambleCap {
+    pub pools:  Vec<String>, // cap applies to every cycle using all of these pools
+    pub amount: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct ConfigSD {
     pub cluster_url:        String,
     pub cluster_url_send:   String,
 
-    pub start_currency:     usize,
+    #[serde(default)]
+    pub start_currency:     StartCurrencies,
+    #[serde(default)]
+    pub start_currency_name: Option<String>,
     pub safety_percentage:  f64,
-    pub minimum_gain:       u128,
+    pub minimum_gain:       StartAmounts<u64>, // untagged enums can't hold u128
+    #[serde(default)]
+    pub minimum_gain_usd:   Option<f64>,
+    #[serde(default)]
+    pub start_currency_oracle: Option<String>,
     #[serde(default)]
     pub minimum_gain_p:     f64,
-    pub minimum_money:      u64,
+    #[serde(default)]
+    pub minimum_gain_any:   bool,
+    pub minimum_money:      StartAmounts<u64>,
+    #[serde(default)]
+    pub reserve_sol:        u64,
     pub slippage:           f64,
     pub max_cycle_length:   u64,
+    #[serde(default = "default_dedup_mirror_cycles")]
+    pub dedup_mirror_cycles: bool,
+    #[serde(default)]
+    pub max_cycles:         Option<usize>,
+    #[serde(default)]
+    pub min_cycle_rate:     Option<f64>,
+    #[serde(default)]
+    pub min_pool_liquidity: Option<f64>,
     pub minimum_display:    f64,
+    #[serde(default)]
+    pub max_display:        Option<usize>,
     pub cooldown:           u64,
+    #[serde(default)]
+    pub warmup_secs:        u64,
+    #[serde(default)]
+    pub max_price_jump:     Option<f64>,
+    #[serde(default)]
+    pub max_leg_impact:     Option<f64>,
+    #[serde(default)]
+    pub max_tx_per_sec:     Option<f64>,
+    #[serde(default)]
+    pub max_in_flight:      Option<usize>,
+    #[serde(default)]
+    pub stable_two_hop:     bool,
+    #[serde(default)]
+    pub verify_before_send: bool,
+    #[serde(default)]
+    pub stable_balance_ttl: u64,
+
+    #[serde(default)]
+    pub max_gamble:         Vec<GambleCap>,
 
     #[serde(default)]
     pub greed:              f64,
+    #[serde(default)]
+    pub gamble_optimizer:   GambleOptimizer,
+
+    #[serde(default)]
+    pub failure_cost:       StartAmounts<u64>,
+    #[serde(default = "default_ev_min_samples")]
+    pub ev_min_samples:     u64,
+    #[serde(default)]
+    pub max_failure_backoff: u64,
 
     #[serde(default)]
     pub extra_budget:       u64,
+    #[serde(default)]
+    pub extra_budget_mode:  ExtraBudgetMode,
+    #[serde(default)]
+    pub compute_unit_limit: Option<u32>,
+    #[serde(default)]
+    pub estimated_compute_units: Option<u32>,
+    #[serde(default)]
+    pub lamport_price:      Option<StartAmounts<f64>>,
+    #[serde(default)]
+    pub wrap_sol:           bool,
+
+    #[serde(default)]
+    pub send_mode:          SendMode,
+    #[serde(default)]
+    pub jito_url:           Option<String>,
+    #[serde(default)]
+    pub jito_tip:           u64,
+    #[serde(default)]
+    pub jito_tip_account:   Option<String>,
+
+    #[serde(default)]
+    pub webhook_url:        Option<String>,
+
+    #[serde(default)]
+    pub confirm_timeout:    Option<u64>,
+    #[serde(default)]
+    pub blockhash_max_age_ms: Option<u64>,
+    #[serde(default)]
+    pub max_silence_secs:   Option<u64>,
+    #[serde(default)]
+    pub commitment:         Option<String>,
+    #[serde(default)]
+    pub subscription_mode:  SubscriptionMode,
+    #[serde(default)]
+    pub grpc_url:           Option<String>,
+    #[serde(default)]
+    pub grpc_token:         Option<String>,
+
+    #[serde(default = "default_rpc_retry_attempts")]
+    pub rpc_retry_attempts: u32,
+    #[serde(default = "default_rpc_retry_delay")]
+    pub rpc_retry_delay:    u64,
+    #[serde(default = "default_rpc_concurrency")]
+    pub rpc_concurrency:    usize,
+    #[serde(default = "default_skip_preflight")]
+    pub skip_preflight:     bool,
+    #[serde(default)]
+    pub backup_cluster_urls: Vec<String>,
+    #[serde(default = "default_failover_error_rate")]
+    pub failover_error_rate: f64,
+    #[serde(default = "default_failover_window")]
+    pub failover_window:    usize,
 
     pub token_program:           String,
     pub associate_token_program: String,
@@ -272,6 +675,11 @@ struct ConfigSD {
 
     pub serum_program_v2:   String,
     pub serum_program_v3:   String,
+
+    #[serde(default = "default_whirlpool_program")]
+    pub whirlpool_program:  String,
+    #[serde(default = "default_raydium_cpmm_program")]
+    pub raydium_cpmm_program: String,
 }
 
 #[derive(Debug, Clone)]
@@ -279,18 +687,125 @@ pub struct Config {
     pub cluster_url:        String,
     pub cluster_url_send:   String,
 
-    pub start_currency:     usize,
+    pub start_currency:     usize, // the first of start_currencies; fees and oracles refer to it
+    pub start_currencies:   Vec<usize>, // cycles are constructed from each
+    pub start_currency_name: Option<String>, // takes precedence over start_currency
     pub safety_percentage:  f64,
-    pub minimum_gain:       u128,
+    // the amounts in toys below hold one per start currency, in the order of start_currencies
+    pub minimum_gain:       Vec<u128>,
+    // print loop: minimum gain in USD instead, converted using the start currency's pyth
+    // price account. minimum_gain applies until the oracle could be read.
+    pub minimum_gain_usd:   Option<f64>,
+    pub start_currency_oracle: Option<Pubkey>,
     pub minimum_gain_p:     f64,
-    pub minimum_money:      u64,
-    pub slippage:           f64,
+    pub minimum_gain_any:   bool, // cycles: either gain threshold suffices instead of both
+    pub minimum_money:      Vec<u64>,
+    pub reserve_sol:        u64, // lamports kept for fees when the start currency is sol
+    pub slippage:           f64, // per hop: fraction of each swap's predicted output we may lose
     pub max_cycle_length:   u64,
+    // drop a cycle if its reverse was already constructed. The reverse trades the opposite
+    // price discrepancy; the print loop evaluates it alongside the kept cycle instead.
+    pub dedup_mirror_cycles: bool,
+    pub max_cycles:         Option<usize>, // construction stops once this many cycles were found
+    // construction drops partial cycles whose product of marginal rates at start up can't
+    // reach this however they continue; needs the pools' reserves.
+    pub min_cycle_rate:     Option<f64>,
+    // construction leaves out pools whose reserves at start up are worth less than this, in
+    // ui units of the start currency; tokens are valued by the best marginal rates back.
+    pub min_pool_liquidity: Option<f64>,
     pub minimum_display:    f64,
+    pub max_display:        Option<usize>, // debug output: only the cycles netting the most
     pub cooldown:           u64,
-
-    pub greed:              f64,
+    pub warmup_secs:        u64, // max time the print loop waits for fresh prices before trading
+    // factor by which a pool's price may move in a single update; a larger move is only
+    // trusted once the next update confirms it.
+    pub max_price_jump:     Option<f64>,
+    // fraction by which a single swap's rate may fall short of the pool's marginal rate
+    // for small amounts; cycles with a leg beyond it are considered unprofitable.
+    pub max_leg_impact:     Option<f64>,
+    pub max_tx_per_sec:     Option<f64>, // across all cycles; excess submissions are dropped
+    pub max_in_flight:      Option<usize>, // per cycle, transactions sent that did not land or fail yet
+    pub stable_two_hop:     bool, // stable printer: also consider two swaps in one transaction
+    // simulate every transaction first and only send it if the start currency gains
+    pub verify_before_send: bool,
+    // stable printer: seconds a cached balance of a currency not currently held is trusted;
+    // balances touched by a swap and the held currency's are always refetched.
+    pub stable_balance_ttl: u64,
+
+    pub max_gamble:         Vec<GambleCap>,
+
+    pub greed:              f64, // fraction of the optimal gamble actually used, in (0, 1]
+    // how the optimal gamble is found; sampling works for any curve, but predicts every
+    // cycle once per sample.
+    pub gamble_optimizer:   GambleOptimizer,
+
+    // toys of the start currency a failed submission is assumed to cost. Once a cycle has
+    // ev_min_samples confirmed outcomes, it is only sent if its expected value, weighing
+    // the gain by the cycle's success rate against this cost, is positive. Outcomes are
+    // only known if confirm_timeout is set.
+    pub failure_cost:       Vec<u64>,
+    pub ev_min_samples:     u64,
+    // print loop iterations a cycle sits out at most after consecutive failed submissions;
+    // the wait starts at the cooldown and doubles with each failure. 0 disables backing off.
+    pub max_failure_backoff: u64,
     pub extra_budget:      u64,
+    // in dynamic mode, extra_budget caps the estimated price and is used if estimating fails
+    pub extra_budget_mode:  ExtraBudgetMode,
+    // compute unit limit requested per transaction; the instruction setting it is emitted
+    // before any swap instruction.
+    pub compute_unit_limit: Option<u32>,
+    // compute units a cycle is expected to consume, e.g. as measured by the profile command;
+    // prices the priority fee subtracted from a cycle's gain
+    pub estimated_compute_units: Option<u32>,
+    // toys of the start currency a lamport is worth; without it, fees are only subtracted
+    // if the start currency is SOL
+    pub lamport_price:      Option<Vec<f64>>,
+    // trade from native lamports if the start currency is wrapped SOL; the configured
+    // account then has to be the wallet's associated token account, which only exists
+    // while a cycle is executed.
+    pub wrap_sol:           bool,
+
+    // how cycles are submitted; in jito mode, the transaction additionally transfers
+    // jito_tip lamports to jito_tip_account and is posted to the block engine at jito_url
+    // (e.g. https://mainnet.block-engine.jito.wtf/api/v1/bundles).
+    pub send_mode:          SendMode,
+    pub jito_url:           Option<String>,
+    pub jito_tip:           u64,
+    pub jito_tip_account:   Option<Pubkey>,
+
+    // receives a JSON POST for every cycle sent for real; besides the details, the payload
+    // has a summary in "text" and "content" for slack and discord.
+    pub webhook_url:        Option<String>,
+
+    // ms to wait for a sent cycle to be confirmed before the balance is re-read; the
+    // print loop doesn't wait for confirmations if unset.
+    pub confirm_timeout:    Option<u64>,
+    // ms a blockhash is reused by the print loop before it is fetched again for the next
+    // transaction; if unset, a blockhash is fetched once per batch of executions.
+    pub blockhash_max_age_ms: Option<u64>,
+    // seconds without any account update after which the print loop subscribes again;
+    // waits forever if unset.
+    pub max_silence_secs:   Option<u64>,
+    // commitment for subscriptions, blockhashes and initial prices; if unset, subscriptions
+    // use confirmed, blockhashes and initial prices finalized.
+    pub commitment:         Option<CommitmentConfig>,
+    // where the print loop gets account updates from; in grpc mode, from the yellowstone
+    // geyser endpoint at grpc_url, authenticated by grpc_token if the provider wants one.
+    pub subscription_mode:  SubscriptionMode,
+    pub grpc_url:           Option<String>,
+    pub grpc_token:         Option<String>,
+
+    pub rpc_retry_attempts: u32, // total attempts for rpc queries before giving up
+    pub rpc_retry_delay:    u64, // ms before the first retry, doubled after each attempt
+    pub rpc_concurrency:    usize, // rpc queries fetching accounts in bulk at the same time
+    // skipping preflight saves time; with it, failing transactions are rejected with the
+    // simulation error instead of failing on chain
+    pub skip_preflight:     bool,
+    // read endpoints tried in turn after cluster_url once more than failover_error_rate of
+    // the last failover_window requests to the active one failed
+    pub backup_cluster_urls: Vec<String>,
+    pub failover_error_rate: f64,
+    pub failover_window:    usize,
 
     pub token_program:        Pubkey,
     pub swap_program:         Pubkey,
@@ -306,97 +821,349 @@ pub struct Config {
 
     pub serum_program_v2:   Pubkey,
     pub serum_program_v3:   Pubkey,
+
+    pub whirlpool_program:  Pubkey,
+    pub raydium_cpmm_program: Pubkey,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cycle {
     pub needs_approval: bool,
-    pub path:           Vec<(usize, usize)>, // List of ( pool indices, idx of input token)
+    pub path:           Vec<(usize, Direction)>, // List of ( pool indices, direction traded )
+    pub max_gamble:     Option<u64>, // upper bound on toys gambled on this cycle
+}
+
+/* A cycle as exported; pools are named, since their indices change with the pool config */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CycleSD {
+    path:               Vec<( String, Direction )>,
 }
 
 // Implementations
 
+impl Direction {
+    pub const BOTH: [ Direction; 2 ] = [ Direction::AtoB, Direction::BtoA ];
+
+    pub fn flip( self ) -> Self {
+        match self {
+            Self::AtoB => { Self::BtoA },
+            Self::BtoA => { Self::AtoB }
+        }
+    }
+
+    /* Index of the input token, for arrays ordered like the pool's tokens */
+    pub fn index( self ) -> usize {
+        match self {
+            Self::AtoB => { 0 },
+            Self::BtoA => { 1 }
+        }
+    }
+}
+
+impl TryFrom<usize> for Direction {
+    type Error = String;
+
+    fn try_from( index: usize ) -> Result<Self, Self::Error> {
+        match index {
+            0 => { Ok( Self::AtoB ) },
+            1 => { Ok( Self::BtoA ) },
+            _ => { Err( format!( "invalid direction {}, expected 0 or 1", index ) ) }
+        }
+    }
+}
+
+impl From<Direction> for usize {
+    fn from( direction: Direction ) -> usize {
+        direction.index( )
+    }
+}
+
 pub fn print_cycle( cyc: &Cycle, pools: &Vec<Pool>, currencies: &Vec<Currency> ) {
+    print!( "{}", format_cycle( cyc, pools, currencies ) );
+}
+
+pub fn format_cycle( cyc: &Cycle, pools: &Vec<Pool>, currencies: &Vec<Currency> ) -> String {
+    let mut res = String::new( );
     let mut i = 0;
     for ( p, idx ) in &cyc.path {
         if i == 0 {
-            // print start currency
-            print!( "{}", currencies[ pools[ *p ].get_currency( *idx ).currency_idx ].name );
+            // start currency
+            res.push_str( &currencies[ pools[ *p ].get_currency( *idx ).currency_idx ].name );
         }
 
-        // print pool type
-        print!( " -{}- ", pools[ *p ].type_name( ) );
+        // pool type
+        res.push_str( &format!( " -{}- ", pools[ *p ].type_name( ) ) );
 
-        // print next currency
-        print!( "{}", currencies[ pools[ *p ].get_currency( 1 - *idx ).currency_idx ].name );
+        // next currency
+        res.push_str( &currencies[ pools[ *p ].get_currency( idx.flip( ) ).currency_idx ].name );
 
         i = i + 1;
     }
+    res
+}
+
+impl Cycle {
+    /* The currency the cycle starts and ends in */
+    pub fn start_currency( &self, pools: &Vec<Pool> ) -> usize {
+        let ( p, w ) = self.path[ 0 ];
+        pools[ p ].get_currency( w ).currency_idx
+    }
+
+    /* The same pools in reverse order and direction */
+    pub fn mirror( &self ) -> Self {
+        Cycle {
+            needs_approval: self.needs_approval,
+            path:           self.path.iter( ).rev( ).map( |( p, w )| ( *p, w.flip( ) ) ).collect( ),
+            max_gamble:     self.max_gamble,
+        }
+    }
+}
+
+impl Currency {
+    pub fn is_native( &self ) -> bool {
+        self.mint == spl_token::native_mint::id( )
+    }
+}
+
+/* The nonce deriving the swap pool's authority from its account, if any does */
+fn swap_authority_nonce( account: &Pubkey, program: &Pubkey, authority: &Pubkey ) -> Option<u8> {
+    ( 0 ..= u8::MAX ).rev( ).find( |nonce| {
+        Pubkey::create_program_address( &[ &account.to_bytes( ), &[ *nonce ] ], program ).ok( ) == Some( *authority )
+    } )
+}
+
+/* Creates the wallet's associated token account for the given currency unless it already
+ * exists; fails if the configured account is not the associated one. */
+pub fn create_associated_account( instructions: &mut Vec<Instruction>, payer: &Pubkey,
+                                  currency: &Currency, config: &Config ) -> bool {
+    let ( ata, _ ) = Pubkey::find_program_address(
+        &[ &payer.to_bytes( ), &config.token_program.to_bytes( ), &currency.mint.to_bytes( ) ],
+        &config.associate_token_program );
+    if ata != currency.account {
+        error!( "Account of {} is not the associated token account, cannot create it.",
+                  currency.name );
+        return false;
+    }
+
+    instructions.push( Instruction {
+        program_id: config.associate_token_program,
+        accounts: vec![
+            AccountMeta::new( *payer, true ),
+            AccountMeta::new( ata, false ),
+            AccountMeta::new_readonly( *payer, false ),
+            AccountMeta::new_readonly( currency.mint, false ),
+            AccountMeta::new_readonly( system_program::id( ), false ),
+            AccountMeta::new_readonly( config.token_program, false ),
+        ],
+        data: vec![ 1 ], // CreateIdempotent, a no-op on an existing account
+    } );
+    true
+}
+
+/* Creates the wallet's wrapped SOL account and funds it with the given lamports. */
+pub fn wrap_sol( instructions: &mut Vec<Instruction>, payer: &Pubkey, currency: &Currency,
+                 lamports: u64, config: &Config ) -> bool {
+    if !create_associated_account( instructions, payer, currency, config ) {
+        return false;
+    }
+    instructions.push( system_instruction::transfer( payer, &currency.account, lamports ) );
+    instructions.push( spl_token::instruction::sync_native( &config.token_program,
+                                                            &currency.account ).unwrap( ) );
+    true
+}
+
+/* Closes the wallet's wrapped SOL account, returning all lamports to the wallet. */
+pub fn unwrap_sol( instructions: &mut Vec<Instruction>, payer: &Pubkey, currency: &Currency,
+                   config: &Config ) {
+    instructions.push( spl_token::instruction::close_account(
+            &config.token_program, &currency.account, payer, payer, &[] ).unwrap( ) );
 }
 
-impl From<CurrencySD> for Currency {
-    fn from( crcy: CurrencySD ) -> Self {
-        Currency {
+impl TryFrom<CurrencySD> for Currency {
+    type Error = ConfigError;
+
+    fn try_from( crcy: CurrencySD ) -> Result<Self, ConfigError> {
+        Ok( Currency {
             name:     crcy.name,
             decimals: crcy.decimals,
-            mint:     pkey!( crcy.mint ),
-            account:  pkey!( crcy.account )
-        }
+            mint:     pkey!( crcy.mint )?,
+            account:  pkey!( crcy.account )?,
+            usd_price: crcy.usd_price,
+            oracle:   opt_pkey!( crcy.oracle )?
+        } )
     }
 }
 
-impl From<TokenSD> for Token {
-    fn from( tkn: TokenSD ) -> Self {
-        Token {
+impl TryFrom<TokenSD> for Token {
+    type Error = ConfigError;
+
+    fn try_from( tkn: TokenSD ) -> Result<Self, ConfigError> {
+        Ok( Token {
             currency_idx: tkn.currency_idx,
-            account: pkey!( tkn.account ),
-            extra_account: if let Some( acc ) = tkn.extra_account { Some( pkey!( acc ) ) } else { None }
-        }
+            account: pkey!( tkn.account )?,
+            extra_account: if let Some( extra_account ) = tkn.extra_account {
+                Some( pkey!( extra_account )? )
+            } else {
+                None
+            }
+        } )
     }
 }
 
-impl From<RaydiumPoolSD> for RaydiumPool {
-    fn from( pool: RaydiumPoolSD ) -> Self {
-        RaydiumPool {
+impl RaydiumPool {
+    fn try_from( pool: RaydiumPoolSD, config: &Config ) -> Result<Self, ConfigError> {
+        let serum_program = if pool.serum_version == 3 {
+            config.serum_program_v3
+        } else {
+            config.serum_program_v2
+        };
+        let derived_signer = match pool.serum_vault_signer_nonce {
+            Some( nonce ) => {
+                Some( raydium::serum_vault_signer( &serum_program, &pkey!( pool.serum_market )?, nonce )
+                      .map_err( |_| {
+                          ConfigError::Invalid( format!( "Pool {}: nonce {} yields no vault signer",
+                                                         pool.name, nonce ) )
+                      } )? )
+            },
+            None => { None }
+        };
+        let serum_signer = match ( &pool.serum_signer, derived_signer ) {
+            ( Some( serum_signer ), Some( derived ) ) => {
+                let serum_signer = pkey!( serum_signer )?;
+                if serum_signer != derived {
+                    return Err( ConfigError::Invalid( format!(
+                        "Pool {}: serum_signer {} differs from the derived vault signer {}",
+                        pool.name, serum_signer, derived ) ) );
+                }
+                Some( serum_signer )
+            },
+            ( Some( _ ), None ) => { opt_pkey!( pool.serum_signer )? },
+            ( None, derived ) => { derived },
+        };
+
+        Ok( RaydiumPool {
             name:           pool.name,
             pool_version:   pool.pool_version,
 
-            account:        pkey!( pool.account ),
-            authority:      pkey!( pool.authority ),
-            open_orders:    pkey!( pool.open_orders ),
-            target_orders:  pkey!( pool.target_orders ),
+            account:        pkey!( pool.account )?,
+            authority:      pkey!( pool.authority )?,
+            open_orders:    pkey!( pool.open_orders )?,
+            target_orders:  opt_pkey!( pool.target_orders )?,
 
             serum_version:  pool.serum_version,
-            serum_market:   pkey!( pool.serum_market ),
-            serum_bids:     pkey!( pool.serum_bids ),
-            serum_asks:     pkey!( pool.serum_asks ),
-            serum_events:   pkey!( pool.serum_events ),
-            serum_signer:   pkey!( pool.serum_signer ),
+            serum_market:   pkey!( pool.serum_market )?,
+            serum_bids:     opt_pkey!( pool.serum_bids )?,
+            serum_asks:     opt_pkey!( pool.serum_asks )?,
+            serum_events:   opt_pkey!( pool.serum_events )?,
+            serum_signer:   serum_signer,
 
-            tokens:         [ Token::from( pool.tokens[ 0 ].clone( ) ),
-                              Token::from( pool.tokens[ 1 ].clone( ) ) ],
+            tokens:         [ Token::try_from( pool.tokens[ 0 ].clone( ) )?,
+                              Token::try_from( pool.tokens[ 1 ].clone( ) )? ],
 
             curve:          CurveType::ConstantProduct( ),
             fees:           DEFAULT_RAYDIUM_FEES,
 
-        }
+        } )
+    }
+}
+
+impl TryFrom<WhirlpoolPoolSD> for WhirlpoolPool {
+    type Error = ConfigError;
+
+    fn try_from( pool: WhirlpoolPoolSD ) -> Result<Self, ConfigError> {
+        Ok( WhirlpoolPool {
+            name:           pool.name,
+
+            account:        pkey!( pool.account )?,
+            oracle:         pkey!( pool.oracle )?,
+
+            tokens:         [ Token::try_from( pool.tokens[ 0 ].clone( ) )?,
+                              Token::try_from( pool.tokens[ 1 ].clone( ) )? ],
+
+            // the whole fee is taken from the input; the protocol's share is part of it
+            fees:           Fees {
+                trade_fee_numerator:            pool.fee_rate.unwrap_or( 0 ),
+                trade_fee_denominator:          1_000_000,
+                owner_trade_fee_numerator:      0,
+                owner_trade_fee_denominator:    1,
+                owner_withdraw_fee_numerator:   0,
+                owner_withdraw_fee_denominator: 0,
+                host_fee_numerator:             0,
+                host_fee_denominator:           0,
+            },
+        } )
+    }
+}
+
+impl RaydiumCpmmPool {
+    fn try_from( pool: RaydiumCpmmPoolSD, config: &Config ) -> Result<Self, ConfigError> {
+        Ok( RaydiumCpmmPool {
+            name:           pool.name,
+
+            account:        pkey!( pool.account )?,
+            authority:      raydium::cpmm_authority( &config.raydium_cpmm_program ),
+            amm_config:     pkey!( pool.amm_config )?,
+            observation:    pkey!( pool.observation )?,
+            // spl token until read_chain_state finds out
+            token_programs: [ config.token_program; 2 ],
+
+            tokens:         [ Token::try_from( pool.tokens[ 0 ].clone( ) )?,
+                              Token::try_from( pool.tokens[ 1 ].clone( ) )? ],
+
+            // the protocol and fund fees are shares of the trade fee
+            fees:           Fees {
+                trade_fee_numerator:            pool.fee_rate,
+                trade_fee_denominator:          1_000_000,
+                owner_trade_fee_numerator:      0,
+                owner_trade_fee_denominator:    1,
+                owner_withdraw_fee_numerator:   0,
+                owner_withdraw_fee_denominator: 0,
+                host_fee_numerator:             0,
+                host_fee_denominator:           0,
+            },
+        } )
     }
 }
 
 impl SwapPool {
-    fn from( pool: SwapPoolSD, program: &Pubkey, tp: &str, is_step: bool ) -> Self {
-        SwapPool {
+    fn try_from( pool: SwapPoolSD, program: &Pubkey, tp: &str,
+                 is_step: bool ) -> Result<Self, ConfigError> {
+        // the curve degenerates without amplification
+        if pool.curve == "stable" {
+            if pool.curve_param == 0 {
+                return Err( ConfigError::Invalid( format!(
+                    "Pool {}: stable curve needs a non-zero curve_param", pool.name ) ) );
+            }
+            if pool.curve_param > MAX_STABLE_AMP {
+                warn!( "Pool {}: amplification {} exceeds {}, predictions may overflow.",
+                       pool.name, pool.curve_param, MAX_STABLE_AMP );
+            }
+        }
+
+        // the program signs for the vaults as the pda of the swap account and the nonce it
+        // stores; any other authority only fails once a swap is sent. The nonce need not be
+        // the canonical one, check compares it with the pool's actual nonce.
+        let account = pkey!( pool.account )?;
+        let authority = pkey!( pool.authority )?;
+        let ( derived, _ ) = Pubkey::find_program_address( &[ &account.to_bytes( ) ], program );
+        if authority != derived && swap_authority_nonce( &account, program, &authority ).is_none( ) {
+            return Err( ConfigError::Invalid( format!(
+                "Pool {}: authority {} is not derived from the swap account with any nonce, expected {}",
+                pool.name, authority, derived ) ) );
+        }
+
+        Ok( SwapPool {
             swap_program:    program.clone( ),
             swap_type:       tp.to_string( ),
 
             name:            pool.name,
-            account:         pkey!( pool.account ),
-            authority:       pkey!( pool.authority ),
-            pool_token_mint: pkey!( pool.pool_token_mint ),
-            fee_account:     pkey!( pool.fee_account ),
+            account:         account,
+            authority:       authority,
+            pool_token_mint: pkey!( pool.pool_token_mint )?,
+            fee_account:     pkey!( pool.fee_account )?,
 
-            tokens:          [ Token::from( pool.tokens[ 0 ].clone( ) ),
-                               Token::from( pool.tokens[ 1 ].clone( ) ) ],
+            tokens:          [ Token::try_from( pool.tokens[ 0 ].clone( ) )?,
+                               Token::try_from( pool.tokens[ 1 ].clone( ) )? ],
 
             needs_approve:   pool.needs_approve,
             is_step:         is_step,
@@ -415,73 +1182,343 @@ impl SwapPool {
                     }
                 },
             }
+        } )
+    }
+}
+
+impl PoolSD {
+    fn disabled( &self ) -> bool {
+        match self {
+            Self::Raydium( r ) => { r.disabled },
+            Self::Orca( o ) | Self::OrcaV2( o ) | Self::Swap( o ) | Self::Step( o ) => { o.disabled },
+            Self::Whirlpool( w ) => { w.disabled },
+            Self::RaydiumCpmm( r ) => { r.disabled }
         }
     }
 }
 
 impl Pool {
-    fn from( pool: PoolSD, config: &Config ) -> Self {
-        match pool {
-            PoolSD::Raydium( r ) => { Self::Raydium( RaydiumPool::from( r ) ) }
-            PoolSD::Orca( o ) => { Self::Swap( SwapPool::from( o,
-                                               &config.orca_swap_program, "orca", false ) ) }
-            PoolSD::OrcaV2( o ) => { Self::Swap( SwapPool::from( o,
-                                                 &config.orca_swap_program_v2, "orcaV2", false ) ) }
-            PoolSD::Swap( o ) => { Self::Swap( SwapPool::from( o,
-                                               &config.swap_program, "swap", false ) ) }
-            PoolSD::Step( o ) => { Self::Swap( SwapPool::from( o,
-                                               &config.step_swap_program, "step", true ) ) }
-        }
+    fn try_from( pool: PoolSD, config: &Config ) -> Result<Self, ConfigError> {
+        Ok( match pool {
+            PoolSD::Raydium( r ) => { Self::Raydium( RaydiumPool::try_from( r, config )? ) }
+            PoolSD::Orca( o ) => { Self::Swap( SwapPool::try_from( o,
+                                               &config.orca_swap_program, "orca", false )? ) }
+            PoolSD::OrcaV2( o ) => { Self::Swap( SwapPool::try_from( o,
+                                                 &config.orca_swap_program_v2, "orcaV2", false )? ) }
+            PoolSD::Swap( o ) => { Self::Swap( SwapPool::try_from( o,
+                                               &config.swap_program, "swap", false )? ) }
+            PoolSD::Step( o ) => { Self::Swap( SwapPool::try_from( o,
+                                               &config.step_swap_program, "step", true )? ) }
+            PoolSD::Whirlpool( w ) => { Self::Whirlpool( WhirlpoolPool::try_from( w )? ) }
+            PoolSD::RaydiumCpmm( r ) => { Self::RaydiumCpmm( RaydiumCpmmPool::try_from( r, config )? ) }
+        } )
     }
 }
 
-impl From<CurrencyConfigSD> for CurrencyConfig {
-    fn from( cfg: CurrencyConfigSD ) -> Self {
-        CurrencyConfig {
+impl TryFrom<CurrencyConfigSD> for CurrencyConfig {
+    type Error = ConfigError;
+
+    fn try_from( cfg: CurrencyConfigSD ) -> Result<Self, ConfigError> {
+        Ok( CurrencyConfig {
             wallet_path: cfg.wallet_path,
-            currencies:  cfg.currencies.into_iter( ).map( Currency::from ).collect( )
-        }
+            wallet_env:  cfg.wallet_env,
+            fee_payer_path: cfg.fee_payer_path,
+            currencies:  cfg.currencies.into_iter( ).map( Currency::try_from )
+                .collect::<Result<_, _>>( )?
+        } )
     }
 }
 
 impl PoolConfig {
-    fn from( cfg: PoolConfigSD, config: &Config ) -> Self {
-        PoolConfig {
-            pools: cfg.pools.into_iter( ).map( |p: PoolSD| Pool::from( p, config ) ).collect( )
+    fn try_from( cfg: PoolConfigSD, config: &Config ) -> Result<Self, ConfigError> {
+        // disabled pools aren't even validated, so they may be kept in any state
+        let disabled = cfg.pools.iter( ).filter( |p| p.disabled( ) ).count( );
+        if disabled > 0 {
+            info!( "Leaving out {} disabled pools.", disabled );
         }
+        Ok( PoolConfig {
+            pools: cfg.pools.into_iter( ).filter( |p| !p.disabled( ) )
+                .map( |p: PoolSD| Pool::try_from( p, config ) )
+                .collect::<Result<_, _>>( )?
+        } )
     }
 }
 
-impl From<ConfigSD> for Config {
-    fn from( con: ConfigSD ) -> Self {
-        Config {
+impl TryFrom<ConfigSD> for Config {
+    type Error = ConfigError;
+
+    fn try_from( con: ConfigSD ) -> Result<Self, ConfigError> {
+        check_ranges( &con )?;
+        if con.send_mode == SendMode::Jito && ( con.jito_url.is_none( ) || con.jito_tip_account.is_none( ) ) {
+            return Err( ConfigError::Invalid( "Jito send mode needs jito_url and jito_tip_account".to_string( ) ) );
+        }
+        if con.subscription_mode == SubscriptionMode::Grpc && con.grpc_url.is_none( ) {
+            return Err( ConfigError::Invalid( "Grpc subscription mode needs grpc_url".to_string( ) ) );
+        }
+        let start_currencies = match con.start_currency {
+            StartCurrencies::One( idx ) => { vec![ idx ] },
+            StartCurrencies::Many( list ) => { list }
+        };
+        if start_currencies.is_empty( ) {
+            return Err( ConfigError::Invalid( "start_currency is an empty list".to_string( ) ) );
+        }
+        let starts = start_currencies.len( );
+
+        Ok( Config {
             cluster_url:        con.cluster_url,
             cluster_url_send:   con.cluster_url_send,
 
-            start_currency:     con.start_currency,
+            start_currency:     start_currencies[ 0 ],
+            start_currencies:   start_currencies,
+            start_currency_name: con.start_currency_name,
             safety_percentage:  con.safety_percentage,
-            minimum_gain:       con.minimum_gain,
+            minimum_gain:       con.minimum_gain.per_start( "minimum_gain", starts )?
+                                    .into_iter( ).map( |gain| gain as u128 ).collect( ),
+            minimum_gain_usd:   con.minimum_gain_usd,
+            start_currency_oracle: match con.start_currency_oracle {
+                Some( oracle ) => { Some( pkey!( oracle )? ) },
+                None => { None }
+            },
             minimum_gain_p:     if con.minimum_gain_p < 1.0 { 1.0 } else { con.minimum_gain_p },
-            minimum_money:      con.minimum_money,
+            minimum_gain_any:   con.minimum_gain_any,
+            minimum_money:      con.minimum_money.per_start( "minimum_money", starts )?,
+            reserve_sol:        con.reserve_sol,
             slippage:           con.slippage,
             max_cycle_length:   con.max_cycle_length,
+            dedup_mirror_cycles: con.dedup_mirror_cycles,
+            max_cycles:         con.max_cycles,
+            min_cycle_rate:     con.min_cycle_rate,
+            min_pool_liquidity: con.min_pool_liquidity,
             minimum_display:    con.minimum_display,
+            max_display:        con.max_display,
             cooldown:           con.cooldown,
-
-            greed:              con.greed,
+            warmup_secs:        con.warmup_secs,
+            max_price_jump:     con.max_price_jump,
+            max_leg_impact:     con.max_leg_impact,
+            max_tx_per_sec:     con.max_tx_per_sec,
+            max_in_flight:      con.max_in_flight,
+            stable_two_hop:     con.stable_two_hop,
+            verify_before_send: con.verify_before_send,
+            stable_balance_ttl: con.stable_balance_ttl,
+
+            max_gamble:         con.max_gamble,
+
+            greed:              if con.greed == 0.0 { 1.0 } else { con.greed },
+            gamble_optimizer:   con.gamble_optimizer,
+
+            failure_cost:       con.failure_cost.per_start( "failure_cost", starts )?,
+            ev_min_samples:     con.ev_min_samples,
+            max_failure_backoff: con.max_failure_backoff,
             extra_budget:       con.extra_budget,
-
-            token_program:                pkey!( con.token_program ),
-            swap_program:                 pkey!( con.swap_program ),
-            orca_swap_program:            pkey!( con.orca_swap_program ),
-            step_swap_program:            pkey!( con.step_swap_program ),
-            associate_token_program:      pkey!( con.associate_token_program ),
-            orca_swap_program_v2:         pkey!( con.orca_swap_program_v2 ),
-            raydium_liquidity_program_v2: pkey!( con.raydium_liquidity_program_v2 ),
-            raydium_liquidity_program_v3: pkey!( con.raydium_liquidity_program_v3 ),
-            raydium_liquidity_program_v4: pkey!( con.raydium_liquidity_program_v4 ),
-            serum_program_v2:             pkey!( con.serum_program_v2 ),
-            serum_program_v3:             pkey!( con.serum_program_v3 ),
+            extra_budget_mode:  con.extra_budget_mode,
+            compute_unit_limit: con.compute_unit_limit,
+            estimated_compute_units: con.estimated_compute_units,
+            lamport_price:      match con.lamport_price {
+                Some( price ) => { Some( price.per_start( "lamport_price", starts )? ) },
+                None => { None }
+            },
+            wrap_sol:           con.wrap_sol,
+
+            send_mode:          con.send_mode,
+            jito_url:           con.jito_url,
+            jito_tip:           con.jito_tip,
+            jito_tip_account:   match con.jito_tip_account {
+                Some( account ) => { Some( pkey!( account )? ) },
+                None => { None }
+            },
+            webhook_url:        con.webhook_url,
+
+            confirm_timeout:    con.confirm_timeout,
+            blockhash_max_age_ms: con.blockhash_max_age_ms,
+            max_silence_secs:   con.max_silence_secs,
+            commitment:         match con.commitment {
+                Some( c ) => {
+                    Some( CommitmentConfig::from_str( &c ).map_err( |_| {
+                        ConfigError::Invalid( format!( "Unknown commitment {}", c ) )
+                    } )? )
+                },
+                None => { None }
+            },
+            subscription_mode:  con.subscription_mode,
+            grpc_url:           con.grpc_url,
+            grpc_token:         con.grpc_token,
+
+            rpc_retry_attempts: con.rpc_retry_attempts,
+            rpc_retry_delay:    con.rpc_retry_delay,
+            rpc_concurrency:    max( con.rpc_concurrency, 1 ),
+            skip_preflight:     con.skip_preflight,
+            backup_cluster_urls: con.backup_cluster_urls,
+            failover_error_rate: con.failover_error_rate,
+            failover_window:    max( con.failover_window, 1 ),
+
+            token_program:                pkey!( con.token_program )?,
+            swap_program:                 pkey!( con.swap_program )?,
+            orca_swap_program:            pkey!( con.orca_swap_program )?,
+            step_swap_program:            pkey!( con.step_swap_program )?,
+            associate_token_program:      pkey!( con.associate_token_program )?,
+            orca_swap_program_v2:         pkey!( con.orca_swap_program_v2 )?,
+            raydium_liquidity_program_v2: pkey!( con.raydium_liquidity_program_v2 )?,
+            raydium_liquidity_program_v3: pkey!( con.raydium_liquidity_program_v3 )?,
+            raydium_liquidity_program_v4: pkey!( con.raydium_liquidity_program_v4 )?,
+            serum_program_v2:             pkey!( con.serum_program_v2 )?,
+            serum_program_v3:             pkey!( con.serum_program_v3 )?,
+            whirlpool_program:            pkey!( con.whirlpool_program )?,
+            raydium_cpmm_program:         pkey!( con.raydium_cpmm_program )?,
+        } )
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        match self {
+            Self::Io( err ) => { write!( f, "{}", err ) },
+            Self::Deserialize( err ) => { write!( f, "{}", err ) },
+            Self::Pubkey { field, value } => { write!( f, "{} is not a valid pubkey: {}", field, value ) },
+            Self::Invalid( reason ) => { write!( f, "{}", reason ) },
+        }
+    }
+}
+
+impl Error for ConfigError { }
+
+impl From<io::Error> for ConfigError {
+    fn from( err: io::Error ) -> Self {
+        Self::Io( err )
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from( err: serde_json::Error ) -> Self {
+        Self::Deserialize( err.to_string( ) )
+    }
+}
+
+impl From<serde_yaml::Error> for ConfigError {
+    fn from( err: serde_yaml::Error ) -> Self {
+        Self::Deserialize( err.to_string( ) )
+    }
+}
+
+/* Rejects values that would make the math downstream meaningless; zero greed and
+ * minimum_gain_p stand for unset. */
+fn check_ranges( con: &ConfigSD ) -> Result<(), ConfigError> {
+    let checks = [
+        ( "slippage", con.slippage, con.slippage >= 0.0 && con.slippage < 1.0, "[0, 1)" ),
+        ( "safety_percentage", con.safety_percentage,
+          con.safety_percentage > 0.0 && con.safety_percentage <= 1.0, "(0, 1]" ),
+        ( "minimum_gain_p", con.minimum_gain_p,
+          con.minimum_gain_p == 0.0 || con.minimum_gain_p >= 1.0, "0 or at least 1" ),
+        ( "greed", con.greed, con.greed >= 0.0 && con.greed <= 1.0, "(0, 1]" ),
+        ( "failover_error_rate", con.failover_error_rate,
+          con.failover_error_rate >= 0.0 && con.failover_error_rate < 1.0, "[0, 1)" ),
+    ];
+    for &( field, value, ok, range ) in checks.iter( ) {
+        // written so that NaN fails as well
+        if !ok {
+            return Err( ConfigError::Invalid( format!( "{} is {}, must be {}", field, value, range ) ) );
+        }
+    }
+    // the oracle only prices the first start currency
+    if let ( Some( _ ), StartCurrencies::Many( starts ) ) = ( con.minimum_gain_usd, &con.start_currency ) {
+        if starts.len( ) > 1 {
+            return Err( ConfigError::Invalid(
+                "minimum_gain_usd only works with a single start currency".to_string( ) ) );
+        }
+    }
+    // no transaction could ever be sent
+    if con.max_in_flight == Some( 0 ) {
+        return Err( ConfigError::Invalid( "max_in_flight is 0, must be at least 1".to_string( ) ) );
+    }
+    // a single sample can only be the maximum
+    if let GambleOptimizer::Sampled( samples ) = con.gamble_optimizer {
+        if samples < 2 {
+            return Err( ConfigError::Invalid( format!( "gamble_optimizer samples is {}, must be at least 2",
+                                                       samples ) ) );
+        }
+    }
+    Ok( ( ) )
+}
+
+impl Default for ExtraBudgetMode {
+    fn default( ) -> Self {
+        Self::Fixed
+    }
+}
+
+impl Default for GambleOptimizer {
+    fn default( ) -> Self {
+        Self::ClosedForm
+    }
+}
+
+impl Default for SendMode {
+    fn default( ) -> Self {
+        Self::Rpc
+    }
+}
+
+impl Default for SubscriptionMode {
+    fn default( ) -> Self {
+        Self::Websocket
+    }
+}
+
+impl Default for StartCurrencies {
+    fn default( ) -> Self {
+        Self::One( 0 )
+    }
+}
+
+impl FromStr for StartCurrencies {
+    type Err = std::num::ParseIntError;
+
+    fn from_str( s: &str ) -> Result<Self, Self::Err> {
+        if !s.contains( ',' ) {
+            return Ok( Self::One( s.trim( ).parse( )? ) );
+        }
+        let list = s.split( ',' ).map( |idx| idx.trim( ).parse( ) ).collect::<Result<Vec<usize>, _>>( )?;
+        Ok( Self::Many( list ) )
+    }
+}
+
+impl<T: Default> Default for StartAmounts<T> {
+    fn default( ) -> Self {
+        Self::One( T::default( ) )
+    }
+}
+
+impl<T: FromStr> FromStr for StartAmounts<T> {
+    type Err = T::Err;
+
+    fn from_str( s: &str ) -> Result<Self, Self::Err> {
+        if !s.contains( ',' ) {
+            return Ok( Self::One( s.trim( ).parse( )? ) );
+        }
+        let list = s.split( ',' ).map( |v| v.trim( ).parse( ) ).collect::<Result<Vec<T>, _>>( )?;
+        Ok( Self::Many( list ) )
+    }
+}
+
+impl<T: Copy + Default + PartialEq> StartAmounts<T> {
+    /* One amount for each of the given number of start currencies; amounts in toys of one
+     * currency mean nothing in another, so anything else is rejected. */
+    fn per_start( self, field: &str, starts: usize ) -> Result<Vec<T>, ConfigError> {
+        match self {
+            Self::One( amount ) => {
+                if starts > 1 && amount != T::default( ) {
+                    return Err( ConfigError::Invalid( format!(
+                        "{} is in toys of a start currency, list one for each of the {} start currencies",
+                        field, starts ) ) );
+                }
+                Ok( vec![ amount; starts ] )
+            },
+            Self::Many( list ) => {
+                if list.len( ) != starts {
+                    return Err( ConfigError::Invalid( format!( "{} lists {} amounts for {} start currencies",
+                                                               field, list.len( ), starts ) ) );
+                }
+                Ok( list )
+            }
         }
     }
 }
@@ -504,43 +1541,634 @@ impl CurveType {
     }
 }
 
+/* Annotated examples of the three config files; fields marked optional may be left out. */
+const CONFIG_SCHEMA: &str = r#"{
+    "cluster_url":             "https://api.mainnet-beta.solana.com", // string
+    "cluster_url_send":        "https://api.mainnet-beta.solana.com", // string, transactions go here
+    "start_currency":          0,          // usize or [usize], optional (default 0), index into currencies;
+                                           // with a list, cycles start from each, the oracle refers to the first
+    "start_currency_name":     "USDC",     // string, optional, overrides start_currency with this one
+    "safety_percentage":       0.9,        // f64, fraction of the balance that may be gambled, in (0, 1]
+    "minimum_gain":            1000,       // u64 or [u64], toys of the start currency; with several start
+                                           // currencies a list of one each, unless it is 0
+    "minimum_gain_usd":        0.05,       // f64, optional, needs start_currency_oracle and a single start currency
+    "start_currency_oracle":   "<pubkey>", // string, optional, pyth price account
+    "minimum_gain_p":          1.001,      // f64, optional (default 0 = 1), relative gain, at least 1
+    "minimum_gain_any":        false,      // bool, optional (default false)
+    "minimum_money":           100000,     // u64 or [u64], toys, like minimum_gain
+    "reserve_sol":             10000000,   // u64, optional (default 0), lamports never gambled
+    "slippage":                0.001,      // f64, per hop, in [0, 1)
+    "max_cycle_length":        3,          // u64, at least 2
+    "dedup_mirror_cycles":     true,       // bool, optional (default true)
+    "max_cycles":              null,       // usize, optional (default unlimited), also caps partial cycles
+    "min_cycle_rate":          null,       // f64, optional, e.g. 0.98, fetches reserves when constructing
+    "min_pool_liquidity":      null,       // f64, optional, start currency (ui), fetches reserves when constructing
+    "minimum_display":         1.0,        // f64
+    "max_display":             null,       // usize, optional, cycles shown per debug pass
+    "cooldown":                10,         // u64, print loop iterations
+    "warmup_secs":             0,          // u64, optional (default 0)
+    "max_price_jump":          null,       // f64, optional, factor > 1
+    "max_leg_impact":          null,       // f64, optional, fraction in (0, 1)
+    "max_tx_per_sec":          null,       // f64, optional (default unlimited)
+    "max_in_flight":           null,       // usize, optional (default unlimited), per cycle, at least 1
+    "stable_two_hop":          false,      // bool, optional (default false)
+    "verify_before_send":      false,      // bool, optional (default false)
+    "stable_balance_ttl":      0,          // u64, optional (default 0 = refetch every pass), seconds
+    "max_gamble":              [ { "pools": [ "<pool name>" ], "amount": 1000000 } ], // optional (default [])
+    "greed":                   1.0,        // f64, optional (default 0 = 1), in (0, 1]
+    "gamble_optimizer":        "ClosedForm", // "ClosedForm" or { "Sampled": <samples, at least 2> }, optional (default "ClosedForm")
+    "failure_cost":            0,          // u64 or [u64], optional (default 0), toys, like minimum_gain
+    "ev_min_samples":          10,         // u64, optional (default 10)
+    "max_failure_backoff":     0,          // u64, optional (default 0 = off), print loop iterations
+    "extra_budget":            0,          // u64, optional (default 0), micro-lamports per compute unit
+    "extra_budget_mode":       "Fixed",    // "Fixed" or { "Dynamic": <percentile> }, optional (default "Fixed")
+    "compute_unit_limit":      null,       // u32, optional
+    "estimated_compute_units": null,       // u32, optional (default compute_unit_limit or 200000)
+    "lamport_price":           null,       // f64 or [f64], optional, toys of the start currency per lamport,
+                                           // like minimum_gain
+    "wrap_sol":                false,      // bool, optional (default false)
+    "send_mode":               "Rpc",      // "Rpc" or "Jito", optional (default "Rpc")
+    "jito_url":                null,       // string, needed for "Jito", block engine bundle endpoint
+    "jito_tip":                0,          // u64, optional (default 0), lamports
+    "jito_tip_account":        null,       // string, needed for "Jito", one of the tip accounts
+    "webhook_url":             null,       // string, optional, notified of sent cycles
+    "confirm_timeout":         null,       // u64, optional, ms
+    "blockhash_max_age_ms":    null,       // u64, optional, ms
+    "max_silence_secs":        null,       // u64, optional, s
+    "commitment":              null,       // "processed", "confirmed" or "finalized", optional
+    "subscription_mode":       "Websocket", // "Websocket" or "Grpc", optional (default "Websocket")
+    "grpc_url":                null,       // string, needed for "Grpc", yellowstone geyser endpoint
+    "grpc_token":              null,       // string, optional, x-token of the grpc endpoint
+    "rpc_retry_attempts":      3,          // u32, optional (default 3)
+    "rpc_retry_delay":         200,        // u64, optional (default 200), ms
+    "rpc_concurrency":         4,          // usize, optional (default 4), for fetching initial prices
+    "skip_preflight":          true,       // bool, optional (default true)
+    "backup_cluster_urls":     [],         // [string], optional, read endpoints to fail over to
+    "failover_error_rate":     0.5,        // f64, optional (default 0.5), in [0, 1)
+    "failover_window":         20,         // usize, optional (default 20), requests
+
+    "token_program":                "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
+    "associate_token_program":      "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL",
+    "swap_program":                 "SwapsVeCiPHMUAtzQWZw7RjsKjgCjhwU55QGu4U1Szw",
+    "step_swap_program":            "SSwpMgqNDsyV7mAgN9ady4bDVu5ySjmmXejXvy2vLt1",
+    "orca_swap_program":            "DjVE6JNiYqPL2QXyCUUh8rNjHrbz9hXHNYt99MQ59qw1",
+    "orca_swap_program_v2":         "9W959DqEETiGZocYWCQPaJ6sBmUzgfxXfqGeTEdp3aQP",
+    "raydium_liquidity_program_v2": "RVKd61ztZW9GUwhRbbLoYVRE5Xf1B2tVscKqwZqXgEr",
+    "raydium_liquidity_program_v3": "27haf8L6oxUeXrHrgEgsexjSY5hbVUWEmvv9Nyxg8vQv",
+    "raydium_liquidity_program_v4": "675kPX9MHTjS2zt1qfr1NYHuzeLXfQM9H24wFSUt1Mp8",
+    "serum_program_v2":             "EUqojwWA2rd19FZrzeBncJsm38Jm1hEhE3zsmX3bRc2o",
+    "serum_program_v3":             "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin",
+    "whirlpool_program":            "whirLbMiicVdio4qvUfM5KAg6Ct8VwpYzGff3uctyCc", // optional (default as shown)
+    "raydium_cpmm_program":         "CPMMoo8L3F4NbTegBCKVNunggL7H1ZpdTHKxQB5qKP1R" // optional (default as shown)
+}"#;
+
+const CURRENCY_SCHEMA: &str = r#"{
+    "wallet_path": "~/.config/solana/id.json", // string, optional (default "")
+    "wallet_env":  "HIKARU_WALLET",            // string, optional, variable holding the keypair
+    "fee_payer_path": "~/.config/solana/fees.json", // string, optional (default the wallet)
+    "currencies": [
+        {
+            "name":     "USDC",     // string
+            "mint":     "<pubkey>", // string
+            "decimals": 6,          // u8
+            "account":  "<pubkey>", // string, the wallet's token account
+            "usd_price": 1.0,       // f64, optional (default 1), stable printer reference price
+            "oracle":   "<pubkey>"  // string, optional, pyth price account, preferred over usd_price
+        }
+    ]
+}"#;
+
+const POOL_SCHEMA: &str = r#"{
+    "pools": [
+        // every pool also takes "disabled": true (bool, optional, default false) to be left out
+        // "Orca", "OrcaV2", "Swap" and "Step" share this layout
+        { "OrcaV2": {
+            "name":            "SOL/USDC", // string
+            "account":         "<pubkey>",
+            "authority":       "<pubkey>",
+            "pool_token_mint": "<pubkey>",
+            "fee_account":     "<pubkey>",
+            "tokens": [ { "currency_idx": 0, "account": "<pubkey>" },   // vaults, extra_account unused
+                        { "currency_idx": 1, "account": "<pubkey>" } ],
+            "curve":           "constant-product", // "constant-product" or "stable", optional
+            "curve_param":     0,                  // u64, amplification, needed (non-zero) for stable curves
+            "needs_approve":   false               // bool, optional (default false)
+        } },
+        { "Raydium": {
+            "name":          "RAY/USDC",
+            "pool_version":  4,          // u64
+            "account":       "<pubkey>", // amm
+            "authority":     "<pubkey>",
+            "open_orders":   "<pubkey>",
+            "target_orders": "<pubkey>", // optional, needed to swap
+            "serum_version": 3,          // u64
+            "serum_market":  "<pubkey>",
+            "serum_bids":    "<pubkey>", // optional, needed to swap
+            "serum_asks":    "<pubkey>", // optional, needed to swap
+            "serum_events":  "<pubkey>", // optional, needed to swap
+            "serum_signer":  "<pubkey>", // optional, needed to swap unless serum_vault_signer_nonce is given
+            "serum_vault_signer_nonce": 1, // u64, optional, the market's nonce to derive serum_signer
+            "tokens": [ { "currency_idx": 0, "account": "<pubkey>", "extra_account": "<pubkey>" },
+                        { "currency_idx": 1, "account": "<pubkey>", "extra_account": "<pubkey>" } ]
+                        // account: amm vault, extra_account: serum vault
+        } },
+        { "Whirlpool": {
+            "name":               "SOL/USDC (whirlpool)",
+            "account":            "<pubkey>",
+            "oracle":             "<pubkey>", // the tick arrays follow from the current tick
+            "fee_rate":           3000, // u64, hundredths of a basis point, optional, read from the whirlpool
+            "tokens": [ { "currency_idx": 0, "account": "<pubkey>" },   // vault of token a
+                        { "currency_idx": 1, "account": "<pubkey>" } ]  // vault of token b
+        } },
+        { "RaydiumCpmm": {
+            "name":        "SOL/USDC (cpmm)",
+            "account":     "<pubkey>", // pool state
+            "amm_config":  "<pubkey>",
+            "observation": "<pubkey>", // observation state
+            "fee_rate":    2500,       // u64, millionths, the amm config's trade fee rate
+            "tokens": [ { "currency_idx": 0, "account": "<pubkey>" },   // vault of token 0
+                        { "currency_idx": 1, "account": "<pubkey>" } ]  // vault of token 1
+        } }
+    ]
+}"#;
+
+/* Prints annotated examples of the config, currency config and pool config files */
+pub fn print_schemas( ) {
+    println!( "Config (-c):\n{}", CONFIG_SCHEMA );
+    println!( "Scalar fields may be overridden by environment variables named after them, e.g.\n\
+               HIKARU_SLIPPAGE; these take precedence over the file.\n" );
+    println!( "Currency config (-y):\n{}\n", CURRENCY_SCHEMA );
+    println!( "Pool config (-p):\n{}", POOL_SCHEMA );
+}
+
+/* Parses a config file as yaml if its extension says so, as json otherwise */
+fn read_sd<T: DeserializeOwned, P: AsRef<Path>>( path: P ) -> Result<T, ConfigError> {
+    let is_yaml = match path.as_ref( ).extension( ).and_then( |e| e.to_str( ) ) {
+        Some( "yaml" ) | Some( "yml" ) => { true },
+        _ => { false }
+    };
+    let file = File::open( path )?;
+    let reader = BufReader::new( file );
+    if is_yaml {
+        Ok( serde_yaml::from_reader( reader )? )
+    } else {
+        Ok( serde_json::from_reader( reader )? )
+    }
+}
+
 impl CurrencyConfig {
-    pub fn read_from_file<P: AsRef<Path>>( path: P ) -> Result<CurrencyConfig, Box<dyn Error>> {
-        let file = File::open( path )?;
-        let reader = BufReader::new( file );
-        let c: CurrencyConfigSD = serde_json::from_reader( reader )?;
-        Ok( Self::from( c ) )
+    pub fn read_from_file<P: AsRef<Path>>( path: P ) -> Result<CurrencyConfig, ConfigError> {
+        let c: CurrencyConfigSD = read_sd( path )?;
+        Self::try_from( c )
     }
 }
 
 impl PoolConfig {
     pub fn read_from_file<P: AsRef<Path>>( path: P,
-                                           config: &Config ) -> Result<Vec<Pool>, Box<dyn Error>> {
-        let file = File::open( path )?;
-        let reader = BufReader::new( file );
-        let c: PoolConfigSD = serde_json::from_reader( reader )?;
-        Ok( Self::from( c, config ).pools )
+                                           config: &Config ) -> Result<Vec<Pool>, ConfigError> {
+        let c: PoolConfigSD = read_sd( path )?;
+        Ok( Self::try_from( c, config )?.pools )
+    }
+
+    /* Drops the pools the filter leaves out */
+    pub fn filter( pools: &mut Vec<Pool>, filter: &PoolFilter ) {
+        for e in &filter.excluded {
+            if !pools.iter( ).any( |p| p.get_name( ) == e ) {
+                warn!( "Cannot exclude pool {}, no such pool.", e );
+            }
+        }
+        pools.retain( |p| {
+            let dex = p.dex_name( ).to_string( );
+            !filter.excluded.contains( p.get_name( ) )
+                && ( filter.only_dex.is_empty( ) || filter.only_dex.contains( &dex ) )
+                && !filter.excluded_dex.contains( &dex )
+        } );
+    }
+
+    /* Checks that every pool token refers to an existing currency and that no pool swaps a
+     * currency for itself. */
+    pub fn validate( pools: &Vec<Pool>, currencies: &Vec<Currency> ) -> Result<(), ConfigError> {
+        for p in pools {
+            for d in Direction::BOTH.iter( ) {
+                let idx = p.get_currency( *d ).currency_idx;
+                if idx >= currencies.len( ) {
+                    return Err( ConfigError::Invalid( format!(
+                        "Pool {}: token {} has currency_idx {}, but only {} currencies are configured",
+                        p.get_name( ), d.index( ), idx, currencies.len( ) ) ) );
+                }
+            }
+            // such a pool would make for a free swap in every cycle using it
+            let ( a, b ) = ( p.get_currency( Direction::AtoB ).currency_idx,
+                             p.get_currency( Direction::BtoA ).currency_idx );
+            if a == b || currencies[ a ].mint == currencies[ b ].mint {
+                return Err( ConfigError::Invalid( format!( "Pool {}: both tokens are {}", p.get_name( ),
+                                                           currencies[ a ].name ) ) );
+            }
+        }
+        Ok( ( ) )
+    }
+}
+
+impl PoolConfig {
+    /* Prints, for each currency, the pools holding it. A currency needs two pools to be
+     * entered and left again within a cycle. */
+    pub fn print_coverage( pools: &Vec<Pool>, currencies: &Vec<Currency> ) {
+        let mut holders: Vec<Vec<&str>> = vec![ Vec::new( ); currencies.len( ) ];
+        for p in pools {
+            for d in Direction::BOTH.iter( ) {
+                holders[ p.get_currency( *d ).currency_idx ].push( p.get_name( ).as_str( ) );
+            }
+        }
+
+        for ( c, names ) in currencies.iter( ).zip( holders.iter( ) ) {
+            let note = match names.len( ) {
+                0 => { " (orphan)" },
+                1 => { " (dead end)" },
+                _ => { "" }
+            };
+            println!( "{:>10}: {:3} pools{}{}{}", c.name, names.len( ), note,
+                      if names.is_empty( ) { "" } else { ": " }, names.join( ", " ) );
+        }
+    }
+
+    /* Fetches every account referenced by the pools and reports the ones that are missing or
+     * owned by an unexpected program, and swap pools whose authority isn't derived with the
+     * nonce stored in their account. Returns the number of problems found. */
+    pub fn check( comm: &Communication, pools: &Vec<Pool>, config: &Config ) -> usize {
+        let mut problems = 0;
+
+        // all pools' accounts at once, then each pool takes its share in order
+        let referenced: Vec<_> = pools.iter( ).map( |p| p.referenced_accounts( config ) ).collect( );
+        let keys: Vec<Pubkey> = referenced.iter( ).flatten( ).map( |( _, key, _ )| *key ).collect( );
+        let mut all_fetched = match comm.accounts( &keys ) {
+            Ok( fetched ) => { fetched.into_iter( ) },
+            Err( err ) => {
+                error!( "Fetching pool accounts failed: {:?}", err );
+                return 1;
+            }
+        };
+
+        // ( pool, start tick index, address ) of the tick arrays swaps from the current ticks cross
+        let mut tick_arrays: Vec<( &Pool, i32, Pubkey )> = Vec::new( );
+
+        for ( p, accounts ) in pools.iter( ).zip( referenced.iter( ) ) {
+            let fetched: Vec<_> = all_fetched.by_ref( ).take( accounts.len( ) ).collect( );
+
+            for ( ( label, key, owners ), acc ) in accounts.iter( ).zip( fetched.iter( ) ) {
+                match acc {
+                    None => {
+                        println!( "Pool {}: {} {} does not exist.", p.get_name( ), label, key );
+                        problems = problems + 1;
+                    },
+                    Some( acc ) if !owners.contains( &acc.owner ) => {
+                        println!( "Pool {}: {} {} is owned by {}, expected one of {:?}.", p.get_name( ),
+                                  label, key, acc.owner, owners );
+                        problems = problems + 1;
+                    },
+                    _ => { }
+                }
+            }
+
+            // the swap account comes first and holds the nonce the program signs with
+            if let ( Pool::Swap( sp ), Some( Some( acc ) ) ) = ( p, fetched.first( ) ) {
+                match SwapVersion::unpack( &acc.data ) {
+                    Ok( state ) => {
+                        let seeds: [ &[ u8 ]; 2 ] = [ &sp.account.to_bytes( ), &[ state.nonce( ) ] ];
+                        match Pubkey::create_program_address( &seeds, &sp.swap_program ) {
+                            Ok( expected ) if expected == sp.authority => { },
+                            Ok( expected ) => {
+                                println!( "Pool {}: authority {} differs from {}, derived with the pool's nonce {}.",
+                                          sp.name, sp.authority, expected, state.nonce( ) );
+                                problems = problems + 1;
+                            },
+                            Err( err ) => {
+                                println!( "Pool {}: nonce {} derives no authority: {:?}.", sp.name,
+                                          state.nonce( ), err );
+                                problems = problems + 1;
+                            }
+                        }
+                    },
+                    Err( err ) => {
+                        println!( "Pool {}: cannot read the swap account: {:?}.", sp.name, err );
+                        problems = problems + 1;
+                    }
+                }
+            }
+
+            // so does the whirlpool account, which holds the current tick
+            if let ( Pool::Whirlpool( wp ), Some( Some( acc ) ) ) = ( p, fetched.first( ) ) {
+                match WhirlpoolInfo::unpack_from_slice( &acc.data ) {
+                    Ok( info ) => {
+                        for a_to_b in [ true, false ] {
+                            for start in info.tick_array_starts( a_to_b ) {
+                                let key = whirlpool::tick_array_address( &config.whirlpool_program,
+                                                                         &wp.account, start );
+                                if !tick_arrays.iter( ).any( |( _, _, k )| *k == key ) {
+                                    tick_arrays.push( ( p, start, key ) );
+                                }
+                            }
+                        }
+                    },
+                    Err( err ) => {
+                        println!( "Pool {}: cannot read the whirlpool account: {:?}.", wp.name, err );
+                        problems = problems + 1;
+                    }
+                }
+            }
+        }
+
+        // swaps pass all three arrays, so each has to exist even if the swap stays in the first
+        let keys: Vec<Pubkey> = tick_arrays.iter( ).map( |( _, _, key )| *key ).collect( );
+        match comm.accounts( &keys ) {
+            Ok( fetched ) => {
+                for ( ( p, start, key ), acc ) in tick_arrays.iter( ).zip( fetched.iter( ) ) {
+                    match acc {
+                        None => {
+                            println!( "Pool {}: tick array {} from tick {} does not exist, so swaps fail.",
+                                      p.get_name( ), key, start );
+                            problems = problems + 1;
+                        },
+                        Some( acc ) if acc.owner != config.whirlpool_program => {
+                            println!( "Pool {}: tick array {} is owned by {}, expected {}.", p.get_name( ),
+                                      key, acc.owner, config.whirlpool_program );
+                            problems = problems + 1;
+                        },
+                        _ => { }
+                    }
+                }
+            },
+            Err( err ) => {
+                error!( "Fetching tick arrays failed: {:?}", err );
+                problems = problems + 1;
+            }
+        }
+        problems
+    }
+
+    /* Takes the fee rate of every whirlpool from its account; it may change on chain, so a
+     * configured one only serves as a sanity check. Cpmm pools get the token programs of
+     * their mints from the pool state, which also has to agree on the vaults. */
+    pub fn read_chain_state( comm: &Communication, pools: &mut Vec<Pool> ) -> Result<(), String> {
+        let keys: Vec<Pubkey> = pools.iter( ).filter_map( |p| match p {
+            Pool::Whirlpool( wp ) => { Some( wp.account ) },
+            Pool::RaydiumCpmm( cp ) => { Some( cp.account ) },
+            _ => { None }
+        } ).collect( );
+
+        // get_multiple_accounts takes at most 100 keys
+        let mut fetched = Vec::new( );
+        for chunk in keys.chunks( 100 ) {
+            let res = comm.with_retries( || comm.rpc_client( ).get_multiple_accounts( chunk ) )
+                .map_err( |err| format!( "Fetching pool states failed: {:?}", err ) )?;
+            fetched.extend( res );
+        }
+
+        let mut fetched = fetched.into_iter( );
+        for p in pools.iter_mut( ) {
+            match p {
+                Pool::Whirlpool( wp ) => {
+                    let info = fetched.next( ).flatten( )
+                        .and_then( |acc| WhirlpoolInfo::unpack_from_slice( &acc.data ).ok( ) )
+                        .ok_or_else( || format!( "Pool {}: cannot read whirlpool account", wp.name ) )?;
+                    let fee_rate = info.fee_rate as u64;
+                    if wp.fees.trade_fee_numerator != 0 && wp.fees.trade_fee_numerator != fee_rate {
+                        warn!( "Pool {}: fee rate is {} on chain, not the configured {}.", wp.name, fee_rate,
+                               wp.fees.trade_fee_numerator );
+                    }
+                    wp.fees.trade_fee_numerator = fee_rate;
+                },
+                Pool::RaydiumCpmm( cp ) => {
+                    let info = fetched.next( ).flatten( )
+                        .and_then( |acc| CpmmPoolInfo::unpack_from_slice( &acc.data ).ok( ) )
+                        .ok_or_else( || format!( "Pool {}: cannot read pool state", cp.name ) )?;
+                    for i in 0 ..= 1 {
+                        if info.token_vaults[ i ] != cp.tokens[ i ].account {
+                            return Err( format!( "Pool {}: vault of token {} is {} on chain, not {}", cp.name, i,
+                                                 info.token_vaults[ i ], cp.tokens[ i ].account ) );
+                        }
+                    }
+                    cp.token_programs = info.token_programs;
+                },
+                _ => { }
+            }
+        }
+        Ok( ( ) )
+    }
+}
+
+impl ConfigSD {
+    /* Overrides fields by HIKARU_<FIELD> environment variables, which take precedence over
+     * the file. The values are converted and validated like those read from the file. */
+    fn apply_env( &mut self ) -> Result<(), ConfigError> {
+        macro_rules! env_override {
+            ( $wrap:expr; $( $field:ident ),* ) => { $(
+                let var = format!( "HIKARU_{}", stringify!( $field ).to_uppercase( ) );
+                if let Ok( value ) = env::var( &var ) {
+                    self.$field = $wrap( value.parse( ).map_err( |_| {
+                        ConfigError::Invalid( format!( "{} is garbage: {}", var, value ) )
+                    } )? );
+                }
+            )* }
+        }
+
+        env_override!( |v| v; cluster_url, cluster_url_send, start_currency, safety_percentage,
+                       minimum_gain, minimum_gain_p, minimum_gain_any, minimum_money, reserve_sol, slippage,
+                       max_cycle_length, dedup_mirror_cycles, minimum_display, cooldown, warmup_secs, greed,
+                       failure_cost, ev_min_samples, max_failure_backoff, extra_budget, wrap_sol, jito_tip,
+                       stable_two_hop, stable_balance_ttl, verify_before_send,
+                       rpc_retry_attempts, rpc_retry_delay, rpc_concurrency, skip_preflight,
+                       failover_error_rate, failover_window );
+        env_override!( Some; start_currency_name, minimum_gain_usd, start_currency_oracle,
+                       max_cycles, min_cycle_rate, min_pool_liquidity, max_price_jump, max_leg_impact,
+                       max_tx_per_sec, max_in_flight, compute_unit_limit, jito_url,
+                       estimated_compute_units, lamport_price,
+                       jito_tip_account, webhook_url, confirm_timeout, blockhash_max_age_ms, commitment,
+                       max_silence_secs, grpc_url, grpc_token, max_display );
+        Ok( ( ) )
     }
 }
 
 impl Config {
-    pub fn read_from_file<P: AsRef<Path>>( path: P ) -> Result<Self, Box<dyn Error>> {
-        let file = File::open( path )?;
-        let reader = BufReader::new( file );
-        let c: ConfigSD = serde_json::from_reader( reader )?;
-        Ok( Self::from( c ) )
+    pub fn read_from_file<P: AsRef<Path>>( path: P ) -> Result<Self, ConfigError> {
+        let mut c: ConfigSD = read_sd( path )?;
+        c.apply_env( )?;
+        Self::try_from( c )
+    }
+
+    /* Position of the start currency among start_currencies, which the amounts in toys follow */
+    fn start_slot( &self, start: usize ) -> usize {
+        // every cycle starts in one of them, construct_cycles and read_cycles see to that
+        self.start_currencies.iter( ).position( |s| *s == start ).unwrap_or( 0 )
+    }
+
+    /* minimum_gain in toys of the given start currency */
+    pub fn minimum_gain_in( &self, start: usize ) -> u128 {
+        self.minimum_gain[ self.start_slot( start ) ]
+    }
+
+    /* minimum_money in toys of the given start currency */
+    pub fn minimum_money_in( &self, start: usize ) -> u64 {
+        self.minimum_money[ self.start_slot( start ) ]
+    }
+
+    /* Checks whether turning gamble_money of the start currency into toys_out clears the
+     * configured gain thresholds */
+    pub fn is_profitable( &self, start: usize, toys_out: u128, gamble_money: u64 ) -> bool {
+        self.is_profitable_for( toys_out, gamble_money, self.minimum_gain_in( start ) )
+    }
+
+    /* Like is_profitable, with minimum_gain replaced by the given one */
+    pub fn is_profitable_for( &self, toys_out: u128, gamble_money: u64, minimum_gain: u128 ) -> bool {
+        let absolute = toys_out > gamble_money as u128 + minimum_gain;
+        let relative = toys_out as f64 > gamble_money as f64 * self.minimum_gain_p;
+
+        if self.minimum_gain_any {
+            absolute || relative
+        } else {
+            absolute && relative
+        }
+    }
+
+    /* Checks whether a cycle yielding toys_out for gamble_money is worth sending given how
+     * many of its confirmed submissions succeeded; too few samples don't count. */
+    pub fn has_positive_ev( &self, start: usize, toys_out: u128, gamble_money: u64,
+                            successes: u64, attempts: u64 ) -> bool {
+        if attempts == 0 || attempts < self.ev_min_samples {
+            return true;
+        }
+        let rate = successes as f64 / attempts as f64;
+        let net_gain = toys_out as f64 - gamble_money as f64;
+        rate * net_gain - ( 1.0 - rate ) * self.failure_cost[ self.start_slot( start ) ] as f64 > 0.0
+    }
+
+    /* Estimated cost of sending a cycle in toys of the start currency (start indexes the
+     * currencies): the signature fee, the priority fee and the jito tip. 0 if lamports can't be
+     * priced in the start currency. */
+    pub fn estimated_fee( &self, start: usize, start_currency: &Currency ) -> u128 {
+        let price = if start_currency.is_native( ) {
+            1.0
+        } else {
+            match &self.lamport_price {
+                Some( prices ) => { prices[ self.start_slot( start ) ] },
+                None => { return 0; }
+            }
+        };
+
+        let units = self.estimated_compute_units.or( self.compute_unit_limit )
+            .map_or( DEFAULT_COMPUTE_UNITS, |u| u as u64 );
+        // extra_budget is in micro-lamports per compute unit
+        let mut lamports = LAMPORTS_PER_SIGNATURE as u128
+            + ( self.extra_budget as u128 * units as u128 + 999_999 ) / 1_000_000;
+        if self.send_mode == SendMode::Jito {
+            lamports = lamports + self.jito_tip as u128;
+        }
+        ( lamports as f64 * price ) as u128
+    }
+
+    /* Whether constructing cycles needs the pools' current reserves */
+    pub fn cycles_need_prices( &self ) -> bool {
+        self.min_cycle_rate.is_some( ) || self.min_pool_liquidity.is_some( )
+    }
+
+    /* Print loop iterations a cycle sits out after the given number of consecutive failures */
+    pub fn failure_backoff( &self, failures: u32 ) -> u64 {
+        if failures == 0 {
+            return 0;
+        }
+        let wait = max( self.cooldown, 1 ).saturating_mul( 1u64 << min( failures - 1, 63 ) );
+        min( wait, self.max_failure_backoff )
+    }
+
+    /* Resolves start_currency_name (if given) to an index into the currency vector. */
+    pub fn resolve_start_currency( &mut self, currencies: &Vec<Currency> ) -> Result<(), ConfigError> {
+        if let Some( name ) = &self.start_currency_name {
+            match currencies.iter( ).position( |c| c.name == *name ) {
+                Some( idx ) => {
+                    // keep the amounts given for it, if it was among the start currencies
+                    let slot = self.start_slot( idx );
+                    self.minimum_gain = vec![ self.minimum_gain[ slot ] ];
+                    self.minimum_money = vec![ self.minimum_money[ slot ] ];
+                    self.failure_cost = vec![ self.failure_cost[ slot ] ];
+                    self.lamport_price = self.lamport_price.as_ref( ).map( |p| vec![ p[ slot ] ] );
+                    self.start_currency = idx;
+                    self.start_currencies = vec![ idx ];
+                },
+                None => {
+                    return Err( ConfigError::Invalid( format!( "Unknown start currency {}", name ) ) );
+                }
+            }
+        }
+
+        for idx in &self.start_currencies {
+            if *idx >= currencies.len( ) {
+                return Err( ConfigError::Invalid( format!( "Start currency index {} out of range", idx ) ) );
+            }
+        }
+        Ok( ( ) )
     }
 }
 
 impl SwapPool {
-    pub fn get_currency( &self, index: usize ) -> Token {
-        return self.tokens[ index ];
+    pub fn get_currency( &self, direction: Direction ) -> Token {
+        return self.tokens[ direction.index( ) ];
     }
 }
 
 impl RaydiumPool {
-    pub fn get_currency( &self, index: usize ) -> Token {
-        return self.tokens[ index ];
+    pub fn get_currency( &self, direction: Direction ) -> Token {
+        return self.tokens[ direction.index( ) ];
+    }
+
+    /// Reserves actually backing the amm, ordered like the pool's tokens: the vault
+    /// balances plus the liquidity parked in serum open orders, minus the pnl the amm
+    /// has yet to take.
+    pub fn effective_reserves( &self, accounts: &RaydiumAccounts ) -> Option<[ u64; 2 ]> {
+        let amm = accounts.amm?;
+        let open_orders = accounts.open_orders?;
+
+        let coin = if amm.token_coin == self.tokens[ 0 ].account {
+            0
+        } else if amm.token_coin == self.tokens[ 1 ].account {
+            1
+        } else {
+            return None;
+        };
+
+        let mut res = [ 0; 2 ];
+        res[ coin ] = accounts.vault_amounts[ coin ]?
+            .checked_add( open_orders.native_coin_total )?
+            .saturating_sub( amm.out_put.need_take_pnl_coin );
+        res[ 1 - coin ] = accounts.vault_amounts[ 1 - coin ]?
+            .checked_add( open_orders.native_pc_total )?
+            .saturating_sub( amm.out_put.need_take_pnl_pc );
+        Some( res )
+    }
+}
+
+impl RaydiumCpmmPool {
+    pub fn get_currency( &self, direction: Direction ) -> Token {
+        return self.tokens[ direction.index( ) ];
+    }
+
+    /// Reserves the amm prices swaps with, ordered like the pool's tokens: the vault
+    /// balances minus the protocol and fund fees that are yet to be collected.
+    pub fn effective_reserves( &self, accounts: &RaydiumAccounts ) -> Option<[ u64; 2 ]> {
+        let state = accounts.cpmm?;
+        if state.token_vaults[ 0 ] != self.tokens[ 0 ].account || state.token_vaults[ 1 ] != self.tokens[ 1 ].account {
+            return None;
+        }
+
+        let mut res = [ 0; 2 ];
+        for i in 0 ..= 1 {
+            res[ i ] = accounts.vault_amounts[ i ]?.checked_sub( state.pending_fees[ i ] )?;
+        }
+        Some( res )
+    }
+}
+
+impl WhirlpoolPool {
+    pub fn get_currency( &self, direction: Direction ) -> Token {
+        return self.tokens[ direction.index( ) ];
     }
 }
 
@@ -553,17 +2181,48 @@ impl Pool {
     pub fn fees( &self ) -> f64 {
         match self {
             Self::Swap( SwapPool{ fees: f, .. } )
-            | Self::Raydium( RaydiumPool{ fees: f, .. } ) => {
+            | Self::Raydium( RaydiumPool{ fees: f, .. } )
+            | Self::Whirlpool( WhirlpoolPool{ fees: f, .. } )
+            | Self::RaydiumCpmm( RaydiumCpmmPool{ fees: f, .. } ) => {
                 1.0 - Self::approximate_fees( f )
             }
         }
     }
 
-    pub fn get_currency( &self, index: usize ) -> Token {
+    /* Marginal exchange rate in ui units of the output token per ui unit of the input token,
+     * including fees but excluding price impact. */
+    pub fn spot_price( &self, pool_price: &PoolPrice, direction: Direction ) -> f64 {
+        let ( a, a_decs ) = pool_price.token_price[ direction.index( ) ].token_amount;
+        let ( b, b_decs ) = pool_price.token_price[ direction.flip( ).index( ) ].token_amount;
+        if a == 0.0 {
+            return 0.0;
+        }
+
+        match self {
+            Self::Swap( SwapPool{ curve: CurveType::Stable( _ ), .. } ) => {
+                // no closed form worth having; probe with an amount too small to move the price.
+                // swap works on the larger of both decimals for in- and output alike.
+                let decs = max( a_decs, b_decs ) as usize;
+                let probe = ( a * pow10( decs ) / 1_000_000.0 ).max( 1.0 );
+                let ( out, traded ) = pool_price.swap( probe as u128, direction, self );
+                if traded == 0 {
+                    return 0.0;
+                }
+                out as f64 / traded as f64
+            },
+            _ => {
+                b / a * self.fees( )
+            }
+        }
+    }
+
+    pub fn get_currency( &self, direction: Direction ) -> Token {
         match self {
             Self::Swap( SwapPool{ tokens: t, .. } )
-            | Self::Raydium( RaydiumPool{ tokens: t, .. } ) => {
-                return t[ index ];
+            | Self::Raydium( RaydiumPool{ tokens: t, .. } )
+            | Self::Whirlpool( WhirlpoolPool{ tokens: t, .. } )
+            | Self::RaydiumCpmm( RaydiumCpmmPool{ tokens: t, .. } ) => {
+                return t[ direction.index( ) ];
             }
         }
     }
@@ -572,18 +2231,99 @@ impl Pool {
         match self {
             Self::Swap( SwapPool{ swap_type: t, .. } ) => { t }
             Self::Raydium( _ ) => { "RayV4" }
+            Self::Whirlpool( _ ) => { "Whirl" }
+            Self::RaydiumCpmm( _ ) => { "RayCP" }
+        }
+    }
+
+    /* The exchange the pool belongs to, see DEX_NAMES */
+    pub fn dex_name( &self ) -> &str {
+        match self {
+            Self::Swap( SwapPool{ swap_type: t, .. } ) => { t }
+            Self::Raydium( _ ) => { "raydium" }
+            Self::Whirlpool( _ ) => { "whirlpool" }
+            Self::RaydiumCpmm( _ ) => { "raydium" }
         }
     }
 
     pub fn get_name( &self ) -> &String {
         match self {
             Self::Swap( SwapPool{ name: n, .. } )
-            | Self::Raydium( RaydiumPool{ name: n, .. } ) => {
+            | Self::Raydium( RaydiumPool{ name: n, .. } )
+            | Self::Whirlpool( WhirlpoolPool{ name: n, .. } )
+            | Self::RaydiumCpmm( RaydiumCpmmPool{ name: n, .. } ) => {
                 return n;
             }
         }
     }
 
+    /* Lists the accounts the pool refers to along with the programs allowed to own them.
+     * Program derived authorities don't need to exist and are left out. */
+    pub fn referenced_accounts( &self, config: &Config ) -> Vec<( &'static str, Pubkey, Vec<Pubkey> )> {
+        let token_owners = vec![ config.token_program, spl_token_2022::id( ) ];
+        let mut res = Vec::new( );
+
+        match self {
+            Self::Swap( SwapPool{ account: acc, pool_token_mint: pmt, fee_account: fees,
+                                  swap_program: program, .. } ) => {
+                res.push( ( "account", *acc, vec![ *program ] ) );
+                res.push( ( "pool_token_mint", *pmt, token_owners.clone( ) ) );
+                res.push( ( "fee_account", *fees, token_owners.clone( ) ) );
+            },
+            Self::Raydium( RaydiumPool{
+                pool_version: ray_v, account: amm_id, open_orders: amm_open_orders,
+                target_orders: amm_target_orders, serum_version: ser_v, serum_market: s_market,
+                serum_bids: s_bids, serum_asks: s_asks, serum_events: s_events, ..
+            } ) => {
+                let ray_program = if *ray_v == 4 {
+                    config.raydium_liquidity_program_v4
+                } else if *ray_v == 3 {
+                    config.raydium_liquidity_program_v3
+                } else {
+                    config.raydium_liquidity_program_v2
+                };
+                let serum_program = if *ser_v == 3 {
+                    config.serum_program_v3
+                } else {
+                    config.serum_program_v2
+                };
+
+                res.push( ( "account", *amm_id, vec![ ray_program ] ) );
+                if let Some( target_orders ) = amm_target_orders {
+                    res.push( ( "target_orders", *target_orders, vec![ ray_program ] ) );
+                }
+                res.push( ( "open_orders", *amm_open_orders, vec![ serum_program ] ) );
+                res.push( ( "serum_market", *s_market, vec![ serum_program ] ) );
+                for ( field, key ) in [ ( "serum_bids", s_bids ), ( "serum_asks", s_asks ),
+                                        ( "serum_events", s_events ) ].iter( ) {
+                    if let Some( key ) = key {
+                        res.push( ( *field, *key, vec![ serum_program ] ) );
+                    }
+                }
+            },
+            Self::Whirlpool( WhirlpoolPool{ account: acc, .. } ) => {
+                // the oracle is left out, it is never initialized; the tick arrays move with
+                // the price
+                res.push( ( "account", *acc, vec![ config.whirlpool_program ] ) );
+            },
+            Self::RaydiumCpmm( RaydiumCpmmPool{ account: acc, amm_config, observation, .. } ) => {
+                res.push( ( "account", *acc, vec![ config.raydium_cpmm_program ] ) );
+                res.push( ( "amm_config", *amm_config, vec![ config.raydium_cpmm_program ] ) );
+                res.push( ( "observation", *observation, vec![ config.raydium_cpmm_program ] ) );
+            }
+        }
+
+        for d in Direction::BOTH.iter( ) {
+            let tkn = self.get_currency( *d );
+            res.push( ( if *d == Direction::AtoB { "token 0" } else { "token 1" }, tkn.account, token_owners.clone( ) ) );
+            if let Some( extra ) = tkn.extra_account {
+                res.push( ( if *d == Direction::AtoB { "token 0 extra_account" } else { "token 1 extra_account" },
+                            extra, token_owners.clone( ) ) );
+            }
+        }
+        res
+    }
+
     pub fn needs_approval( &self ) -> bool {
         match self {
             Self::Swap( SwapPool{ needs_approve: appr, .. } ) => { *appr },
@@ -591,13 +2331,42 @@ impl Pool {
         }
     }
 
-    pub fn predict_swap( &self, toys_in: u128, swap_source_amount: u128,
+    /* Whether both tokens are the same currency; validate rejects such pools */
+    pub fn swaps_itself( &self ) -> bool {
+        self.get_currency( Direction::AtoB ).currency_idx == self.get_currency( Direction::BtoA ).currency_idx
+    }
+
+    /* Predicts swapping toys_in of the input token of direction into the other token; the
+     * amounts are the pool's reserves of the source and destination token. */
+    pub fn predict_swap( &self, toys_in: u128, direction: Direction, swap_source_amount: u128,
                          swap_destination_amount: u128 ) -> ( u128, u128 ) {
+        let trade_direction = match direction {
+            Direction::AtoB => { TradeDirection::AtoB },
+            Direction::BtoA => { TradeDirection::BtoA }
+        };
          match self {
             Self::Swap( SwapPool{ curve: c, fees: f, .. } )
             | Self::Raydium( RaydiumPool{ curve: c, fees: f, .. } ) => {
                 match c.get_curve( ).swap( toys_in, swap_source_amount,
-                    swap_destination_amount, TradeDirection::AtoB /*unused*/, &f ) {
+                    swap_destination_amount, trade_direction, &f ) {
+                    Some( SwapResult {
+                        source_amount_swapped: source_amount,
+                        destination_amount_swapped: amount_swapped,
+                        ..
+                    } ) => {
+                        ( amount_swapped, source_amount )
+                    },
+                    _ => {
+                        ( 0, 0 )
+                    }
+                }
+            },
+            Self::Whirlpool( WhirlpoolPool{ fees: f, .. } )
+            | Self::RaydiumCpmm( RaydiumCpmmPool{ fees: f, .. } ) => {
+                // within the current tick range the whirlpool trades like a constant
+                // product pool over its virtual reserves; cpmm pools do so over their vaults
+                match SwapCurve::default( ).swap( toys_in, swap_source_amount,
+                    swap_destination_amount, trade_direction, &f ) {
                     Some( SwapResult {
                         source_amount_swapped: source_amount,
                         destination_amount_swapped: amount_swapped,
@@ -616,9 +2385,10 @@ impl Pool {
     pub fn swap( &self, instructions: &mut Vec<Instruction>,
                  payer: &Pubkey, extra_payer: &Pubkey,
                  toys_in: u128, toys_out: u128,
-                 direction: usize, config: &Config, currencies: &Vec<Currency> ) -> bool {
+                 direction: Direction, pool_price: &PoolPrice,
+                 config: &Config, currencies: &Vec<Currency> ) -> bool {
         let tkn_a = self.get_currency( direction );
-        let tkn_b = self.get_currency( 1 - direction );
+        let tkn_b = self.get_currency( direction.flip( ) );
 
         match self {
             Self::Swap( SwapPool{ authority: auth, account: acc, pool_token_mint: pmt,
@@ -695,11 +2465,18 @@ impl Pool {
                 true
             }
             Self::Raydium( RaydiumPool{
-                pool_version: ray_v, account: amm_id, authority: amm_authority,
+                name, pool_version: ray_v, account: amm_id, authority: amm_authority,
                 open_orders: amm_open_orders, target_orders: amm_target_orders,
                 serum_version: ser_v, serum_market: s_market, serum_bids: s_bids,
                 serum_asks: s_asks, serum_events: s_events, serum_signer: s_signer, ..
             } ) => {
+                let required = [ ( "target_orders", amm_target_orders ), ( "serum_bids", s_bids ),
+                                 ( "serum_asks", s_asks ), ( "serum_events", s_events ),
+                                 ( "serum_signer", s_signer ) ];
+                if let Some( ( field, _ ) ) = required.iter( ).find( |( _, key )| key.is_none( ) ) {
+                    error!( "Pool {} missing {}, required for swap", name, field );
+                    return false;
+                }
                 match raydium::swap_base_in(
                     if *ray_v == 4 {
                         &config.raydium_liquidity_program_v4
@@ -711,7 +2488,7 @@ impl Pool {
                     &amm_id,
                     &amm_authority,
                     &amm_open_orders,
-                    &amm_target_orders,
+                    &amm_target_orders.unwrap( ),
                     &tkn_a.account,
                     &tkn_b.account,
                     if *ser_v == 3 {
@@ -720,9 +2497,9 @@ impl Pool {
                         &config.serum_program_v2
                     },
                     &s_market,
-                    &s_bids,
-                    &s_asks,
-                    &s_events,
+                    &s_bids.unwrap( ),
+                    &s_asks.unwrap( ),
+                    &s_events.unwrap( ),
                     &if let Some( exa ) = tkn_a.extra_account {
                         exa
                     } else {
@@ -733,7 +2510,7 @@ impl Pool {
                     } else {
                         return false;
                     },
-                    &s_signer,
+                    &s_signer.unwrap( ),
                     &currencies[ tkn_a.currency_idx ].account,
                     &currencies[ tkn_b.currency_idx ].account,
                     payer, // TODO: is this correct?
@@ -743,7 +2520,75 @@ impl Pool {
                 ) {
                     Ok( ins ) => { instructions.push( ins ); true },
                     Err( err ) => {
-                        println!( "creating instruction failed {:?}", err );
+                        error!( "creating instruction failed {:?}", err );
+                        std::process::exit( 1 )
+                    }
+                }
+            }
+            Self::Whirlpool( WhirlpoolPool{
+                name, account: acc, oracle, tokens, ..
+            } ) => {
+                // the arrays to cross depend on where the price is now
+                let tick_arrays = match pool_price.whirlpool {
+                    Some( info ) => {
+                        info.tick_arrays( &config.whirlpool_program, acc, direction == Direction::AtoB )
+                    },
+                    None => {
+                        warn!( "Pool {}: current tick unknown, cannot pick its tick arrays.", name );
+                        return false;
+                    }
+                };
+                // the user's accounts are passed in the pool's token order
+                match whirlpool::swap(
+                    &config.whirlpool_program,
+                    &config.token_program,
+                    payer,
+                    &acc,
+                    &currencies[ tokens[ 0 ].currency_idx ].account,
+                    &tokens[ 0 ].account,
+                    &currencies[ tokens[ 1 ].currency_idx ].account,
+                    &tokens[ 1 ].account,
+                    &tick_arrays,
+                    &oracle,
+
+                    toys_in as u64,
+                    toys_out as u64,
+                    direction == Direction::AtoB
+                ) {
+                    Ok( ins ) => { instructions.push( ins ); true },
+                    Err( err ) => {
+                        error!( "creating instruction failed {:?}", err );
+                        std::process::exit( 1 )
+                    }
+                }
+            }
+            Self::RaydiumCpmm( RaydiumCpmmPool{
+                account: acc, authority, amm_config, observation, token_programs, ..
+            } ) => {
+                let curr_a = &currencies[ tkn_a.currency_idx ];
+                let curr_b = &currencies[ tkn_b.currency_idx ];
+                match raydium::cpmm_swap_base_input(
+                    &config.raydium_cpmm_program,
+                    payer,
+                    &authority,
+                    &amm_config,
+                    &acc,
+                    &curr_a.account,
+                    &curr_b.account,
+                    &tkn_a.account,
+                    &tkn_b.account,
+                    &token_programs[ direction.index( ) ],
+                    &token_programs[ direction.flip( ).index( ) ],
+                    &curr_a.mint,
+                    &curr_b.mint,
+                    &observation,
+
+                    toys_in as u64,
+                    toys_out as u64
+                ) {
+                    Ok( ins ) => { instructions.push( ins ); true },
+                    Err( err ) => {
+                        error!( "creating instruction failed {:?}", err );
                         std::process::exit( 1 )
                     }
                 }
@@ -753,49 +2598,741 @@ impl Pool {
 }
 
 
-pub fn construct_cycles( config: &Config, pools: &Vec<Pool> ) -> Vec<Cycle> {
-    let start = config.start_currency;
+/* Index of each pool by name; None for names used by several pools */
+fn pools_by_name( pools: &Vec<Pool> ) -> HashMap<&String, Option<usize>> {
+    let mut res = HashMap::new( );
+    for ( i, p ) in pools.iter( ).enumerate( ) {
+        res.entry( p.get_name( ) ).and_modify( |idx| *idx = None ).or_insert( Some( i ) );
+    }
+    res
+}
+
+/* Writes the cycles as json for read_cycles, referring to the pools by name so the file
+ * survives changes to the pool config. Fails if a cycle uses a pool whose name is not unique. */
+pub fn write_cycles<P: AsRef<Path>>( path: P, cycles: &Vec<Cycle>,
+                                     pools: &Vec<Pool> ) -> Result<(), Box<dyn Error>> {
+    let by_name = pools_by_name( pools );
+    let mut sd = Vec::new( );
+    for c in cycles {
+        let mut named = Vec::new( );
+        for ( p, w ) in &c.path {
+            let name = pools[ *p ].get_name( );
+            if by_name[ name ].is_none( ) {
+                return Err( format!( "Several pools are named {}", name ).into( ) );
+            }
+            named.push( ( name.clone( ), *w ) );
+        }
+        sd.push( CycleSD{ path: named } );
+    }
+    let mut writer = BufWriter::new( File::create( path )? );
+    serde_json::to_writer( &mut writer, &sd )?;
+    writer.flush( )?;
+    Ok( ( ) )
+}
+
+/* Reads cycles written by write_cycles; every pool they name has to be among the given ones.
+ * Approval and gamble caps follow the current config. */
+pub fn read_cycles<P: AsRef<Path>>( path: P, config: &Config,
+                                    pools: &Vec<Pool> ) -> Result<Vec<Cycle>, Box<dyn Error>> {
+    let sd: Vec<CycleSD> = serde_json::from_reader( BufReader::new( File::open( path )? ) )?;
+    let by_name = pools_by_name( pools );
+    let mut cycles = Vec::new( );
+    for ( i, c ) in sd.iter( ).enumerate( ) {
+        let mut resolved = Vec::new( );
+        for ( name, w ) in &c.path {
+            match by_name.get( name ) {
+                Some( Some( p ) ) => { resolved.push( ( *p, *w ) ); },
+                Some( None ) => {
+                    return Err( format!( "Cycle {} refers to pool {}, but several pools have that name", i, name ).into( ) );
+                },
+                None => {
+                    return Err( format!( "Cycle {} refers to unknown pool {}", i, name ).into( ) );
+                }
+            }
+        }
+        cycles.push( Cycle{ needs_approval: resolved.iter( ).any( |( p, _ )| pools[ *p ].needs_approval( ) ),
+                            path: resolved, max_gamble: None } );
+    }
+    for ( i, c ) in cycles.iter( ).enumerate( ) {
+        if c.path.is_empty( ) {
+            return Err( format!( "Cycle {} is empty", i ).into( ) );
+        }
+        let start = c.start_currency( pools );
+        if !config.start_currencies.contains( &start ) {
+            return Err( format!( "Cycle {} doesn't start in a start currency", i ).into( ) );
+        }
+        let mut curr = start;
+        for ( p, w ) in &c.path {
+            if pools[ *p ].get_currency( *w ).currency_idx != curr {
+                return Err( format!( "Cycle {} doesn't chain at pool {}", i, pools[ *p ].get_name( ) ).into( ) );
+            }
+            curr = pools[ *p ].get_currency( w.flip( ) ).currency_idx;
+        }
+        if curr != start {
+            return Err( format!( "Cycle {} doesn't end in its start currency", i ).into( ) );
+        }
+    }
+    apply_gamble_caps( config, pools, &mut cycles );
+    Ok( cycles )
+}
+
+/* Highest product of marginal rates leading from each currency back to the start currency,
+ * by the maximum number of swaps. Pools may be reused, so this bounds any actual cycle. */
+fn best_return_rates( start: usize, pools: &Vec<Pool>, rates: &Vec<[ f64; 2 ]>,
+                      max_swaps: usize ) -> Vec<Vec<f64>> {
+    let currencies = pools.iter( )
+        .flat_map( |p| Direction::BOTH.iter( ).map( move |d| p.get_currency( *d ).currency_idx ) )
+        .fold( start + 1, |n, c| max( n, c + 1 ) );
+
+    let mut res: Vec<Vec<f64>> = vec![ vec![ 0.0; currencies ] ];
+    res[ 0 ][ start ] = 1.0;
+    for k in 1 ..= max_swaps {
+        let mut best = res[ k - 1 ].clone( );
+        for ( p, r ) in pools.iter( ).zip( rates.iter( ) ) {
+            for d in Direction::BOTH.iter( ) {
+                let from = p.get_currency( *d ).currency_idx;
+                let to = p.get_currency( d.flip( ) ).currency_idx;
+                best[ from ] = best[ from ].max( r[ d.index( ) ] * res[ k - 1 ][ to ] );
+            }
+        }
+        res.push( best );
+    }
+    res
+}
+
+/* Enumerates the cycles starting and ending in each of the start currencies, using every pool
+ * at most once and at most max_cycle_length pools. pool_prices are needed for min_cycle_rate,
+ * to skip cycles whose marginal rates can't reach it, and for min_pool_liquidity. */
+pub fn construct_cycles( config: &Config, pools: &Vec<Pool>,
+                         pool_prices: Option<&Vec<PoolPrice>> ) -> Result<Vec<Cycle>, Box<dyn Error>> {
     let mut results: Vec<Cycle> = Vec::new( );
 
-    let mut tmp: Vec<Cycle> = Vec::new( );
-    for p in 0 .. pools.len( ) {
-        for w in 0 ..= 1 {
-            if pools[ p ].get_currency( w ).currency_idx == start {
-                let mut cpy = Vec::new( );
-                cpy.push(( p, w ));
-                tmp.push( Cycle{ needs_approval: pools[ p ].needs_approval( ), path: cpy } );
+    // a single swap can't end in the currency it started from
+    if config.max_cycle_length < 2 {
+        return Err( format!( "max_cycle_length is {}, a cycle needs at least 2 pools",
+                             config.max_cycle_length ).into( ) );
+    }
+
+    // marginal rates by pool and direction, to drop partial cycles that can't pay off
+    let rates: Option<Vec<[ f64; 2 ]>> = pool_prices.map( |pp| {
+        pools.iter( ).zip( pp.iter( ) ).map( |( p, price )| {
+            [ p.spot_price( price, Direction::AtoB ), p.spot_price( price, Direction::BtoA ) ]
+        } ).collect( )
+    } );
+    let min_rate = config.min_cycle_rate.unwrap_or( 0.0 );
+
+    // pools too thin to trade are left out of every cycle; valued in the first start currency
+    let mut thin = vec![ false; pools.len( ) ];
+    if let ( Some( min_liquidity ), Some( pp ), Some( r ) ) = ( config.min_pool_liquidity, pool_prices, &rates ) {
+        let best = best_return_rates( config.start_currency, pools, r, config.max_cycle_length as usize );
+        let value = &best[ config.max_cycle_length as usize ];
+        for ( i, ( p, price ) ) in pools.iter( ).zip( pp.iter( ) ).enumerate( ) {
+            let liquidity: f64 = Direction::BOTH.iter( ).map( |d| {
+                price.token_price[ d.index( ) ].token_amount.0 * value[ p.get_currency( *d ).currency_idx ]
+            } ).sum( );
+            if liquidity < min_liquidity {
+                warn!( "Leaving out pool {}, its liquidity of {} is below min_pool_liquidity.",
+                       p.get_name( ), liquidity );
+                thin[ i ] = true;
             }
         }
     }
 
-    for _i in 1 .. config.max_cycle_length {
-        let mut tmp2: Vec<Cycle> = Vec::new( );
+    'starts: for &start in &config.start_currencies {
+        let best_return = rates.as_ref( ).map( |r| {
+            best_return_rates( start, pools, r, config.max_cycle_length as usize )
+        } );
+
+        // partial cycles along with their product of marginal rates so far
+        let mut tmp: Vec<( Cycle, f64 )> = vec![ ( Cycle{ needs_approval: false, path: Vec::new( ),
+                                                           max_gamble: None }, 1.0 ) ];
+
+        for len in 1 ..= config.max_cycle_length as usize {
+            let mut tmp2: Vec<( Cycle, f64 )> = Vec::new( );
+
+            for ( Cycle{ path: c, needs_approval: n, .. }, rate ) in &tmp {
+                let lst_out_currency = match c.last( ) {
+                    Some( ( lst_pool, lst_dir ) ) => { pools[ *lst_pool ].get_currency( lst_dir.flip( ) ).currency_idx },
+                    None => { start }
+                };
+
+                'pools: for p in 0 .. pools.len( ) {
+                    // a pool swapping a currency for itself would close a cycle on its own
+                    if thin[ p ] || pools[ p ].swaps_itself( ) { continue; }
+                    // avoid using a pool twice
+                    for ( cc, _ ) in c { if *cc == p { continue 'pools; } }
+
+                    for &w in Direction::BOTH.iter( ) {
+                        if pools[ p ].get_currency( w ).currency_idx == lst_out_currency {
+                            let nn = *n || pools[ p ].needs_approval( );
+                            let rr = rate * rates.as_ref( ).map_or( 1.0, |r| r[ p ][ w.index( ) ] );
+                            let out = pools[ p ].get_currency( w.flip( ) ).currency_idx;
+                            let mut cpy = c.clone( );
+                            cpy.push(( p, w ));
+                            if out == start {
+                                if rates.is_some( ) && rr < min_rate { continue; }
+                                results.push( Cycle{ path: cpy, needs_approval: nn, max_gamble: None } );
+                                if let Some( max ) = config.max_cycles {
+                                    if results.len( ) >= max {
+                                        warn!( "Stopped constructing cycles at max_cycles = {}.", max );
+                                        break 'starts;
+                                    }
+                                }
+                                continue;
+                            }
+                            // even the best way back can't make up for the rate so far
+                            if let Some( best ) = &best_return {
+                                if rr * best[ config.max_cycle_length as usize - len ][ out ] < min_rate {
+                                    continue;
+                                }
+                            }
+                            tmp2.push( ( Cycle{ path: cpy, needs_approval: nn, max_gamble: None }, rr ) );
+                        }
+                    }
+                }
+            }
+
+            if let Some( max ) = config.max_cycles {
+                if tmp2.len( ) > max {
+                    warn!( "Keeping {} of {} partial cycles of {} pools, see max_cycles.",
+                           max, tmp2.len( ), len );
+                    // the most promising first; without rates the order is kept
+                    tmp2.sort_by( |a, b| b.1.partial_cmp( &a.1 ).unwrap_or( Ordering::Equal ) );
+                    tmp2.truncate( max );
+                }
+            }
+            tmp = tmp2;
+        }
+    }
+
+    if config.dedup_mirror_cycles {
+        let mut seen: HashSet<Vec<( usize, Direction )>> = HashSet::new( );
+        results.retain( |c| {
+            if seen.contains( &c.mirror( ).path ) {
+                return false;
+            }
+            seen.insert( c.path.clone( ) );
+            true
+        } );
+    }
+
+    apply_gamble_caps( config, pools, &mut results );
+    return Ok( results );
+}
 
-        for Cycle{ path: c, needs_approval: n } in &tmp {
-            let ( lst_pool, lst_in_tkn_idx ) = c.last( ).unwrap( );
-            let lst_out_tkn = pools[ *lst_pool ].get_currency( 1 - lst_in_tkn_idx );
+/* Applies the configured gamble caps; the tightest matching one wins */
+fn apply_gamble_caps( config: &Config, pools: &[ Pool ], cycles: &mut [ Cycle ] ) {
+    for c in cycles {
+        for cap in &config.max_gamble {
+            let applies = cap.pools.iter( ).all( |name| {
+                c.path.iter( ).any( |( p, _ )| pools[ *p ].get_name( ) == name )
+            } );
+            if applies {
+                c.max_gamble = Some( c.max_gamble.map_or( cap.amount, |m| min( m, cap.amount ) ) );
+            }
+        }
+    }
+}
 
-            'pools: for p in 0 .. pools.len( ) {
-                // avoid using a pool twice
-                for ( cc, _ ) in c { if *cc == p { continue 'pools; } }
+/* The cycles construct_cycles finds on pools[ ..= new_pool ] that trade the pool at new_pool,
+ * i.e. what adding that pool to the ones before it adds to existing. Pools after new_pool are
+ * not used, so pools appended one after the other are covered by extending with each in turn.
+ * min_cycle_rate, min_pool_liquidity and max_cycles are not applied, they need the whole
+ * picture. */
+pub fn extend_cycles( existing: &[ Cycle ], new_pool: usize, config: &Config,
+                      pools: &[ Pool ] ) -> Vec<Cycle> {
+    let max_len = config.max_cycle_length as usize;
+    let input = |( p, w ): ( usize, Direction )| pools[ p ].get_currency( w ).currency_idx;
+    let output = |( p, w ): ( usize, Direction )| pools[ p ].get_currency( w.flip( ) ).currency_idx;
+    if pools[ new_pool ].swaps_itself( ) {
+        return Vec::new( );
+    }
 
-                for w in 0 ..= 1 {
-                    if pools[ p ].get_currency( w ).currency_idx == lst_out_tkn.currency_idx {
-                        let nn = *n || pools[ p ].needs_approval( );
+    // closed paths starting with the new pool, in either direction
+    let mut loops: Vec<Vec<( usize, Direction )>> = Vec::new( );
+    for &w in Direction::BOTH.iter( ) {
+        let mut tmp = vec![ vec![ ( new_pool, w ) ] ];
+        for _ in 1 .. max_len {
+            let mut tmp2 = Vec::new( );
+            for c in &tmp {
+                let lst_out_currency = output( *c.last( ).unwrap( ) );
+                'pools: for p in 0 ..= new_pool {
+                    if pools[ p ].swaps_itself( ) { continue; }
+                    // avoid using a pool twice
+                    for ( cc, _ ) in c { if *cc == p { continue 'pools; } }
+
+                    for &d in Direction::BOTH.iter( ) {
+                        if input( ( p, d ) ) != lst_out_currency { continue; }
                         let mut cpy = c.clone( );
-                        cpy.push(( p, w ));
-                        if pools[ p ].get_currency( 1 - w ).currency_idx == config.start_currency {
-                            results.push( Cycle{ path: cpy.clone( ), needs_approval: nn } );
-                            continue;
+                        cpy.push(( p, d ));
+                        if output( ( p, d ) ) == input( cpy[ 0 ] ) {
+                            loops.push( cpy.clone( ) );
                         }
-                        tmp2.push( Cycle{ path: cpy, needs_approval: nn } );
+                        tmp2.push( cpy );
                     }
                 }
             }
+            tmp = tmp2;
+        }
+    }
+
+    // construct_cycles ends a cycle whenever it gets back to the start currency, so a loop
+    // is a cycle only from a start currency it passes exactly once
+    let mut seen: HashSet<Vec<( usize, Direction )>> = existing.iter( ).map( |c| c.path.clone( ) ).collect( );
+    let mut results: Vec<Cycle> = Vec::new( );
+    for l in &loops {
+        for &start in &config.start_currencies {
+            let at: Vec<usize> = ( 0 .. l.len( ) ).filter( |k| input( l[ *k ] ) == start ).collect( );
+            if at.len( ) != 1 { continue; }
+            let mut path = l[ at[ 0 ] .. ].to_vec( );
+            path.extend_from_slice( &l[ .. at[ 0 ] ] );
+
+            let cycle = Cycle{ needs_approval: path.iter( ).any( |( p, _ )| pools[ *p ].needs_approval( ) ),
+                               path: path, max_gamble: None };
+            if seen.contains( &cycle.path )
+                || ( config.dedup_mirror_cycles && seen.contains( &cycle.mirror( ).path ) ) {
+                continue;
+            }
+            seen.insert( cycle.path.clone( ) );
+            results.push( cycle );
+        }
+    }
+
+    apply_gamble_caps( config, pools, &mut results );
+    results
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /* A deterministic key, told apart by a kind and an index */
+    pub fn test_key( kind: u8, idx: u8 ) -> Pubkey {
+        let mut key = [ 0u8; 32 ];
+        key[ 0 ] = kind;
+        key[ 1 ] = idx;
+        Pubkey::new_from_array( key )
+    }
+
+    /* The config file contents with every required field set; the fields in extra replace them */
+    pub fn test_config_value( extra: serde_json::Value ) -> serde_json::Value {
+        let mut sd = serde_json::json!( {
+            "cluster_url":                  "http://127.0.0.1:8899",
+            "cluster_url_send":             "http://127.0.0.1:8899",
+            "safety_percentage":            1.0,
+            "minimum_gain":                 0,
+            "minimum_money":                1000,
+            "slippage":                     0.0,
+            "max_cycle_length":             3,
+            "minimum_display":              1.0,
+            "cooldown":                     10,
+            "token_program":                "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
+            "associate_token_program":      "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL",
+            "swap_program":                 "SwapsVeCiPHMUAtzQWZw7RjsKjgCjhwU55QGu4U1Szw",
+            "step_swap_program":            "SSwpMgqNDsyV7mAgN9ady4bDVu5ySjmmXejXvy2vLt1",
+            "orca_swap_program":            "DjVE6JNiYqPL2QXyCUUh8rNjHrbz9hXHNYt99MQ59qw1",
+            "orca_swap_program_v2":         "9W959DqEETiGZocYWCQPaJ6sBmUzgfxXfqGeTEdp3aQP",
+            "raydium_liquidity_program_v2": "RVKd61ztZW9GUwhRbbLoYVRE5Xf1B2tVscKqwZqXgEr",
+            "raydium_liquidity_program_v3": "27haf8L6oxUeXrHrgEgsexjSY5hbVUWEmvv9Nyxg8vQv",
+            "raydium_liquidity_program_v4": "675kPX9MHTjS2zt1qfr1NYHuzeLXfQM9H24wFSUt1Mp8",
+            "serum_program_v2":             "EUqojwWA2rd19FZrzeBncJsm38Jm1hEhE3zsmX3bRc2o",
+            "serum_program_v3":             "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin"
+        } );
+        for ( field, value ) in extra.as_object( ).expect( "extra fields are an object" ) {
+            sd[ field ] = value.clone( );
+        }
+        sd
+    }
+
+    /* A config with every required field set; the fields in extra replace them */
+    pub fn test_config( extra: serde_json::Value ) -> Config {
+        let sd: ConfigSD = serde_json::from_value( test_config_value( extra ) ).unwrap( );
+        Config::try_from( sd ).unwrap( )
+    }
+
+    /* n currencies with 6 decimals, named C0, C1, .. */
+    pub fn test_currencies( n: u8 ) -> Vec<Currency> {
+        ( 0 .. n ).map( |i| Currency {
+            name:      format!( "C{}", i ),
+            mint:      test_key( 1, i ),
+            decimals:  6,
+            account:   test_key( 2, i ),
+            usd_price: None,
+            oracle:    None,
+        } ).collect( )
+    }
+
+    /* Swap pool idx, named P<idx>, trading currency a (token 0) for b (token 1) */
+    pub fn test_swap_pool( config: &Config, idx: u8, a: usize, b: usize, curve: &str,
+                           needs_approve: bool ) -> Pool {
+        let sd = test_swap_pool_sd( config, idx, a, b, curve, needs_approve );
+        Pool::try_from( PoolSD::Swap( sd ), config ).unwrap( )
+    }
+
+    fn test_swap_pool_sd( config: &Config, idx: u8, a: usize, b: usize, curve: &str,
+                          needs_approve: bool ) -> SwapPoolSD {
+        let account = test_key( 3, idx );
+        let ( authority, _ ) = Pubkey::find_program_address( &[ &account.to_bytes( ) ], &config.swap_program );
+        SwapPoolSD {
+            name:            format!( "P{}", idx ),
+            account:         account.to_string( ),
+            authority:       authority.to_string( ),
+            pool_token_mint: test_key( 4, idx ).to_string( ),
+            fee_account:     test_key( 5, idx ).to_string( ),
+            tokens:          [ TokenSD{ currency_idx: a, account: test_key( 6, idx ).to_string( ), extra_account: None },
+                               TokenSD{ currency_idx: b, account: test_key( 7, idx ).to_string( ), extra_account: None } ],
+            curve:           curve.to_string( ),
+            curve_param:     if curve == "stable" { 100 } else { 0 },
+            needs_approve:   needs_approve,
+            disabled:        false,
+        }
+    }
+
+    /* Constant product pool idx trading currency a for b */
+    pub fn test_pool( config: &Config, idx: u8, a: usize, b: usize ) -> Pool {
+        test_swap_pool( config, idx, a, b, "constant-product", false )
+    }
+
+    /* Cpmm pool idx, named P<idx>, with the vaults of test_swap_pool and a 0.25% fee */
+    pub fn test_cpmm_pool( config: &Config, idx: u8, a: usize, b: usize ) -> Pool {
+        let sd = RaydiumCpmmPoolSD {
+            name:        format!( "P{}", idx ),
+            account:     test_key( 3, idx ).to_string( ),
+            amm_config:  test_key( 4, idx ).to_string( ),
+            observation: test_key( 5, idx ).to_string( ),
+            fee_rate:    2500,
+            tokens:      [ TokenSD{ currency_idx: a, account: test_key( 6, idx ).to_string( ), extra_account: None },
+                           TokenSD{ currency_idx: b, account: test_key( 7, idx ).to_string( ), extra_account: None } ],
+            disabled:    false,
+        };
+        Pool::try_from( PoolSD::RaydiumCpmm( sd ), config ).unwrap( )
+    }
+
+    /* Checks that every cycle starts in a start currency, chains and ends where it started */
+    fn assert_well_formed( cycles: &Vec<Cycle>, config: &Config, pools: &Vec<Pool> ) {
+        for c in cycles {
+            let start = c.start_currency( pools );
+            assert!( config.start_currencies.contains( &start ) );
+            let mut curr = start;
+            for ( p, w ) in &c.path {
+                assert_eq!( pools[ *p ].get_currency( *w ).currency_idx, curr );
+                curr = pools[ *p ].get_currency( w.flip( ) ).currency_idx;
+            }
+            assert_eq!( curr, start );
+        }
+    }
+
+    #[test]
+    fn triangle_has_a_cycle_each_way( ) {
+        let config = test_config( serde_json::json!( { "dedup_mirror_cycles": false } ) );
+        let pools = vec![ test_pool( &config, 0, 0, 1 ), test_pool( &config, 1, 1, 2 ),
+                          test_pool( &config, 2, 2, 0 ) ];
+
+        let cycles = construct_cycles( &config, &pools, None ).unwrap( );
+        assert_well_formed( &cycles, &config, &pools );
+        let paths: Vec<_> = cycles.iter( ).map( |c| c.path.clone( ) ).collect( );
+        assert_eq!( paths.len( ), 2 );
+        assert!( paths.contains( &vec![ ( 0, Direction::AtoB ), ( 1, Direction::AtoB ), ( 2, Direction::AtoB ) ] ) );
+        assert!( paths.contains( &vec![ ( 2, Direction::BtoA ), ( 1, Direction::BtoA ), ( 0, Direction::BtoA ) ] ) );
+    }
+
+    #[test]
+    fn cycles_respect_max_cycle_length( ) {
+        let config = test_config( serde_json::json!( { "max_cycle_length": 2 } ) );
+        let pools = vec![ test_pool( &config, 0, 0, 1 ), test_pool( &config, 1, 1, 2 ),
+                          test_pool( &config, 2, 2, 0 ), test_pool( &config, 3, 1, 0 ) ];
+
+        // only the two pools sharing a pair close a cycle of two swaps
+        let cycles = construct_cycles( &config, &pools, None ).unwrap( );
+        assert_well_formed( &cycles, &config, &pools );
+        assert!( !cycles.is_empty( ) );
+        for c in &cycles {
+            assert_eq!( c.path.len( ), 2 );
+            assert!( c.path.iter( ).all( |( p, _ )| *p == 0 || *p == 3 ) );
+        }
+    }
+
+    /* Pools over four currencies, with two pools on the C0/C1 pair */
+    pub fn test_graph( config: &Config ) -> Vec<Pool> {
+        vec![ test_pool( config, 0, 0, 1 ), test_pool( config, 1, 1, 2 ), test_pool( config, 2, 2, 0 ),
+              test_pool( config, 3, 1, 0 ), test_pool( config, 4, 2, 3 ), test_pool( config, 5, 3, 0 ),
+              test_pool( config, 6, 3, 1 ) ]
+    }
+
+    #[test]
+    fn dedup_is_on_by_default( ) {
+        assert!( test_config( serde_json::json!( { } ) ).dedup_mirror_cycles );
+    }
+
+    #[test]
+    fn dedup_keeps_one_of_each_mirror_pair( ) {
+        let all_config = test_config( serde_json::json!( { "dedup_mirror_cycles": false, "max_cycle_length": 4 } ) );
+        let dedup_config = test_config( serde_json::json!( { "dedup_mirror_cycles": true, "max_cycle_length": 4 } ) );
+        let pools = test_graph( &all_config );
+
+        let all = construct_cycles( &all_config, &pools, None ).unwrap( );
+        let dedup = construct_cycles( &dedup_config, &pools, None ).unwrap( );
+        assert!( !dedup.is_empty( ) );
+        assert_eq!( all.len( ), 2 * dedup.len( ) );
+
+        let all_paths: HashSet<_> = all.iter( ).map( |c| c.path.clone( ) ).collect( );
+        let dedup_paths: HashSet<_> = dedup.iter( ).map( |c| c.path.clone( ) ).collect( );
+        for c in &dedup {
+            assert!( all_paths.contains( &c.path ) );
+            assert!( all_paths.contains( &c.mirror( ).path ) );
+            assert!( !dedup_paths.contains( &c.mirror( ).path ) );
+        }
+    }
+
+    #[test]
+    fn same_currency_pool_is_rejected_and_never_cycled( ) {
+        let config = test_config( serde_json::json!( { "dedup_mirror_cycles": false } ) );
+        let mut pools = test_graph( &config );
+        pools.push( test_pool( &config, 7, 1, 1 ) );
+        let same = pools.len( ) - 1;
+
+        assert!( PoolConfig::validate( &pools, &test_currencies( 4 ) ).is_err( ) );
+        assert!( PoolConfig::validate( &pools[ .. same ].to_vec( ), &test_currencies( 4 ) ).is_ok( ) );
+
+        let cycles = construct_cycles( &config, &pools, None ).unwrap( );
+        assert!( !cycles.is_empty( ) );
+        assert!( cycles.iter( ).all( |c| c.path.iter( ).all( |( p, _ )| *p != same ) ) );
+        assert!( extend_cycles( &cycles, same, &config, &pools ).is_empty( ) );
+    }
+
+    /* Writes the value as name.json and name.yaml to the temp dir, returning both paths */
+    fn write_json_and_yaml( name: &str, value: &serde_json::Value ) -> ( std::path::PathBuf, std::path::PathBuf ) {
+        let base = std::env::temp_dir( ).join( format!( "hikaru-{}-{}", std::process::id( ), name ) );
+        let ( json, yaml ) = ( base.with_extension( "json" ), base.with_extension( "yaml" ) );
+        std::fs::write( &json, serde_json::to_string_pretty( value ).unwrap( ) ).unwrap( );
+        std::fs::write( &yaml, serde_yaml::to_string( value ).unwrap( ) ).unwrap( );
+        ( json, yaml )
+    }
+
+    #[test]
+    fn json_and_yaml_configs_read_the_same( ) {
+        let value = test_config_value( serde_json::json!( {
+            "start_currency":   [ 0, 1 ],
+            "minimum_money":    [ 1000, 2000 ],
+            "gamble_optimizer": { "Sampled": 16 },
+            "max_cycles":       100,
+            "slippage":         0.001
+        } ) );
+        let ( json, yaml ) = write_json_and_yaml( "config", &value );
+        let from_json = Config::read_from_file( &json ).unwrap( );
+        let from_yaml = Config::read_from_file( &yaml ).unwrap( );
+        for path in &[ json, yaml ] { std::fs::remove_file( path ).unwrap( ); }
+        assert_eq!( format!( "{:?}", from_json ), format!( "{:?}", from_yaml ) );
+        assert_eq!( from_yaml.start_currencies, vec![ 0, 1 ] );
+    }
+
+    #[test]
+    fn json_and_yaml_currencies_read_the_same( ) {
+        let value = serde_json::json!( {
+            "wallet_path": "wallet.json",
+            "currencies":  test_currencies( 2 ).iter( ).map( |c| serde_json::json!( {
+                "name":      c.name,
+                "mint":      c.mint.to_string( ),
+                "decimals":  c.decimals,
+                "account":   c.account.to_string( ),
+                "usd_price": 1.0
+            } ) ).collect::<Vec<_>>( )
+        } );
+        let ( json, yaml ) = write_json_and_yaml( "currencies", &value );
+        let from_json = CurrencyConfig::read_from_file( &json ).unwrap( );
+        let from_yaml = CurrencyConfig::read_from_file( &yaml ).unwrap( );
+        for path in &[ json, yaml ] { std::fs::remove_file( path ).unwrap( ); }
+        assert_eq!( format!( "{:?}", from_json ), format!( "{:?}", from_yaml ) );
+        assert_eq!( from_yaml.currencies.len( ), 2 );
+    }
+
+    #[test]
+    fn json_and_yaml_pools_read_the_same( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let sd = PoolConfigSD{ pools: vec![
+            PoolSD::Swap( test_swap_pool_sd( &config, 0, 0, 1, "constant-product", false ) ),
+            PoolSD::Swap( test_swap_pool_sd( &config, 1, 1, 0, "stable", true ) ) ] };
+        let value = serde_json::to_value( &sd ).unwrap( );
+        let ( json, yaml ) = write_json_and_yaml( "pools", &value );
+        let from_json = PoolConfig::read_from_file( &json, &config ).unwrap( );
+        let from_yaml = PoolConfig::read_from_file( &yaml, &config ).unwrap( );
+        for path in &[ json, yaml ] { std::fs::remove_file( path ).unwrap( ); }
+        assert_eq!( format!( "{:?}", from_json ), format!( "{:?}", from_yaml ) );
+        assert_eq!( from_yaml.len( ), 2 );
+    }
+
+    #[test]
+    fn rescale_toys_is_exact_where_floats_are_not( ) {
+        let toys = u64::MAX as u128;
+        let float_down = ( toys as f64 / pow10( 3 ) ) as u128;
+        let float_up = ( toys as f64 * pow10( 3 ) ) as u128;
+
+        assert_eq!( rescale_toys( toys, 9, 6 ), 18_446_744_073_709_551 );
+        assert_ne!( float_down, rescale_toys( toys, 9, 6 ) );
+        assert_eq!( rescale_toys( toys, 6, 9 ), 18_446_744_073_709_551_615_000 );
+        assert_ne!( float_up, rescale_toys( toys, 6, 9 ) );
+        // scaling down and back up only drops the digits below the coarser decimals
+        for &t in &[ toys, toys - 1, toys - 999, toys / 7 ] {
+            assert_eq!( rescale_toys( rescale_toys( t, 9, 6 ), 6, 9 ), t - t % 1000 );
+        }
+        assert_eq!( rescale_toys( toys, 6, 6 ), toys );
+        assert_eq!( rescale_toys( u128::MAX, 0, 9 ), u128::MAX );
+    }
+
+    #[test]
+    fn constant_product_predicts_both_directions_alike( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let pool = test_pool( &config, 0, 0, 1 );
+        let ( a, b ) = ( 1_000_000_000_000u128, 3_000_000_000_000u128 );
+
+        for &toys_in in &[ 1_000u128, 1_000_000, 1_000_000_000 ] {
+            let ( out_ab, in_ab ) = pool.predict_swap( toys_in, Direction::AtoB, a, b );
+            let ( out_ba, in_ba ) = pool.predict_swap( toys_in, Direction::BtoA, a, b );
+            assert_eq!( ( out_ab, in_ab ), ( out_ba, in_ba ) );
+            assert_eq!( in_ab, toys_in );
+
+            // x * b / ( a + x ) after the fees, give or take rounding
+            let x = toys_in as f64 * pool.fees( );
+            let expected = x * b as f64 / ( a as f64 + x );
+            assert!( ( out_ab as f64 - expected ).abs( ) <= 1.0 + expected * 1e-9 );
+        }
+    }
+
+    #[test]
+    fn stable_predicts_near_parity_in_both_directions( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let stable = test_swap_pool( &config, 0, 0, 1, "stable", false );
+        let product = test_pool( &config, 1, 0, 1 );
+        let toys_in = 1_000_000_000u128;
+
+        // balanced, a stable pool trades close to one for one, either way
+        let big = 1_000_000_000_000u128;
+        let ( out_ab, _ ) = stable.predict_swap( toys_in, Direction::AtoB, big, big );
+        let ( out_ba, _ ) = stable.predict_swap( toys_in, Direction::BtoA, big, big );
+        assert_eq!( out_ab, out_ba );
+        assert!( out_ab < toys_in );
+        assert!( out_ab as f64 >= toys_in as f64 * stable.fees( ) * 0.9999 );
+        assert!( out_ab > product.predict_swap( toys_in, Direction::AtoB, big, big ).0 );
+
+        // the scarce token fetches more than parity, the abundant one less
+        let ( cheap, _ ) = stable.predict_swap( toys_in, Direction::AtoB, big, 2 * big );
+        let ( dear, _ ) = stable.predict_swap( toys_in, Direction::BtoA, 2 * big, big );
+        assert!( cheap > out_ab );
+        assert!( dear < out_ab );
+    }
+
+    /* Cycles over all pools, built from the first few and extended by each later one */
+    fn extended_cycles( config: &Config, pools: &Vec<Pool>, first: usize ) -> Vec<Cycle> {
+        let mut cycles = construct_cycles( config, &pools[ .. first ].to_vec( ), None ).unwrap( );
+        for p in first .. pools.len( ) {
+            let added = extend_cycles( &cycles, p, config, pools );
+            cycles.extend( added );
+        }
+        cycles
+    }
+
+    #[test]
+    fn extending_matches_rebuilding( ) {
+        for &dedup in &[ false, true ] {
+            for starts in &[ vec![ 0 ], vec![ 0, 1 ], vec![ 3, 1, 2 ] ] {
+                let config = test_config( serde_json::json!( { "dedup_mirror_cycles": dedup,
+                                                               "start_currency": starts,
+                                                               "minimum_money": vec![ 1000; starts.len( ) ],
+                                                               "max_cycle_length": 4 } ) );
+                let pools = test_graph( &config );
+                let rebuilt = construct_cycles( &config, &pools, None ).unwrap( );
+
+                for first in 1 .. pools.len( ) {
+                    let extended = extended_cycles( &config, &pools, first );
+                    assert_well_formed( &extended, &config, &pools );
+                    assert_eq!( extended.len( ), rebuilt.len( ) );
+                    // with dedup either of a mirror pair may be the one kept
+                    let key = |c: &Cycle| {
+                        let plain = |path: &Vec<( usize, Direction )>| -> Vec<( usize, usize )> {
+                            path.iter( ).map( |( p, d )| ( *p, d.index( ) ) ).collect( )
+                        };
+                        if dedup { min( plain( &c.path ), plain( &c.mirror( ).path ) ) } else { plain( &c.path ) }
+                    };
+                    let a: HashSet<_> = extended.iter( ).map( key ).collect( );
+                    let b: HashSet<_> = rebuilt.iter( ).map( key ).collect( );
+                    assert_eq!( a, b, "dedup {}, start {:?}, first {}", dedup, starts, first );
+                }
+            }
         }
+    }
 
-        tmp = tmp2;
+    #[test]
+    fn cycles_file_follows_pools_by_name( ) {
+        let config = test_config( serde_json::json!( { "dedup_mirror_cycles": false } ) );
+        let pools = test_graph( &config );
+        let cycles = construct_cycles( &config, &pools, None ).unwrap( );
+        let path = std::env::temp_dir( ).join( format!( "hikaru-{}-cycles.json", std::process::id( ) ) );
+        write_cycles( &path, &cycles, &pools ).unwrap( );
+
+        // the same pools in a different order
+        let mut reordered = pools.clone( );
+        reordered.reverse( );
+        let read = read_cycles( &path, &config, &reordered ).unwrap( );
+        assert_eq!( read.len( ), cycles.len( ) );
+        let n = pools.len( ) - 1;
+        for ( c, r ) in cycles.iter( ).zip( &read ) {
+            let moved: Vec<_> = c.path.iter( ).map( |( p, w )| ( n - *p, *w ) ).collect( );
+            assert_eq!( r.path, moved );
+        }
+
+        // a pool the cycles use is gone
+        let used = cycles[ 0 ].path[ 0 ].0;
+        let mut fewer = pools.clone( );
+        fewer.remove( used );
+        let err = read_cycles( &path, &config, &fewer ).unwrap_err( );
+        assert!( err.to_string( ).contains( &format!( "unknown pool P{}", used ) ) );
+        std::fs::remove_file( &path ).unwrap( );
     }
 
-    return results;
+    #[test]
+    fn amounts_in_toys_are_given_per_start_currency( ) {
+        let read = |extra: serde_json::Value| {
+            let sd: ConfigSD = serde_json::from_value( test_config_value( extra ) ).unwrap( );
+            Config::try_from( sd )
+        };
+
+        let config = read( serde_json::json!( { "start_currency": [ 2, 0 ], "minimum_money": [ 5, 7 ],
+                                                "minimum_gain": [ 1, 3 ], "lamport_price": [ 0.5, 2.0 ] } ) ).unwrap( );
+        assert_eq!( ( config.minimum_money_in( 2 ), config.minimum_money_in( 0 ) ), ( 5, 7 ) );
+        assert_eq!( ( config.minimum_gain_in( 2 ), config.minimum_gain_in( 0 ) ), ( 1, 3 ) );
+        let currencies = test_currencies( 3 );
+        assert_eq!( config.estimated_fee( 0, &currencies[ 0 ] ), 4 * config.estimated_fee( 2, &currencies[ 2 ] ) );
+        // 0 means the same in any currency
+        assert_eq!( config.failure_cost, vec![ 0, 0 ] );
+
+        assert!( read( serde_json::json!( { "start_currency": [ 2, 0 ], "minimum_money": 5,
+                                            "minimum_gain": [ 1, 3 ] } ) ).is_err( ) );
+        assert!( read( serde_json::json!( { "start_currency": [ 2, 0 ], "minimum_money": [ 5, 7, 9 ],
+                                            "minimum_gain": [ 1, 3 ] } ) ).is_err( ) );
+        assert_eq!( read( serde_json::json!( { "minimum_money": 5 } ) ).unwrap( ).minimum_money, vec![ 5 ] );
+        // the oracle prices only one of them
+        assert!( read( serde_json::json!( { "start_currency": [ 2, 0 ], "minimum_money": [ 5, 7 ],
+                                            "minimum_gain_usd": 0.05 } ) ).is_err( ) );
+    }
+
+    #[test]
+    fn swap_authority_may_use_any_nonce( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let sd = |authority: Pubkey| {
+            let mut sd = test_swap_pool_sd( &config, 0, 0, 1, "constant-product", false );
+            sd.authority = authority.to_string( );
+            sd
+        };
+        let account = test_key( 3, 0 );
+        let ( canonical, bump ) = Pubkey::find_program_address( &[ &account.to_bytes( ) ], &config.swap_program );
+        let ( other, nonce ) = ( 0 .. bump ).rev( ).find_map( |nonce| {
+            Pubkey::create_program_address( &[ &account.to_bytes( ), &[ nonce ] ], &config.swap_program ).ok( )
+                .map( |key| ( key, nonce ) )
+        } ).unwrap( );
+
+        assert!( SwapPool::try_from( sd( canonical ), &config.swap_program, "swap", false ).is_ok( ) );
+        assert!( SwapPool::try_from( sd( other ), &config.swap_program, "swap", false ).is_ok( ) );
+        assert_eq!( swap_authority_nonce( &account, &config.swap_program, &other ), Some( nonce ) );
+        assert!( SwapPool::try_from( sd( test_key( 9, 0 ) ), &config.swap_program, "swap", false ).is_err( ) );
+    }
 }
@@ -1,23 +1,62 @@
 use std::path::Path;
 #[macro_use]
 extern crate clap;
+use log::{ info, error };
+use solana_sdk::signature::Signer;
 
 use crate::{
     config::*,
     printer::*,
     stable::*,
     communication::*,
+    metrics::*,
+    price::*,
+    record::*,
 };
 
 pub mod raydium;
+pub mod raydium_layout;
 pub mod config;
 pub mod printer;
 pub mod stable;
 pub mod price;
 pub mod communication;
+pub mod metrics;
+pub mod record;
+pub mod subscription;
+pub mod whirlpool;
 
 const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 
+/* The directory given by --output-dir, created if needed */
+fn output_dir<'a>( matches: &'a clap::ArgMatches ) -> Option<&'a Path> {
+    let dir = Path::new( matches.value_of( "OUTPUT_DIR" )? );
+    if let Err( err ) = std::fs::create_dir_all( dir ) {
+        error!( "Cannot create output directory {}: {}", dir.display( ), err );
+        std::process::exit( 1 );
+    }
+    Some( dir )
+}
+
+/* The amount given by --gamble, in toys */
+fn fixed_gamble( matches: &clap::ArgMatches ) -> Option<u64> {
+    let amount = matches.value_of( "GAMBLE" )?;
+    match amount.parse::<u64>( ) {
+        Ok( amount ) => { Some( amount ) },
+        Err( _ ) => {
+            error!( "--gamble {} is no amount of toys.", amount );
+            std::process::exit( 1 )
+        }
+    }
+}
+
+/* Reports a config file that cannot be loaded and quits */
+fn garbage( what: &str, err: ConfigError ) -> ! {
+    eprintln!( "..failed" );
+    error!( "{} is garbage: {}", what, err );
+    std::process::exit( 1 )
+}
+
 fn main( ) {
     // cli param options and stuff
 
@@ -34,76 +73,338 @@ fn main( ) {
     let matches = clap_app!( hikaru_print =>
         ( version: VERSION.unwrap_or( "unknown" ) )
         ( about: "Prints money using the solana blockchain." )
-        ( @arg CONFIG_PATH: -c --config +required +takes_value "Sets the config file" )
-        ( @arg CURRENCY_PATH: -y --currency_config +required +takes_value "Sets the currency config file" )
-        ( @arg POOL_PATH: -p --pool_config +required +takes_value "Sets the pool config file" )
+        ( @arg CONFIG_PATH: -c --config +takes_value required_unless[list_formats] "Sets the config file" )
+        ( @arg CURRENCY_PATH: -y --currency_config +takes_value required_unless[list_formats] "Sets the currency config file" )
+        ( @arg POOL_PATH: -p --pool_config +takes_value required_unless[list_formats] "Sets the pool config file" )
+        ( @arg list_formats: --("list-formats") +hidden "Prints annotated examples of the config files." )
         ( @subcommand list =>
             ( about: "Lists contents of specified config files and corresponding cycles." )
             ( @arg POOL: -P --pool +takes_value "Pool name of a specific pool to list details about." )
+            ( @arg json: --json "Print the cycles as a JSON array instead of prose." )
+            ( @arg CYCLES_PATH: --cycles +takes_value "Use the cycles exported to the given file instead of constructing them." )
+            ( @arg EXCLUDE: --exclude +takes_value +multiple number_of_values(1) "Ignore the pool with the given name; may be repeated." )
+            ( @arg ONLY_DEX: --only +takes_value +multiple number_of_values(1) possible_values(&DEX_NAMES) "Only use pools of the given exchange; may be repeated." )
+            ( @arg EXCLUDE_DEX: --("exclude-dex") +takes_value +multiple number_of_values(1) possible_values(&DEX_NAMES) "Ignore pools of the given exchange; may be repeated." )
+        )
+        ( @subcommand balances =>
+            ( about: "Prints the wallet's balance of SOL and of every configured currency." )
+        )
+        ( @subcommand setup =>
+            ( about: "Creates the associated token accounts of currencies that lack one." )
+            ( @arg sim: -s --simulate "Don't send, just simulate." )
+        )
+        ( @subcommand ping =>
+            ( about: "Measures the round-trip latency of fetching a blockhash from each RPC: read, backups and send." )
+            ( @arg SAMPLES: -n --samples +takes_value "Number of requests (default 20)." )
+        )
+        ( @subcommand check =>
+            ( about: "Checks that every account referenced by the pool config exists on-chain and that swap pool authorities match their stored nonces." )
+        )
+        ( @subcommand coverage =>
+            ( about: "Lists the pools holding each currency, revealing currencies no cycle can pass through." )
+            ( @arg EXCLUDE: --exclude +takes_value +multiple number_of_values(1) "Ignore the pool with the given name; may be repeated." )
+            ( @arg ONLY_DEX: --only +takes_value +multiple number_of_values(1) possible_values(&DEX_NAMES) "Only use pools of the given exchange; may be repeated." )
+            ( @arg EXCLUDE_DEX: --("exclude-dex") +takes_value +multiple number_of_values(1) possible_values(&DEX_NAMES) "Ignore pools of the given exchange; may be repeated." )
         )
         ( @subcommand print =>
-            ( about: "Prints money leveraging arbitrage cycles." )
+            ( about: "Prints money leveraging arbitrage cycles; SIGHUP reloads the pool config." )
             ( @arg sim: -s --simulate "Don't gamble, just simulate." )
-            ( @arg deb: -d --debug "Print debug output to stdout." )
+            ( @arg sim_confirm: --("simulate-confirm") "Simulate and report the resulting balance change per currency." )
+            ( @arg deb: -d --debug "Log debug output (same as RUST_LOG=debug)." )
+            ( @arg METRICS_ADDR: --("metrics-addr") +takes_value "Serve prometheus metrics on host:port." )
+            ( @arg RECORD_PATH: --record +takes_value "Record received account updates to the given file." )
+            ( @arg STATE_PATH: --state +takes_value "Keep the highest observed yield in the given file across restarts." )
+            ( @arg CYCLES_PATH: --cycles +takes_value "Use the cycles exported to the given file instead of constructing them." )
+            ( @arg EXCLUDE: --exclude +takes_value +multiple number_of_values(1) "Ignore the pool with the given name; may be repeated." )
+            ( @arg ONLY_DEX: --only +takes_value +multiple number_of_values(1) possible_values(&DEX_NAMES) "Only use pools of the given exchange; may be repeated." )
+            ( @arg EXCLUDE_DEX: --("exclude-dex") +takes_value +multiple number_of_values(1) possible_values(&DEX_NAMES) "Ignore pools of the given exchange; may be repeated." )
+            ( @arg GAMBLE: --gamble +takes_value "Always gamble this many toys instead of the optimum, at most the balance allows." )
+        )
+        ( @subcommand replay =>
+            ( about: "Replays recorded account updates and prints the cycles that would have been executed." )
+            ( @arg RECORD_PATH: +required "The recording to replay." )
+            ( @arg deb: -d --debug "Log debug output (same as RUST_LOG=debug)." )
         )
         ( @subcommand stable =>
             ( about: "Prints money by swapping back and forth between different stable coins." )
             ( @arg sim: -s --simulate "Don't gamble, just simulate." )
-            ( @arg deb: -d --debug "Print debug output to stdout." )
+            ( @arg deb: -d --debug "Log debug output (same as RUST_LOG=debug)." )
+            ( @arg once: --once "Evaluate pools once, perform at most one swap, then exit." )
+            ( @arg EXCLUDE: --exclude +takes_value +multiple number_of_values(1) "Ignore the pool with the given name; may be repeated." )
+            ( @arg METRICS_ADDR: --("metrics-addr") +takes_value "Serve prometheus metrics on host:port." )
+        )
+        ( @subcommand cycles =>
+            ( about: "Manages constructed cycles." )
+            ( @subcommand export =>
+                ( about: "Writes the constructed cycles to a file for --cycles." )
+                ( @arg PATH: +required "The file to write." )
+            )
+        )
+        ( @subcommand profile =>
+            ( about: "Simulates every cycle and reports the compute units it consumes." )
+            ( @arg OUTPUT_DIR: --("output-dir") +takes_value "Write the program logs of each cycle to <OUTPUT_DIR>/<cycle index>.log." )
         )
         ( @subcommand execute =>
             ( about: "Forcibly execute a cycle by sending a corresponding tx (which should fail or yield profit)." )
             ( @arg CYCLE_IDX: +required "The index of the cycle to execute." )
+            ( @arg CYCLES_PATH: --cycles +takes_value "Use the cycles exported to the given file instead of constructing them." )
             ( @arg sim: -s --simulate "Don't gamble, just simulate." )
-            ( @arg deb: -d --debug "Print debug output to stdout." )
+            ( @arg sim_confirm: --("simulate-confirm") "Simulate and report the resulting balance change per currency." )
+            ( @arg deb: -d --debug "Log debug output (same as RUST_LOG=debug)." )
+            ( @arg force: -f --force "Proceed with the minimum money if the balance is insufficient." )
+            ( @arg dump_ix: --("dump-ix") "Print the instructions that would be sent instead of sending them." )
+            ( @arg GAMBLE: --gamble +takes_value "Gamble this many toys instead of the optimum, at most the balance allows." )
+            ( @arg report: --report "Wait for the transaction and report the realized balance change against the prediction." )
+            ( @arg OUTPUT_DIR: --("output-dir") +takes_value "When simulating, write the program logs to <OUTPUT_DIR>/<cycle index>.log." )
         )
     ).get_matches( );
 
+    // --debug (and --simulate) only change the default; RUST_LOG takes precedence
+    let debug = match matches.subcommand( ) {
+        ( _, Some( scmd ) ) => {
+            scmd.is_present( "deb" ) || scmd.is_present( "sim" ) || scmd.is_present( "sim_confirm" )
+        },
+        _ => { false }
+    };
+    env_logger::Builder::from_env(
+        env_logger::Env::default( ).default_filter_or( if debug { "debug" } else { "info" } ) ).init( );
+
+    if matches.is_present( "list_formats" ) {
+        print_schemas( );
+        return;
+    }
+
     let config_path = Path::new( matches.value_of("CONFIG_PATH").unwrap( ) );
-    print!( "Reading config from {}.", config_path.display( ) );
-    let config = Config::read_from_file( config_path ).expect( "Config is garbage" );
+    eprint!( "Reading config from {}.", config_path.display( ) );
+    let mut config = Config::read_from_file( config_path ).unwrap_or_else( |err| garbage( "Config", err ) );
 
-    println!( "..OK" );
+    eprintln!( "..OK" );
 
     let crcy_path = Path::new( matches.value_of("CURRENCY_PATH").unwrap( ) );
-    print!( "Reading currencies from {}.", crcy_path.display( ) );
-    let crcy_cfg = CurrencyConfig::read_from_file( crcy_path ).expect( "Currency config is garbage" );
-    let currencies = crcy_cfg.currencies;
+    eprint!( "Reading currencies from {}.", crcy_path.display( ) );
+    let crcy_cfg = CurrencyConfig::read_from_file( crcy_path ).unwrap_or_else( |err| {
+        garbage( "Currency config", err )
+    } );
+    let currencies = crcy_cfg.currencies.clone( );
+    config.resolve_start_currency( &currencies ).unwrap_or_else( |err| garbage( "Config", err ) );
 
-    let comm = Communication::init( &config.cluster_url, &crcy_cfg.wallet_path );
+    let comm = Communication::init( &config.cluster_url, &crcy_cfg, &config );
     let comm_send = if config.cluster_url != config.cluster_url_send {
-        Some( Communication::init( &config.cluster_url_send, &crcy_cfg.wallet_path ) )
+        Some( Communication::init( &config.cluster_url_send, &crcy_cfg, &config ) )
     } else {
         None
     };
-    println!( "..OK" );
+    eprintln!( "..OK" );
 
 
     let pool_path = Path::new( matches.value_of("POOL_PATH").unwrap( ) );
-    print!( "Reading pools from {}.", pool_path.display( ) );
-    let pools = PoolConfig::read_from_file( pool_path, &config ).expect( "Pool config is garbage" );
-    println!( "..OK" );
+    eprint!( "Reading pools from {}.", pool_path.display( ) );
+    let mut pools = PoolConfig::read_from_file( pool_path, &config ).unwrap_or_else( |err| {
+        garbage( "Pool config", err )
+    } );
+    PoolConfig::validate( &pools, &currencies ).unwrap_or_else( |err| garbage( "Pool config", err ) );
+
+    // drop excluded pools before any cycle refers to them by index
+    let mut filter = PoolFilter::default( );
+    if let ( _, Some( scmd ) ) = matches.subcommand( ) {
+        let values = |arg: &str| -> Vec<String> {
+            scmd.values_of( arg ).map_or( Vec::new( ), |v| v.map( String::from ).collect( ) )
+        };
+        filter.excluded = values( "EXCLUDE" );
+        filter.only_dex = values( "ONLY_DEX" );
+        filter.excluded_dex = values( "EXCLUDE_DEX" );
+    }
+    PoolConfig::filter( &mut pools, &filter );
+    eprintln!( "..OK" );
+
+    if let Some( _ ) = matches.subcommand_matches( "balances" ) {
+        comm.dump_balances( &currencies );
+        return;
+    }
+
+    if let Some( scmd_setup ) = matches.subcommand_matches( "setup" ) {
+        let simulate = scmd_setup.is_present( "sim" );
+        let missing = comm.missing_currency_accounts( &currencies ).unwrap_or_else( |err| {
+            error!( "Fetching currency accounts failed: {:?}", err );
+            std::process::exit( 1 );
+        } );
+        if missing.is_empty( ) {
+            println!( "All currency accounts exist." );
+            return;
+        }
+
+        let cs = comm_send.as_ref( ).unwrap_or( &comm );
+        let mut failures = 0;
+        for i in missing {
+            let currency = &currencies[ i ];
+            if currency.is_native( ) && config.wrap_sol {
+                // the print loop wraps SOL itself and closes the account afterwards
+                println!( "Skipping {}, wrapped SOL is created when needed.", currency.name );
+                continue;
+            }
+            let mut instructions = Vec::new( );
+            if !create_associated_account( &mut instructions, &comm.wallet.pubkey( ), currency, &config ) {
+                failures = failures + 1;
+                continue;
+            }
+            if simulate {
+                // send_transaction reports a successful simulation as an error
+                let res = comm.get_blockhash( ).and_then( |hash| {
+                    cs.simulate_transaction( &instructions, &vec![ &comm.wallet ], hash )
+                } );
+                match res {
+                    Ok( sim ) if sim.err.is_none( ) => {
+                        println!( "Simulated creating account {} for {}: OK", currency.account, currency.name );
+                    },
+                    Ok( sim ) => {
+                        error!( "Simulating account creation of {} failed: {:?}", currency.name, sim.err );
+                        for l in sim.logs.unwrap_or_default( ) {
+                            info!( "{}", l );
+                        }
+                        failures = failures + 1;
+                    },
+                    Err( err ) => {
+                        error!( "Simulating account creation of {} failed: {:?}", currency.name, err );
+                        failures = failures + 1;
+                    }
+                }
+                continue;
+            }
+            let res = comm.get_blockhash( ).and_then( |hash| {
+                cs.send_transaction( &instructions, &vec![ &comm.wallet ], false, hash, None )
+            } );
+            match res {
+                Ok( sig ) => { println!( "Created account {} for {}: {}", currency.account, currency.name, sig ); },
+                Err( err ) => {
+                    error!( "Creating account of {} failed: {:?}", currency.name, err );
+                    failures = failures + 1;
+                }
+            }
+        }
+        if failures > 0 {
+            std::process::exit( 1 );
+        }
+        return;
+    }
+
+    if let Some( scmd_list ) = matches.subcommand_matches( "ping" ) {
+        let samples = scmd_list.value_of( "SAMPLES" ).map_or( 20, |n| {
+            n.parse::<usize>( ).expect( "Number of samples is garbage" )
+        } ).max( 1 );
+        // the read endpoint, its backups, then the send endpoint unless it is the read one
+        let mut urls = comm.cluster_urls( );
+        if !urls.contains( &config.cluster_url_send ) {
+            urls.push( config.cluster_url_send.clone( ) );
+        }
+
+        let mut failures = 0;
+        for url in &urls {
+            match comm.measure_latency( url, samples ) {
+                Ok( latencies ) => {
+                    let ms = |i: usize| latencies[ i ].as_secs_f64( ) * 1000.0;
+                    let n = latencies.len( );
+                    println!( "{}: {} samples, min {:.1} ms, median {:.1} ms, p95 {:.1} ms, max {:.1} ms",
+                              url, n, ms( 0 ), ms( n / 2 ),
+                              ms( ( ( n as f64 * 0.95 ).ceil( ) as usize ).max( 1 ) - 1 ), ms( n - 1 ) );
+                },
+                Err( err ) => {
+                    error!( "{}: fetching blockhash failed: {:?}", url, err );
+                    failures = failures + 1;
+                }
+            }
+        }
+        if failures > 0 {
+            std::process::exit( 1 );
+        }
+        return;
+    }
+
+    if let Some( _ ) = matches.subcommand_matches( "check" ) {
+        let problems = PoolConfig::check( &comm, &pools, &config );
+        if problems > 0 {
+            println!( "Found {} problems.", problems );
+            std::process::exit( 1 );
+        }
+        println!( "All pool accounts look fine." );
+        return;
+    }
+
+    if let Some( _ ) = matches.subcommand_matches( "coverage" ) {
+        PoolConfig::print_coverage( &pools, &currencies );
+        return;
+    }
+
+    PoolConfig::read_chain_state( &comm, &mut pools ).unwrap_or_else( |err| {
+        error!( "{}", err );
+        std::process::exit( 1 )
+    } );
 
     // don't need cycles for stable printer
     if let Some( scmd_list ) = matches.subcommand_matches( "stable" ) {
+        let mut printer = StablePrinter::init( &comm, &currencies, &pools, &config );
+        if let Some( addr ) = scmd_list.value_of( "METRICS_ADDR" ) {
+            Metrics::serve( printer.metrics.clone( ), addr ).expect( "Cannot serve metrics" );
+        }
+
         // run the money printer
         if let Some( cs ) = comm_send {
-            return StablePrinter::init( &comm, &currencies, &pools,
-                                        scmd_list.is_present( "deb" )
-                                        || scmd_list.is_present( "sim" ) ).
-                run( &comm, &cs, &config, scmd_list.is_present( "sim" ) );
+            return printer.run( &comm, &cs, &config, scmd_list.is_present( "sim" ),
+                                scmd_list.is_present( "once" ) );
         } else {
-            return StablePrinter::init( &comm, &currencies, &pools,
-                                        scmd_list.is_present( "deb" )
-                                        || scmd_list.is_present( "sim" ) ).
-                run( &comm, &comm, &config, scmd_list.is_present( "sim" ) );
+            return printer.run( &comm, &comm, &config, scmd_list.is_present( "sim" ),
+                                scmd_list.is_present( "once" ) );
         }
     }
 
-    print!( "Constructing cycles." );
-    // construct graph out of currencies and pools; compute cycles found
-    let cycles = construct_cycles( &config, &pools );
-    println!( "..OK, {} cycles constructed.", cycles.len( ) );
+    let cycles_path = match matches.subcommand( ) {
+        ( _, Some( scmd ) ) => { scmd.value_of( "CYCLES_PATH" ) },
+        _ => { None }
+    };
+    let cycles = if let Some( path ) = cycles_path {
+        eprint!( "Reading cycles from {}.", path );
+        match read_cycles( path, &config, &pools ) {
+            Ok( cycles ) => { cycles },
+            Err( err ) => {
+                eprintln!( "..failed" );
+                error!( "Cycles are garbage: {}", err );
+                std::process::exit( 1 )
+            }
+        }
+    } else {
+        eprint!( "Constructing cycles." );
+        // reserves are only needed to prune by rate
+        let pool_prices = if config.cycles_need_prices( ) {
+            Some( PoolPrice::init_all( &comm, &pools, &currencies ).unwrap_or_else( |err| {
+                eprintln!( "..failed" );
+                error!( "Initializing pool prices failed: {}", err );
+                std::process::exit( 1 )
+            } ).0 )
+        } else {
+            None
+        };
+        // construct graph out of currencies and pools; compute cycles found
+        match construct_cycles( &config, &pools, pool_prices.as_ref( ) ) {
+            Ok( cycles ) => { cycles },
+            Err( err ) => {
+                eprintln!( "..failed" );
+                error!( "Cannot construct cycles: {}", err );
+                std::process::exit( 1 )
+            }
+        }
+    };
+    eprintln!( "..OK, {} cycles.", cycles.len( ) );
+
+    if let Some( scmd_cycles ) = matches.subcommand_matches( "cycles" ) {
+        if let Some( scmd_export ) = scmd_cycles.subcommand_matches( "export" ) {
+            let path = scmd_export.value_of( "PATH" ).unwrap( );
+            if let Err( err ) = write_cycles( path, &cycles, &pools ) {
+                error!( "Writing cycles failed: {}", err );
+                std::process::exit( 1 );
+            }
+            println!( "Wrote {} cycles to {}.", cycles.len( ), path );
+        }
+        return;
+    }
 
     // do what we were instructed to do
     if let Some( scmd_list ) = matches.subcommand_matches( "list" ) {
@@ -113,19 +414,22 @@ fn main( ) {
 
             for p in &pools {
                 if p.get_name( ) == pl.trim( ) {
-//                    PoolPrice::dump( &comm, p );
+                    PoolPrice::dump( &comm, p );
                 }
             }
 
             return;
         }
 
-        println!( "Config:\n{:?}", config );
-        // println!( "Currencies:\n{:?}", currencies );
-        // println!( "Pools:\n{:?}", pools );
+        let json = scmd_list.is_present( "json" );
+        if !json {
+            println!( "Config:\n{:?}", config );
+            // println!( "Currencies:\n{:?}", currencies );
+            // println!( "Pools:\n{:?}", pools );
+        }
 
-        Printer::init( &comm, &config, &currencies, &pools, &cycles, true ).list_path(
-            &comm, &config );
+        Printer::init( &comm, &config, &currencies, &pools, &cycles ).list_path(
+            &comm, &config, json );
 
         /*
         println!( "Cycles:" );
@@ -142,33 +446,62 @@ fn main( ) {
         return;
     }
 
+    if let Some( scmd_list ) = matches.subcommand_matches( "profile" ) {
+        let log_dir = output_dir( scmd_list );
+        Printer::init( &comm, &config, &currencies, &pools, &cycles ).profile_path(
+            &comm, &config, log_dir );
+        return;
+    }
+
     if let Some( scmd_list ) = matches.subcommand_matches( "print" ) {
+        let mut printer = Printer::init( &comm, &config, &currencies, &pools, &cycles );
+        if let Some( addr ) = scmd_list.value_of( "METRICS_ADDR" ) {
+            Metrics::serve( printer.metrics.clone( ), addr ).expect( "Cannot serve metrics" );
+        }
+        printer.simulate_confirm = scmd_list.is_present( "sim_confirm" );
+        printer.fixed_gamble = fixed_gamble( scmd_list );
+        let simulate = scmd_list.is_present( "sim" ) || printer.simulate_confirm;
+
         // run the money printer
         if let Some( cs ) = comm_send {
-            return Printer::init( &comm, &config, &currencies, &pools, &cycles,
-                                  scmd_list.is_present( "deb" ) || scmd_list.is_present( "sim" ) ).
-                run( &comm, &cs, &config, scmd_list.is_present( "sim" ) );
+            return printer.run( &comm, &cs, &config, simulate,
+                                scmd_list.value_of( "RECORD_PATH" ), scmd_list.value_of( "STATE_PATH" ),
+                                matches.value_of( "POOL_PATH" ).unwrap( ), &filter );
         } else {
-            return Printer::init( &comm, &config, &currencies, &pools, &cycles,
-                                  scmd_list.is_present( "deb" ) || scmd_list.is_present( "sim" ) ).
-                run( &comm, &comm, &config, scmd_list.is_present( "sim" ) );
+            return printer.run( &comm, &comm, &config, simulate,
+                                scmd_list.value_of( "RECORD_PATH" ), scmd_list.value_of( "STATE_PATH" ),
+                                matches.value_of( "POOL_PATH" ).unwrap( ), &filter );
         }
     }
 
+    if let Some( scmd_list ) = matches.subcommand_matches( "replay" ) {
+        let record_path = Path::new( scmd_list.value_of( "RECORD_PATH" ).unwrap( ) );
+        let records = read_records( record_path ).expect( "Recording is garbage" );
+
+        // the balance is taken from the recording
+        return Printer::new( vec![ 0; currencies.len( ) ], &currencies, &pools, &cycles ).
+            replay( &config, &records );
+    }
+
     if let Some( scmd_list ) = matches.subcommand_matches( "execute" ) {
+        let mut printer = Printer::init( &comm, &config, &currencies, &pools, &cycles );
+        printer.simulate_confirm = scmd_list.is_present( "sim_confirm" );
+        printer.dump_instructions = scmd_list.is_present( "dump_ix" );
+        printer.fixed_gamble = fixed_gamble( scmd_list );
+        let simulate = scmd_list.is_present( "sim" ) || printer.simulate_confirm;
+        let log_dir = output_dir( scmd_list );
+
         // run the money printer
         if let Some( cs ) = comm_send {
-            return Printer::init( &comm, &config, &currencies, &pools, &cycles,
-                                  scmd_list.is_present( "deb" ) || scmd_list.is_present( "sim" ) ).
-                test_path( &comm, &cs, &config,
+            return printer.test_path( &comm, &cs, &config,
                            scmd_list.value_of( "CYCLE_IDX" ).unwrap( ).parse::<usize>( ).unwrap( ),
-                           scmd_list.is_present( "sim" ) );
+                           simulate, scmd_list.is_present( "force" ), log_dir,
+                           scmd_list.is_present( "report" ) );
         } else {
-            return Printer::init( &comm, &config, &currencies, &pools, &cycles,
-                                  scmd_list.is_present( "deb" ) || scmd_list.is_present( "sim" ) ).
-                test_path( &comm, &comm, &config,
+            return printer.test_path( &comm, &comm, &config,
                            scmd_list.value_of( "CYCLE_IDX" ).unwrap( ).parse::<usize>( ).unwrap( ),
-                           scmd_list.is_present( "sim" ) );
+                           simulate, scmd_list.is_present( "force" ), log_dir,
+                           scmd_list.is_present( "report" ) );
 
         }
     }
@@ -0,0 +1,85 @@
+use std::{
+    io::{ Read, Write },
+    net::TcpListener,
+    sync::{
+        Arc,
+        atomic::{ AtomicI64, AtomicU64, Ordering },
+    },
+    thread,
+};
+
+// Structs
+
+/* Counters shared between the print loop and the metrics endpoint. */
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub balance:       AtomicU64,
+    pub cycles:        AtomicU64,
+    pub tx_attempted:  AtomicU64,
+    pub tx_succeeded:  AtomicU64,
+    pub tx_failed:     AtomicU64,
+    pub ath_gain:      AtomicI64,
+    pub ws_reconnects: AtomicU64,
+}
+
+// Implementations
+
+impl Metrics {
+    pub fn tx_sent( &self, success: bool ) {
+        self.tx_attempted.fetch_add( 1, Ordering::Relaxed );
+        if success {
+            self.tx_succeeded.fetch_add( 1, Ordering::Relaxed );
+        } else {
+            self.tx_failed.fetch_add( 1, Ordering::Relaxed );
+        }
+    }
+
+    /* Renders all values in the prometheus text exposition format */
+    pub fn render( &self ) -> String {
+        let mut res = String::new( );
+        let values: [ ( &str, &str, &str, i128 ); 7 ] = [
+            ( "balance", "gauge", "Balance of the start currency in toys.",
+              self.balance.load( Ordering::Relaxed ) as i128 ),
+            ( "cycles", "gauge", "Number of cycles considered.",
+              self.cycles.load( Ordering::Relaxed ) as i128 ),
+            ( "tx_attempted_total", "counter", "Transactions attempted.",
+              self.tx_attempted.load( Ordering::Relaxed ) as i128 ),
+            ( "tx_succeeded_total", "counter", "Transactions sent successfully.",
+              self.tx_succeeded.load( Ordering::Relaxed ) as i128 ),
+            ( "tx_failed_total", "counter", "Transactions that failed to send.",
+              self.tx_failed.load( Ordering::Relaxed ) as i128 ),
+            ( "ath_gain", "gauge", "Highest yield observed so far in toys of the first start currency.",
+              self.ath_gain.load( Ordering::Relaxed ) as i128 ),
+            ( "ws_reconnects_total", "counter", "Websocket reconnects.",
+              self.ws_reconnects.load( Ordering::Relaxed ) as i128 ),
+        ];
+
+        for ( name, tp, help, val ) in &values {
+            res.push_str( &format!( "# HELP hikaru_{} {}\n# TYPE hikaru_{} {}\nhikaru_{} {}\n",
+                                    name, help, name, tp, name, val ) );
+        }
+        res
+    }
+
+    /* Serves the metrics on the given address from a background thread. */
+    pub fn serve( metrics: Arc<Metrics>, addr: &str ) -> std::io::Result<( )> {
+        let listener = TcpListener::bind( addr )?;
+
+        thread::spawn( move || {
+            for stream in listener.incoming( ) {
+                if let Ok( mut stream ) = stream {
+                    // the same page is served regardless of what was requested
+                    let mut buf = [ 0u8; 1024 ];
+                    let _ = stream.read( &mut buf );
+
+                    let body = metrics.render( );
+                    let _ = write!( stream,
+                                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                    body.len( ), body );
+                }
+            }
+        } );
+        Ok( ( ) )
+    }
+}
@@ -2,8 +2,11 @@ use std::{
     cmp::max,
 };
 use solana_sdk::{
-    commitment_config::CommitmentConfig,
     account::Account as SdkAccount,
+    pubkey::Pubkey,
+};
+use solana_client::{
+    client_error::Result as ClientResult,
 };
 use solana_account_decoder::{
     UiAccount
@@ -14,14 +17,30 @@ use spl_token::{
     },
     state::Account,
 };
+use spl_token_2022::{
+    extension::StateWithExtensions,
+    state::Account as Account2022,
+};
+
+use log::{ warn, error };
 
 use crate::{
-    communication::*,
     config::*,
+    raydium_layout::{ AmmInfo, CpmmPoolInfo, OpenOrdersInfo },
+    whirlpool::WhirlpoolInfo,
 };
 
 // Structs
 
+/* Supplies the on-chain state prices are initialized from. Communication queries the
+ * cluster; anything else can stand in for it to evaluate pools without a network. */
+pub trait PriceSource {
+    /* Balance of a token account in ui units, along with its decimals */
+    fn token_balance( &self, account: &Pubkey ) -> ClientResult<( f64, u8 )>;
+    /* The given accounts in the same order, None for those that don't exist */
+    fn accounts( &self, keys: &[ Pubkey ] ) -> ClientResult<Vec<Option<SdkAccount>>>;
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct TokenPrice {
     pub token_amount:       (f64, u8),
@@ -33,56 +52,334 @@ pub struct PoolPrice {
     pub sanity:        bool,
     pub token_price:   [ TokenPrice; 2 ],
     pub token_updated: [ bool; 2 ],
+    pub whirlpool:     Option<WhirlpoolInfo>, // current tick of a whirlpool, to pick the tick arrays of its swaps
+}
+
+/// Raw on-chain state needed to compute the effective reserves of a raydium pool.
+/// Indices follow the subscription layout: 0, 1 pool token vaults; 2 amm (pool state for
+/// cpmm pools); 3 open orders.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RaydiumAccounts {
+    pub vault_amounts: [ Option<u64>; 2 ],
+    pub amm:           Option<AmmInfo>,
+    pub open_orders:   Option<OpenOrdersInfo>,
+    pub cpmm:          Option<CpmmPoolInfo>,
 }
 
 // Implementations
 
 impl PoolPrice {
-    pub fn init( comm: &Communication, pool: &Pool ) -> Self {
+    pub fn init( source: &dyn PriceSource, pool: &Pool ) -> Self {
+        let mut res = PoolPrice{
+            sanity: true,
+            token_price: [
+                TokenPrice::init( &pool.get_currency( Direction::AtoB ), source ),
+                TokenPrice::init( &pool.get_currency( Direction::BtoA ), source )
+            ],
+            token_updated: [ false, false ],
+            whirlpool: None,
+        };
+
+        if let Pool::Raydium( rp ) = pool {
+            // vault balances alone miss the liquidity raydium keeps on serum
+            if let Some( reserves ) = rp.effective_reserves( &RaydiumAccounts::init( source, rp ) ) {
+                res.set_reserves( reserves );
+            }
+        }
+        if let Pool::RaydiumCpmm( cp ) = pool {
+            // part of the vault balances are fees waiting to be collected
+            if let Some( reserves ) = cp.effective_reserves( &RaydiumAccounts::init_cpmm( source, cp ) ) {
+                res.set_reserves( reserves );
+            }
+        }
+        if let Pool::Whirlpool( wp ) = pool {
+            // vault balances say nothing about the liquidity around the current price
+            match source.accounts( &[ wp.account ] ) {
+                Ok( accounts ) => {
+                    res.whirlpool = accounts[ 0 ].as_ref( ).and_then( whirlpool_info );
+                    if let Some( reserves ) = res.whirlpool.and_then( |info| info.virtual_reserves( ) ) {
+                        res.set_reserves( reserves );
+                    }
+                },
+                Err( err ) => {
+                    error!( "{:?}", err );
+                }
+            }
+        }
+        res
+    }
+
+    /* Initializes the prices of all pools (and the state of raydium pools) with as few rpc
+     * requests as possible; fails if a pool's accounts cannot be read. */
+    pub fn init_all( source: &dyn PriceSource, pools: &Vec<Pool>, currencies: &Vec<Currency> )
+        -> Result<( Vec<Self>, Vec<RaydiumAccounts> ), String> {
+        // token accounts first, then amm and open orders for raydium pools or the
+        // whirlpool or cpmm pool state itself
+        let mut keys = Vec::new( );
+        for p in pools {
+            keys.push( p.get_currency( Direction::AtoB ).account );
+            keys.push( p.get_currency( Direction::BtoA ).account );
+            match p {
+                Pool::Raydium( rp ) => {
+                    keys.push( rp.account );
+                    keys.push( rp.open_orders );
+                },
+                Pool::Whirlpool( wp ) => {
+                    keys.push( wp.account );
+                },
+                Pool::RaydiumCpmm( cp ) => {
+                    keys.push( cp.account );
+                },
+                _ => { }
+            }
+        }
+
+        let accounts = source.accounts( &keys ).map_err( |err| format!( "{:?}", err ) )?;
+
+        let mut prices = Vec::new( );
+        let mut raydium_accounts = Vec::new( );
+        let mut idx = 0;
+        for p in pools {
+            let mut token_price = [ TokenPrice{ token_amount: ( 0.0, 0 ) }; 2 ];
+            for i in 0 ..= 1 {
+                let decs = currencies[ p.get_currency( Direction::BOTH[ i ] ).currency_idx ].decimals;
+                let amount = match &accounts[ idx + i ] {
+                    Some( acc ) => { unpack_token_amount( acc ) },
+                    None => { None }
+                };
+                match amount {
+                    Some( amount ) => {
+                        token_price[ i ].token_amount = ( amount as f64 / pow10( decs as usize ), decs );
+                    },
+                    None => {
+                        return Err( format!( "Pool {}: cannot read token account {}", p.get_name( ),
+                                             p.get_currency( Direction::BOTH[ i ] ).account ) );
+                    }
+                }
+            }
+
+            let mut res = PoolPrice{
+                sanity: true,
+                token_price: token_price,
+                token_updated: [ false, false ],
+                whirlpool: None,
+            };
+            let mut ray_accs = RaydiumAccounts::default( );
+
+            match p {
+                Pool::Raydium( rp ) => {
+                    for kind in 0 .. 4 {
+                        if let Some( acc ) = &accounts[ idx + kind ] {
+                            ray_accs.update_data( kind, acc );
+                        }
+                    }
+                    // vault balances alone miss the liquidity raydium keeps on serum
+                    if let Some( reserves ) = rp.effective_reserves( &ray_accs ) {
+                        res.set_reserves( reserves );
+                    }
+                    idx = idx + 4;
+                },
+                Pool::Whirlpool( _ ) => {
+                    res.whirlpool = accounts[ idx + 2 ].as_ref( ).and_then( whirlpool_info );
+                    match res.whirlpool.and_then( |info| info.virtual_reserves( ) ) {
+                        Some( reserves ) => { res.set_reserves( reserves ); },
+                        None => {
+                            return Err( format!( "Pool {}: cannot read whirlpool account",
+                                                 p.get_name( ) ) );
+                        }
+                    }
+                    idx = idx + 3;
+                },
+                Pool::RaydiumCpmm( cp ) => {
+                    for kind in 0 .. 3 {
+                        if let Some( acc ) = &accounts[ idx + kind ] {
+                            ray_accs.update_cpmm_data( kind, acc );
+                        }
+                    }
+                    match cp.effective_reserves( &ray_accs ) {
+                        Some( reserves ) => { res.set_reserves( reserves ); },
+                        None => {
+                            return Err( format!( "Pool {}: cannot read pool state", p.get_name( ) ) );
+                        }
+                    }
+                    idx = idx + 3;
+                },
+                _ => {
+                    idx = idx + 2;
+                }
+            }
+
+            prices.push( res );
+            raydium_accounts.push( ray_accs );
+        }
+        Ok( ( prices, raydium_accounts ) )
+    }
+
+    pub fn from_amounts( token_amounts: [ ( f64, u8 ); 2 ] ) -> Self {
         PoolPrice{
             sanity: true,
             token_price: [
-                TokenPrice::init( &pool.get_currency( 0 ), comm ),
-                TokenPrice::init( &pool.get_currency( 1 ), comm )
+                TokenPrice{ token_amount: token_amounts[ 0 ] },
+                TokenPrice{ token_amount: token_amounts[ 1 ] }
             ],
             token_updated: [ false, false ],
+            whirlpool: None,
+        }
+    }
+
+    /* Applies an account update for the given pool; tkn follows the subscription layout */
+    pub fn update( &mut self, pool: &Pool, raydium_accounts: &mut RaydiumAccounts,
+                   tkn: usize, account_data: &UiAccount ) {
+        match pool {
+            Pool::Swap( _ ) => {
+                self.token_price[ tkn ].update( &pool.get_currency( Direction::BOTH[ tkn ] ), account_data );
+
+                if self.token_updated[ 1 - tkn ] {
+                    self.token_updated[ tkn ] = false;
+                    self.token_updated[ 1 - tkn ] = false;
+                    self.sanity = true;
+                } else {
+                    self.token_updated[ tkn ] = true;
+                    self.sanity = false;
+                }
+            },
+            Pool::Raydium( rp ) => {
+                raydium_accounts.update( tkn, account_data );
+                match rp.effective_reserves( raydium_accounts ) {
+                    Some( reserves ) => {
+                        self.set_reserves( reserves );
+                        self.sanity = true;
+                    },
+                    None => {
+                        self.sanity = false;
+                    }
+                }
+            },
+            Pool::RaydiumCpmm( cp ) => {
+                raydium_accounts.update_cpmm( tkn, account_data );
+                match cp.effective_reserves( raydium_accounts ) {
+                    Some( reserves ) => {
+                        self.set_reserves( reserves );
+                        self.sanity = true;
+                    },
+                    None => {
+                        self.sanity = false;
+                    }
+                }
+            },
+            Pool::Whirlpool( _ ) => {
+                // only the whirlpool account (kind 2) carries its price
+                if tkn != 2 {
+                    return;
+                }
+                let info = account_data.decode::<SdkAccount>( ).as_ref( ).and_then( whirlpool_info );
+                match info.and_then( |info| info.virtual_reserves( ) ) {
+                    Some( reserves ) => {
+                        self.set_reserves( reserves );
+                        self.whirlpool = info;
+                        self.sanity = true;
+                    },
+                    None => {
+                        warn!( "Malformed whirlpool {:?}", account_data );
+                        self.sanity = false;
+                    }
+                }
+            }
+        }
+    }
+
+    /* Reserve ratio in ui units of token 1 per token 0; 0 if a reserve is empty */
+    pub fn implied_price( &self ) -> f64 {
+        let ( a, _ ) = self.token_price[ 0 ].token_amount;
+        let ( b, _ ) = self.token_price[ 1 ].token_amount;
+        if a <= 0.0 || b <= 0.0 {
+            return 0.0;
+        }
+        b / a
+    }
+
+    /* Distrusts a consistent price that moved by more than a factor of max_jump since the
+     * previous consistent one (reference); the next update within max_jump of it restores
+     * trust. Returns whether the price jumped. */
+    pub fn check_jump( &mut self, reference: &mut f64, max_jump: f64 ) -> bool {
+        let price = self.implied_price( );
+        if !self.sanity || price == 0.0 {
+            return false;
+        }
+
+        let previous = *reference;
+        *reference = price;
+        if previous == 0.0 {
+            return false;
         }
+        let ratio = if price > previous { price / previous } else { previous / price };
+        if ratio > max_jump {
+            self.sanity = false;
+            return true;
+        }
+        false
     }
 
-    pub fn dump( _comm: &Communication, pool: &Pool ) {
-        // print token prices
-        // TODO
+    pub fn set_reserves( &mut self, reserves: [ u64; 2 ] ) {
+        for i in 0 ..= 1 {
+            let ( _old_amt, decs ) = self.token_price[ i ].token_amount;
+            self.token_price[ i ].token_amount = ( reserves[ i ] as f64 / pow10( decs as usize ),
+                                                   decs );
+        }
+    }
+
+    pub fn dump( source: &dyn PriceSource, pool: &Pool ) {
+        println!( "Dumping pool {} ({})", pool.get_name( ), pool.type_name( ) );
 
-        println!( "Dumping pool {}", pool.get_name( ) );
+        let price = Self::init( source, pool );
+
+        // print token reserves
+        for i in 0 ..= 1 {
+            let ( ui_amount, decs ) = price.token_price[ i ].token_amount;
+            println!( "Token {}: {} toys ({} at {} decimals)", i, price.token_amount( Direction::BOTH[ i ] ) as u128,
+                      ui_amount, decs );
+        }
 
-        // let price = Self::init( comm, pool );
+        // spot prices in ui units
+        let ( a, _ ) = price.token_price[ 0 ].token_amount;
+        let ( b, _ ) = price.token_price[ 1 ].token_amount;
+        println!( "Spot price: 1 token 0 = {} token 1, 1 token 1 = {} token 0", b / a, a / b );
+        println!( "Spot price after fees: 1 token 0 = {} token 1, 1 token 1 = {} token 0",
+                  pool.spot_price( &price, Direction::AtoB ), pool.spot_price( &price, Direction::BtoA ) );
 
+        println!( "Fees: {}%", ( 1.0 - pool.fees( ) ) * 100.0 );
     }
 
-    pub fn swap( &self, toys_in: u128, direction: usize, pool_info: &Pool ) -> ( u128, u128 ) {
-        let ( a_val, a_decs ) = self.token_price[ direction ].token_amount;
-        let ( b_val, b_decs ) = self.token_price[ 1 - direction ].token_amount;
+    pub fn swap( &self, toys_in: u128, direction: Direction, pool_info: &Pool ) -> ( u128, u128 ) {
+        let ( a_val, a_decs ) = self.token_price[ direction.index( ) ].token_amount;
+        let ( b_val, b_decs ) = self.token_price[ direction.flip( ).index( ) ].token_amount;
 
         let decs = max( a_decs, b_decs );
-        let a_val = ( a_val * POWERS_OF_TEN[ decs as usize ] ) as u128;
-        let b_val = ( b_val * POWERS_OF_TEN[ decs as usize ] ) as u128;
+        let a_val = ( a_val * pow10( decs as usize ) ) as u128;
+        let b_val = ( b_val * pow10( decs as usize ) ) as u128;
 
-        return pool_info.predict_swap( toys_in as u128, a_val, b_val );
+        return pool_info.predict_swap( toys_in as u128, direction, a_val, b_val );
     }
 
-    pub fn token_amount( &self, direction: usize ) -> f64 {
-        let ( val, decs ) = self.token_price[ direction ].token_amount;
+    pub fn token_amount( &self, direction: Direction ) -> f64 {
+        let ( val, decs ) = self.token_price[ direction.index( ) ].token_amount;
 
-        val * POWERS_OF_TEN[ decs as usize ]
+        val * pow10( decs as usize )
     }
 }
 
 impl TokenPrice {
-    pub fn init( token: &Token, comm: &Communication ) -> Self {
-        TokenPrice {
-            token_amount: comm.get_current_balance_for_pubkey_with_commitment(
-                              &token.account,
-                              CommitmentConfig::finalized( ) )
+    pub fn init( token: &Token, source: &dyn PriceSource ) -> Self {
+        match source.token_balance( &token.account ) {
+            Ok( amount ) => {
+                TokenPrice {
+                    token_amount: amount
+                }
+            },
+            Err( err ) => {
+                error!( "{:?}", err );
+                std::process::exit( 1 )
+            }
         }
 //        self.last_update = Instant::now( );
     }
@@ -92,7 +389,7 @@ impl TokenPrice {
             /* SOL_IDX => {
                 // just use the provided lamports value.
                 let ( _old_amt, decs ) = self.token_amount;
-                self.token_amount = ( account_data.lamports as f64 / POWERS_OF_TEN[ decs as usize],
+                self.token_amount = ( account_data.lamports as f64 / pow10( decs as usize ),
                                       decs );
             }, */
             _ => {
@@ -100,14 +397,253 @@ impl TokenPrice {
                     Some( sdk_acc ) => {
                         let ( _old_amt, decs ) = self.token_amount;
                         // here we need to parse the account data.
-                        let account = Account::unpack_unchecked( &sdk_acc.data ).unwrap( );
-                        self.token_amount = ( account.amount as f64 / POWERS_OF_TEN[ decs as usize ], decs );
+                        match unpack_token_amount( &sdk_acc ) {
+                            Some( amount ) => {
+                                self.token_amount = ( amount as f64 / pow10( decs as usize ), decs );
+                            },
+                            None => {
+                                warn!( "Malformed token account {:?}", account_data );
+                            }
+                        }
                     },
                     None => {
-                        println!( "Malformed uiaccount {:?}", account_data );
+                        warn!( "Malformed uiaccount {:?}", account_data );
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl RaydiumAccounts {
+    pub fn init( source: &dyn PriceSource, pool: &RaydiumPool ) -> Self {
+        let mut res = Self::default( );
+        let keys = [ pool.get_currency( Direction::AtoB ).account, pool.get_currency( Direction::BtoA ).account,
+                     pool.account, pool.open_orders ];
+
+        match source.accounts( &keys ) {
+            Ok( accounts ) => {
+                for ( i, acc ) in accounts.iter( ).enumerate( ) {
+                    if let Some( acc ) = acc {
+                        res.update_data( i, acc );
                     }
                 }
             },
+            Err( err ) => {
+                error!( "{:?}", err );
+            }
+        }
+        res
+    }
+
+    /* Reads the vaults and pool state of a cpmm pool */
+    pub fn init_cpmm( source: &dyn PriceSource, pool: &RaydiumCpmmPool ) -> Self {
+        let mut res = Self::default( );
+        let keys = [ pool.get_currency( Direction::AtoB ).account, pool.get_currency( Direction::BtoA ).account,
+                     pool.account ];
+
+        match source.accounts( &keys ) {
+            Ok( accounts ) => {
+                for ( i, acc ) in accounts.iter( ).enumerate( ) {
+                    if let Some( acc ) = acc {
+                        res.update_cpmm_data( i, acc );
+                    }
+                }
+            },
+            Err( err ) => {
+                error!( "{:?}", err );
+            }
+        }
+        res
+    }
+
+    pub fn update( &mut self, kind: usize, account_data: &UiAccount ) {
+        match account_data.decode::<SdkAccount>( ) {
+            Some( sdk_acc ) => {
+                self.update_data( kind, &sdk_acc );
+            },
+            None => {
+                warn!( "Malformed uiaccount {:?}", account_data );
+            }
+        }
+    }
+
+    fn update_data( &mut self, kind: usize, account: &SdkAccount ) {
+        match kind {
+            0 | 1 => {
+                self.vault_amounts[ kind ] = unpack_token_amount( account );
+            },
+            2 => {
+                self.amm = AmmInfo::unpack_from_slice( &account.data ).ok( );
+            },
+            3 => {
+                self.open_orders = OpenOrdersInfo::unpack_from_slice( &account.data ).ok( );
+            },
+            _ => {
+                // serum market, nothing to extract
+            }
+        }
+    }
+
+    /* Like update, for a cpmm pool: vaults 0 and 1, pool state 2 */
+    pub fn update_cpmm( &mut self, kind: usize, account_data: &UiAccount ) {
+        match account_data.decode::<SdkAccount>( ) {
+            Some( sdk_acc ) => {
+                self.update_cpmm_data( kind, &sdk_acc );
+            },
+            None => {
+                warn!( "Malformed uiaccount {:?}", account_data );
+            }
+        }
+    }
+
+    fn update_cpmm_data( &mut self, kind: usize, account: &SdkAccount ) {
+        match kind {
+            0 | 1 => {
+                self.vault_amounts[ kind ] = unpack_token_amount( account );
+            },
+            2 => {
+                self.cpmm = CpmmPoolInfo::unpack_from_slice( &account.data ).ok( );
+            },
+            _ => { }
+        }
+    }
+}
+
+/* Reads the state of a whirlpool account; its virtual reserves price the current tick range */
+fn whirlpool_info( account: &SdkAccount ) -> Option<WhirlpoolInfo> {
+    WhirlpoolInfo::unpack_from_slice( &account.data ).ok( )
+}
+
+/* Reads the balance of a token account owned by either the classic token program or token-2022 */
+pub fn unpack_token_amount( account: &SdkAccount ) -> Option<u64> {
+    if account.owner == spl_token_2022::id( ) {
+        // token-2022 accounts may carry extensions after the base state
+        StateWithExtensions::<Account2022>::unpack( &account.data ).ok( ).map( |a| a.base.amount )
+    } else {
+        Account::unpack_unchecked( &account.data ).ok( ).map( |a| a.amount )
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::config::tests::*;
+
+    /* In-memory token balances, raw amount and decimals per token account, and any other
+     * accounts as they are */
+    pub struct MockSource {
+        pub balances: HashMap<Pubkey, ( u64, u8 )>,
+        pub others:   HashMap<Pubkey, SdkAccount>,
+    }
+
+    impl MockSource {
+        /* Funds both vaults of each pool with the given raw amounts */
+        pub fn new( pools: &Vec<Pool>, amounts: &[ [ u64; 2 ] ] ) -> Self {
+            let mut balances = HashMap::new( );
+            for ( p, amount ) in pools.iter( ).zip( amounts ) {
+                for i in 0 ..= 1 {
+                    balances.insert( p.get_currency( Direction::BOTH[ i ] ).account, ( amount[ i ], 6 ) );
+                }
+            }
+            MockSource{ balances: balances, others: HashMap::new( ) }
+        }
+    }
+
+    impl PriceSource for MockSource {
+        fn token_balance( &self, account: &Pubkey ) -> ClientResult<( f64, u8 )> {
+            let ( amount, decs ) = self.balances.get( account ).cloned( ).unwrap_or( ( 0, 6 ) );
+            Ok( ( amount as f64 / pow10( decs as usize ), decs ) )
+        }
+
+        fn accounts( &self, keys: &[ Pubkey ] ) -> ClientResult<Vec<Option<SdkAccount>>> {
+            Ok( keys.iter( ).map( |k| {
+                if let Some( acc ) = self.others.get( k ) {
+                    return Some( acc.clone( ) );
+                }
+                self.balances.get( k ).map( |( amount, _ )| {
+                    let mut data = vec![ 0u8; Account::LEN ];
+                    let acc = Account{ amount: *amount,
+                                       state: spl_token::state::AccountState::Initialized,
+                                       ..Account::default( ) };
+                    Account::pack( acc, &mut data ).unwrap( );
+                    SdkAccount{ lamports: 1, data: data, owner: spl_token::id( ),
+                                executable: false, rent_epoch: 0 }
+                } )
+            } ).collect( ) )
+        }
+    }
+
+    /* The prices of the pools, funded as in MockSource::new */
+    pub fn test_prices( pools: &Vec<Pool>, amounts: &[ [ u64; 2 ] ] ) -> Vec<PoolPrice> {
+        let source = MockSource::new( pools, amounts );
+        let n = pools.iter( ).map( |p| max( p.get_currency( Direction::AtoB ).currency_idx,
+                                            p.get_currency( Direction::BtoA ).currency_idx ) ).max( );
+        let currencies = test_currencies( n.map_or( 0, |n| n as u8 + 1 ) );
+        PoolPrice::init_all( &source, pools, &currencies ).unwrap( ).0
+    }
+
+    #[test]
+    fn init_all_reads_vault_balances( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let pools = vec![ test_pool( &config, 0, 0, 1 ), test_pool( &config, 1, 1, 2 ) ];
+        let prices = test_prices( &pools, &[ [ 1_000_000, 2_000_000 ], [ 5, 7 ] ] );
+
+        assert_eq!( prices.len( ), 2 );
+        assert_eq!( prices[ 0 ].token_price[ 0 ].token_amount, ( 1.0, 6 ) );
+        assert_eq!( prices[ 0 ].token_price[ 1 ].token_amount, ( 2.0, 6 ) );
+        assert_eq!( prices[ 1 ].token_amount( Direction::AtoB ).round( ), 5.0 );
+        assert_eq!( prices[ 1 ].token_amount( Direction::BtoA ).round( ), 7.0 );
+    }
+
+    #[test]
+    fn init_all_fails_on_a_missing_vault( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let pools = vec![ test_pool( &config, 0, 0, 1 ) ];
+        let mut source = MockSource::new( &pools, &[ [ 1, 1 ] ] );
+        source.balances.remove( &pools[ 0 ].get_currency( Direction::BtoA ).account );
+
+        assert!( PoolPrice::init_all( &source, &pools, &test_currencies( 2 ) ).is_err( ) );
+    }
+
+    /* A cpmm pool state for the pool's vaults, with the given protocol and fund fees */
+    fn cpmm_state( pool: &Pool, protocol_fees: [ u64; 2 ], fund_fees: [ u64; 2 ] ) -> SdkAccount {
+        let mut data = vec![ 0u8; 637 ];
+        data[ 72 .. 104 ].copy_from_slice( &pool.get_currency( Direction::AtoB ).account.to_bytes( ) );
+        data[ 104 .. 136 ].copy_from_slice( &pool.get_currency( Direction::BtoA ).account.to_bytes( ) );
+        data[ 232 .. 264 ].copy_from_slice( &spl_token::id( ).to_bytes( ) );
+        data[ 264 .. 296 ].copy_from_slice( &spl_token_2022::id( ).to_bytes( ) );
+        for i in 0 ..= 1 {
+            data[ 341 + 8 * i .. 349 + 8 * i ].copy_from_slice( &protocol_fees[ i ].to_le_bytes( ) );
+            data[ 357 + 8 * i .. 365 + 8 * i ].copy_from_slice( &fund_fees[ i ].to_le_bytes( ) );
         }
+        SdkAccount{ lamports: 1, data: data, owner: Pubkey::default( ), executable: false, rent_epoch: 0 }
+    }
+
+    #[test]
+    fn cpmm_reserves_leave_out_pending_fees( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let pools = vec![ test_cpmm_pool( &config, 0, 0, 1 ) ];
+        let state_key = test_key( 3, 0 );
+        let mut source = MockSource::new( &pools, &[ [ 1_000_000, 2_000_000 ] ] );
+
+        // without the pool state the fees are unknown
+        assert!( PoolPrice::init_all( &source, &pools, &test_currencies( 2 ) ).is_err( ) );
+
+        source.others.insert( state_key, cpmm_state( &pools[ 0 ], [ 1_000, 0 ], [ 500, 2_000 ] ) );
+        let ( mut prices, mut accounts ) = PoolPrice::init_all( &source, &pools, &test_currencies( 2 ) ).unwrap( );
+        assert_eq!( prices[ 0 ].token_amount( Direction::AtoB ).round( ), 998_500.0 );
+        assert_eq!( prices[ 0 ].token_amount( Direction::BtoA ).round( ), 1_998_000.0 );
+        assert_eq!( accounts[ 0 ].cpmm.unwrap( ).token_programs, [ spl_token::id( ), spl_token_2022::id( ) ] );
+
+        // collecting the fees leaves the vaults as they are
+        let collected = cpmm_state( &pools[ 0 ], [ 0, 0 ], [ 0, 0 ] );
+        let ui = UiAccount::encode( &state_key, &collected, solana_account_decoder::UiAccountEncoding::Base64,
+                                    None, None );
+        prices[ 0 ].update( &pools[ 0 ], &mut accounts[ 0 ], 2, &ui );
+        assert!( prices[ 0 ].sanity );
+        assert_eq!( prices[ 0 ].token_amount( Direction::AtoB ).round( ), 1_000_000.0 );
+        assert_eq!( prices[ 0 ].token_amount( Direction::BtoA ).round( ), 2_000_000.0 );
     }
 }
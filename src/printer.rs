@@ -1,296 +1,754 @@
 use std::{
-    sync::mpsc::channel,
-    time::{ SystemTime, UNIX_EPOCH },
+    cmp::{ max, min },
+    collections::HashMap,
+    error::Error,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{ AtomicBool, Ordering },
+        mpsc::{ channel, RecvTimeoutError },
+    },
+    time::{ Duration, Instant, SystemTime, UNIX_EPOCH },
 };
 use solana_sdk::{
+    account::Account as SdkAccount,
     signature::{ Signer, Signature },
-    commitment_config::CommitmentConfig,
     signer::keypair::Keypair,
     hash::Hash,
+    pubkey::Pubkey,
     compute_budget::ComputeBudgetInstruction,
 };
 use solana_client::{
-    rpc_config::{
-        RpcAccountInfoConfig,
-    },
     rpc_response::{
         Response as RpcResponse
     },
 };
-use solana_rpc::{
-    rpc_pubsub::gen_client::Client as PubsubClient,
-};
-use solana_account_decoder::{
-    UiAccount, UiAccountEncoding
-};
 use spl_token::{
     solana_program::{
         instruction::{ Instruction },
     },
 };
 
-use jsonrpc_core::futures::StreamExt;
-use jsonrpc_client_transports::transports::ws;
-use tokio::runtime::Runtime;
+use tokio::{
+    runtime::Runtime,
+    signal::unix::{ signal, SignalKind },
+    sync::mpsc::unbounded_channel,
+};
 use bit_vec::BitVec;
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::json;
+use log::{ debug, info, warn, error, log_enabled, Level };
 
 use crate::{
     communication::*,
     config::*,
+    metrics::*,
     price::*,
+    record::*,
+    subscription::*,
 };
 
+// Number of print loop iterations between two cooldown summaries in debug mode
+const COOLDOWN_SUMMARY_INTERVAL: u64 = 1000;
+// Number of cycles listed in a cooldown summary
+const COOLDOWN_SUMMARY_LENGTH: usize = 10;
+// Seconds between two reads of the start currency's price for minimum_gain_usd
+const ORACLE_REFRESH_INTERVAL: u64 = 30;
+// seconds after which a sent transaction is no longer in flight; its blockhash expired
+const IN_FLIGHT_EXPIRY: u64 = 90;
+// ms the print loop waits for an update before checking for a requested reload
+const RELOAD_POLL_INTERVAL: u64 = 1000;
+// ms execute --report waits for the transaction if confirm_timeout is unset
+const REPORT_CONFIRM_TIMEOUT: u64 = 60_000;
+// Upper bounds (exclusive, in toys) of the yield histogram buckets; the last bucket is unbounded
+const YIELD_BUCKETS: [ i128; 7 ] = [ -1_000_000, -10_000, -100, 0, 100, 10_000, 1_000_000 ];
+
 // Structs
 
 pub struct Printer {
-    pub money: u64,
-    pub debug: bool,
+    pub money: Vec<u64>, // balance of each start currency, indexed like currencies
     pub currencies: Vec<Currency>,
     pub pools: Vec<Pool>,
-    pub cycles: Vec<Cycle>
+    pub cycles: Vec<Cycle>,
+    pub metrics: Arc<Metrics>,
+    pub simulate_confirm: bool, // when simulating, report the resulting balance changes
+    pub dump_instructions: bool, // print the instructions of a cycle instead of sending them
+    pub extra_signer_seed: Option<[ u8; 32 ]>, // fixed extra signer, for reproducible transactions
+    pub fixed_gamble: Option<u64>, // gambled instead of the optimum, clamped to the balance
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListedHop {
+    pub pool: String,
+    pub dir:  Direction,
+    pub from: String,
+    pub to:   String,
+    pub impact: Option<f64>, // price impact of this leg at the optimal gamble, unknown if a pool is stale
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListedCycle {
+    pub index:           usize,
+    pub path:            Vec<ListedHop>,
+    pub opt_gamble:      u64,
+    pub predicted_yield: u128,
+}
+
+/* Token bucket limiting the transactions sent across all cycles; holds up to a second's
+ * worth of tokens. */
+struct TxRateLimiter {
+    rate:   Option<f64>,
+    tokens: f64,
+    last:   Instant,
+}
+
+/* Transactions sent per cycle that did not land or fail yet, with the time they were sent */
+struct InFlight {
+    max:     Option<usize>,
+    pending: Vec<Vec<( Signature, Instant )>>,
+}
+
+/* Distribution of the yields (gain - gamble money, in toys) observed for a cycle */
+#[derive(Debug, Clone, Default)]
+struct YieldStats {
+    count:     u64,
+    min:       i128,
+    max:       i128,
+    sum:       i128,
+    histogram: [ u64; YIELD_BUCKETS.len( ) + 1 ],
+}
+
+/* Highest net yield observed by the print loop */
+struct Ath {
+    gain: i128,          // in toys of the first start currency, so only its cycles compete for it
+    cyc:  Option<usize>, // None once a reload removed the cycle
+    desc: String,        // outlives the cycle if a reload removes it
+    decs: u8,
+    date: Duration,      // since the epoch
+}
+
+/* Per cycle bookkeeping of the submissions, which gates sending a cycle again */
+struct SubmitState {
+    submissions:  Vec<u64>, // how often each cycle was sent, for the debug summary
+    attempts:     Vec<u64>, // confirmed outcomes, for the expected value check
+    successes:    Vec<u64>,
+    failures:     Vec<u32>, // consecutive failed submissions
+    backoff:      Vec<u64>, // iterations the cycle still sits out
+    in_flight:    InFlight,
+    rate_limiter: TxRateLimiter,
 }
 
 // Implementations
 
+impl InFlight {
+    fn new( max: Option<usize>, cycles: usize ) -> Self {
+        InFlight {
+            max:     max,
+            pending: vec![ Vec::new( ); cycles ],
+        }
+    }
+
+    /* Whether another transaction may be sent for the cycle; asks for the status of the
+     * outstanding ones only if the cap is reached. */
+    fn try_reserve( &mut self, comm: &Communication, cycle: usize ) -> bool {
+        let max = match self.max {
+            Some( max ) => { max },
+            None => { return true; }
+        };
+        let pending = &mut self.pending[ cycle ];
+        pending.retain( |( _, sent )| sent.elapsed( ).as_secs( ) < IN_FLIGHT_EXPIRY );
+        if pending.len( ) < max {
+            return true;
+        }
+
+        let signatures: Vec<Signature> = pending.iter( ).map( |( sig, _ )| *sig ).collect( );
+        match comm.with_retries( || comm.rpc_client( ).get_signature_statuses( &signatures ) ) {
+            Ok( statuses ) => {
+                let mut i = 0;
+                pending.retain( |_| {
+                    let unresolved = statuses.value.get( i ).map_or( true, |s| s.is_none( ) );
+                    i = i + 1;
+                    unresolved
+                } );
+            },
+            Err( err ) => {
+                warn!( "Fetching status of transactions in flight failed: {:?}", err );
+            }
+        }
+        pending.len( ) < max
+    }
+
+    fn add( &mut self, cycle: usize, signature: Signature ) {
+        if self.max.is_some( ) {
+            self.pending[ cycle ].push( ( signature, Instant::now( ) ) );
+        }
+    }
+}
+
+/* Whether the cycle is profitable once the estimated fee is paid; logs cycles that only
+ * the fee makes unprofitable. */
+fn is_profitable_net( config: &Config, cycle: usize, toys_out: u128, gamble_money: u64,
+                      minimum_gain: u128, fee: u128 ) -> bool {
+    if config.is_profitable_for( toys_out.saturating_sub( fee ), gamble_money, minimum_gain ) {
+        return true;
+    }
+    if fee > 0 && config.is_profitable_for( toys_out, gamble_money, minimum_gain ) {
+        debug!( "Cycle {} is rejected by its estimated fee of {} toys.", cycle, fee );
+    }
+    false
+}
+
+/* Prints every instruction with its program, accounts (w: writable, s: signer) and data */
+fn dump_instructions( instructions: &Vec<Instruction> ) {
+    for ( i, ins ) in instructions.iter( ).enumerate( ) {
+        println!( "Instruction {}: program {}", i, ins.program_id );
+        for ( j, acc ) in ins.accounts.iter( ).enumerate( ) {
+            println!( "  {:2} {}{} {}", j,
+                      if acc.is_writable { "w" } else { "-" },
+                      if acc.is_signer { "s" } else { "-" },
+                      acc.pubkey );
+        }
+        let data: String = ins.data.iter( ).map( |b| format!( "{:02x}", b ) ).collect( );
+        println!( "  data: {}", data );
+    }
+}
+
+/* Fetches a new blockhash if the given one is older than configured. Returns false if that
+ * failed. */
+fn refresh_blockhash( comm: &Communication, config: &Config, hash: &mut Hash,
+                      fetched: &mut Instant ) -> bool {
+    let max_age = match config.blockhash_max_age_ms {
+        Some( max_age ) => { Duration::from_millis( max_age ) },
+        None => { return true; }
+    };
+    if fetched.elapsed( ) < max_age {
+        return true;
+    }
+
+    match comm.get_blockhash( ) {
+        Ok( new_hash ) => {
+            *hash = new_hash;
+            *fetched = Instant::now( );
+            true
+        },
+        Err( err ) => {
+            error!( "Fetching blockhash failed: {:?}", err );
+            false
+        }
+    }
+}
+
+impl TxRateLimiter {
+    fn new( rate: Option<f64> ) -> Self {
+        TxRateLimiter {
+            rate:   rate,
+            tokens: rate.unwrap_or( 0.0 ).max( 1.0 ),
+            last:   Instant::now( ),
+        }
+    }
+
+    fn try_acquire( &mut self ) -> bool {
+        let rate = match self.rate {
+            Some( rate ) => { rate },
+            None => { return true; }
+        };
+
+        let now = Instant::now( );
+        let capacity = rate.max( 1.0 );
+        self.tokens = ( self.tokens + now.duration_since( self.last ).as_secs_f64( ) * rate ).min( capacity );
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens = self.tokens - 1.0;
+        true
+    }
+}
+
+impl YieldStats {
+    fn add( &mut self, y: i128 ) {
+        if self.count == 0 || y < self.min { self.min = y; }
+        if self.count == 0 || y > self.max { self.max = y; }
+        self.count = self.count + 1;
+        self.sum = self.sum + y;
+        let bucket = YIELD_BUCKETS.iter( ).position( |b| y < *b ).unwrap_or( YIELD_BUCKETS.len( ) );
+        self.histogram[ bucket ] = self.histogram[ bucket ] + 1;
+    }
+
+    fn mean( &self ) -> f64 {
+        if self.count == 0 { 0.0 } else { self.sum as f64 / self.count as f64 }
+    }
+}
+
+impl SubmitState {
+    fn new( config: &Config, cycles: usize ) -> Self {
+        SubmitState {
+            submissions:  vec![ 0; cycles ],
+            attempts:     vec![ 0; cycles ],
+            successes:    vec![ 0; cycles ],
+            failures:     vec![ 0; cycles ],
+            backoff:      vec![ 0; cycles ],
+            in_flight:    InFlight::new( config.max_in_flight, cycles ),
+            rate_limiter: TxRateLimiter::new( config.max_tx_per_sec ),
+        }
+    }
+
+    /* Starts the per cycle state over, e.g. after the cycles changed; the rate limit carries on */
+    fn reset( &mut self, config: &Config, cycles: usize ) {
+        let rate_limiter = std::mem::replace( &mut self.rate_limiter, TxRateLimiter::new( None ) );
+        *self = SubmitState{ rate_limiter: rate_limiter, ..SubmitState::new( config, cycles ) };
+    }
+}
+
+impl Ath {
+    fn save( &self, n_cycles: usize, state_path: Option<&str> ) {
+        if let ( Some( path ), Some( cyc ) ) = ( state_path, self.cyc ) {
+            AthState{ cycles: n_cycles, ath: self.gain, ath_cyc: cyc,
+                      ath_date: self.date.as_secs( ) }.save( path );
+        }
+    }
+}
+
 impl Printer {
     pub fn init( comm: &Communication, config: &Config,
-                 currencies: &Vec<Currency>, pools: &Vec<Pool>, cycles: &Vec<Cycle>,
-                 debug: bool ) -> Self {
-        let money = comm.get_current_balance( config, currencies );
+                 currencies: &Vec<Currency>, pools: &Vec<Pool>, cycles: &Vec<Cycle> ) -> Self {
+        let mut money = vec![ 0; currencies.len( ) ];
+        for &start in &config.start_currencies {
+            money[ start ] = match comm.get_current_balance( config, currencies, start ) {
+                Ok( money ) => { money },
+                Err( err ) => {
+                    error!( "{:?}", err );
+                    std::process::exit( 1 )
+                }
+            };
+        }
+        let printer = Self::new( money, currencies, pools, cycles );
+        printer.metrics.balance.store( printer.money[ config.start_currency ], Ordering::Relaxed );
+        for &start in &config.start_currencies {
+            printer.check_reserve( config, start );
+        }
+        printer
+    }
+
+    pub fn new( money: Vec<u64>, currencies: &Vec<Currency>, pools: &Vec<Pool>, cycles: &Vec<Cycle> ) -> Self {
+        let metrics = Arc::new( Metrics::default( ) );
+        metrics.cycles.store( cycles.len( ) as u64, Ordering::Relaxed );
+
         Printer {
             money:      money,
-            debug:      debug,
             currencies: currencies.clone( ),
             pools:      pools.clone( ),
-            cycles:     cycles.clone( )
+            cycles:     cycles.clone( ),
+            metrics:    metrics,
+            simulate_confirm: false,
+            dump_instructions: false,
+            extra_signer_seed: None,
+            fixed_gamble: None,
         }
     }
 
     pub fn test_path( &self, comm: &Communication, comm_send: &Communication, config: &Config,
-                      cycle_idx: usize, simulate: bool ) {
+                      cycle_idx: usize, simulate: bool, force: bool, log_dir: Option<&Path>,
+                      report: bool ) {
         let cycle = &self.cycles[ cycle_idx ];
 
         // initialize pool prizes
-        let mut pool_prices = Vec::new( );
-        for p in &self.pools {
-            pool_prices.push( PoolPrice::init( comm, &p ) );
-        }
-
-        let gamble_money = self.get_best_gamble_money( config, cycle, &pool_prices );
-
-        if gamble_money < config.minimum_money {
-            eprintln!( "Insufficient balance, aborting." );
-            std::process::exit( 1 );
+        let ( pool_prices, _ ) = PoolPrice::init_all( comm, &self.pools, &self.currencies )
+            .unwrap_or_else( |err| {
+                error!( "Initializing pool prices failed: {}", err );
+                std::process::exit( 1 )
+            } );
+
+        let mut gamble_money = self.get_best_gamble_money( config, cycle, &pool_prices );
+
+        let minimum_money = config.minimum_money_in( cycle.start_currency( &self.pools ) );
+        if gamble_money < minimum_money {
+            if !force {
+                error!( "Insufficient balance, aborting." );
+                std::process::exit( 1 );
+            }
+            warn!( "Insufficient balance." );
+            gamble_money = minimum_money;
         }
 
 
         let toys_out = self.compute_potential( config, cycle, &pool_prices, gamble_money );
 
-        if self.debug {
-            println!( "Executing path for {} toys:", gamble_money );
-            print_cycle( &cycle, &self.pools, &self.currencies );
-            println!( " yields {}.", toys_out );
-        }
+        debug!( "Executing path for {} toys: {} yields {}.", gamble_money,
+                format_cycle( &cycle, &self.pools, &self.currencies ), toys_out );
+
+        let start = cycle.start_currency( &self.pools );
+        let report = report && !simulate;
+        let before = if report {
+            comm.get_current_balance( config, &self.currencies, start ).unwrap_or_else( |err| {
+                error!( "Fetching balance failed: {:?}", err );
+                std::process::exit( 1 )
+            } )
+        } else {
+            0
+        };
 
         // execute path
 
-        let hash = comm_send.get_blockhash( );
-        self.execute_path( comm_send, &cycle, gamble_money, config, &pool_prices, simulate, hash );
+        let hash = comm_send.get_blockhash( ).expect( "Fetching blockhash failed" );
+        let log_path = log_dir.map( |dir| dir.join( format!( "{}.log", cycle_idx ) ) );
+        let signature = match self.execute_path( comm_send, &cycle, gamble_money, config, &pool_prices,
+                                                 simulate, hash, log_path.as_deref( ) ) {
+            Some( signature ) => { signature },
+            None => { return; }
+        };
+        if !report {
+            return;
+        }
+
+        // compare the outcome with the prediction
+        let timeout = Duration::from_millis( config.confirm_timeout.unwrap_or( REPORT_CONFIRM_TIMEOUT ) );
+        match comm_send.confirm_signature( &signature, timeout ) {
+            Ok( Some( status ) ) => {
+                match status.err {
+                    Some( err ) => { println!( "Transaction {} failed: {:?}", signature, err ); },
+                    None => { println!( "Transaction {} confirmed in slot {}.", signature, status.slot ); }
+                }
+            },
+            Ok( None ) => {
+                println!( "Transaction {} not confirmed in time, the balance may still change.", signature );
+            },
+            Err( err ) => {
+                error!( "Confirming transaction {} failed: {:?}", signature, err );
+            }
+        }
+        match comm.get_current_balance( config, &self.currencies, start ) {
+            Ok( after ) => {
+                let start_currency = &self.currencies[ start ];
+                let realized = after as i128 - before as i128;
+                let predicted = toys_out as i128 - gamble_money as i128;
+                println!( "Realized {} {}, predicted {} {} ({} toys off).",
+                          format_amount( realized, start_currency.decimals ), start_currency.name,
+                          format_amount( predicted, start_currency.decimals ), start_currency.name,
+                          realized - predicted );
+            },
+            Err( err ) => {
+                error!( "Fetching balance failed: {:?}", err );
+            }
+        }
     }
 
-    pub fn list_path( &self, comm: &Communication, config: &Config ) {
+    pub fn list_path( &self, comm: &Communication, config: &Config, json: bool ) {
         // initialize pool prizes
-        let mut pool_prices = Vec::new( );
-        for p in &self.pools {
-            pool_prices.push( PoolPrice::init( comm, &p ) );
+        let ( pool_prices, _ ) = PoolPrice::init_all( comm, &self.pools, &self.currencies )
+            .unwrap_or_else( |err| {
+                error!( "Initializing pool prices failed: {}", err );
+                std::process::exit( 1 )
+            } );
+
+        if !json {
+            println!( "Printing paths and estimated gains." );
+        }
+
+        for &start in &config.start_currencies {
+            let mut gamble_money = self.get_gamble_money( config, start );
+            if gamble_money < config.minimum_money_in( start ) {
+                warn!( "Insufficient balance of {}.", self.currencies[ start ].name );
+                gamble_money = config.minimum_money_in( start );
+            }
+
+            let start_currency = &self.currencies[ start ];
+            if !json {
+                println!( "Testing for {} {}:", format_amount( gamble_money as i128, start_currency.decimals ),
+                          start_currency.name );
+            }
         }
+        let mut listed = Vec::new( );
+        let mut idx = 0;
 
-        let mut gamble_money = self.get_gamble_money( config );
+        for cycle in &self.cycles {
+            let opt_gamble_money = self.get_best_gamble_money( config, cycle, &pool_prices );
 
-        println!( "Printing paths and estimated gains." );
+            let mut impacts = Vec::new( );
+            let toys_out = self.compute_legs( config, cycle, &pool_prices, opt_gamble_money,
+                                              Some( &mut impacts ) );
+
+            if json {
+                let path = cycle.path.iter( ).enumerate( ).map( |( i, ( p, w ) )| ListedHop {
+                    pool: self.pools[ *p ].get_name( ).clone( ),
+                    dir:  *w,
+                    from: self.currencies[ self.pools[ *p ].get_currency( *w ).currency_idx ].name.clone( ),
+                    to:   self.currencies[ self.pools[ *p ].get_currency( w.flip( ) ).currency_idx ].name.clone( ),
+                    impact: impacts.get( i ).cloned( ),
+                } ).collect( );
+
+                listed.push( ListedCycle {
+                    index:           idx,
+                    path:            path,
+                    opt_gamble:      opt_gamble_money,
+                    predicted_yield: toys_out,
+                } );
+            } else {
+                print!( "{}:", idx );
+                print_cycle( cycle, &self.pools, &self.currencies );
+                // several pools may trade the same pair; tell them apart by name
+                let names: Vec<&str> = cycle.path.iter( ).map( |( p, _ )| self.pools[ *p ].get_name( ).as_str( ) ).collect( );
+                print!( " via {}", names.join( ", " ) );
+                let start_currency = &self.currencies[ cycle.start_currency( &self.pools ) ];
+                print!( " yields {}.", format_amount( toys_out as i128, start_currency.decimals ) );
+
+                let impacts: Vec<String> = impacts.iter( ).map( |i| format!( "{:.2}%", i * 100.0 ) ).collect( );
+                println!( " (Opt gamble: {}, leg impacts: {})",
+                          format_amount( opt_gamble_money as i128, start_currency.decimals ), impacts.join( ", " ) );
+            }
 
-        if gamble_money < config.minimum_money {
-            println!( "WARNING: Insufficient balance." );
-            gamble_money = config.minimum_money;
+            idx = idx + 1;
+        }
+
+        if json {
+            // the startup progress goes to stderr, so stdout carries nothing but the array
+            println!( "{}", serde_json::to_string( &listed ).expect( "Serializing cycles failed" ) );
         }
+    }
+
+    /* Simulates every cycle; with a log_dir, each cycle's program logs go to
+     * <log_dir>/<cycle index>.log */
+    pub fn profile_path( &self, comm: &Communication, config: &Config, log_dir: Option<&Path> ) {
+        // initialize pool prizes
+        let ( pool_prices, _ ) = PoolPrice::init_all( comm, &self.pools, &self.currencies )
+            .unwrap_or_else( |err| {
+                error!( "Initializing pool prices failed: {}", err );
+                std::process::exit( 1 )
+            } );
+
+        println!( "Simulating paths and measuring compute units." );
 
-        println!( "Testing for {} toys:", gamble_money );
+        let hash = comm.get_blockhash( ).expect( "Fetching blockhash failed" );
+        let mut max_units = 0;
+        let mut max_cyc = 0;
         let mut idx = 0;
 
         for cycle in &self.cycles {
             print!( "{}:", idx );
+            print_cycle( cycle, &self.pools, &self.currencies );
 
+            let mut gamble_money = self.get_best_gamble_money( config, cycle, &pool_prices );
+            let minimum_money = config.minimum_money_in( cycle.start_currency( &self.pools ) );
+            if gamble_money < minimum_money {
+                gamble_money = minimum_money;
+            }
 
-            let opt_gamble_money = self.get_best_gamble_money( config, cycle, &pool_prices );
-
-            let toys_out = self.compute_potential( config, cycle, &pool_prices, opt_gamble_money );
+            // extra signer required for some marketplaces. Only used if required.
+            let extra_signer = extra_signer( self.extra_signer_seed );
 
-            print_cycle( cycle, &self.pools, &self.currencies );
-            print!( " yields {}.", toys_out );
+            // don't insist on a profit; the whole cycle needs to run to be measured
+            match self.build_path( comm, cycle, gamble_money, None, config, &pool_prices, &extra_signer ) {
+                Some( instructions ) => {
+                    let signers = if cycle.needs_approval {
+                        vec![ &comm.wallet, &extra_signer ]
+                    } else {
+                        vec![ &comm.wallet ]
+                    };
+                    match comm.simulate_transaction( &instructions, &signers, hash ) {
+                        Ok( res ) => {
+                            if let ( Some( dir ), Some( logs ) ) = ( log_dir, &res.logs ) {
+                                write_logs( &dir.join( format!( "{}.log", idx ) ), logs );
+                            }
+                            let units = res.units_consumed.unwrap_or( 0 );
+                            print!( " consumes {} compute units", units );
+                            if let Some( err ) = res.err {
+                                print!( " (failed: {:?})", err );
+                            }
+                            println!( "." );
 
-            println!( " (Opt gamble: {})", opt_gamble_money );
+                            if units > max_units {
+                                max_units = units;
+                                max_cyc = idx;
+                            }
+                        },
+                        Err( err ) => {
+                            println!( " simulation failed: {:?}", err );
+                        }
+                    }
+                },
+                None => {
+                    println!( " creating tx failed." );
+                }
+            }
 
             idx = idx + 1;
         }
+
+        if !self.cycles.is_empty( ) {
+            print!( "Highest usage: {} compute units on cycle {} ", max_units, max_cyc );
+            print_cycle( &self.cycles[ max_cyc ], &self.pools, &self.currencies );
+            println!( "." );
+        }
+    }
+
+    /* Replays recorded account updates against the decision logic, without any rpc */
+    pub fn replay( &mut self, config: &Config, records: &Vec<RecordEntry> ) {
+        let mut pool_prices: Vec<PoolPrice> = Vec::new( );
+        let mut raydium_accounts = vec![ RaydiumAccounts::default( ); self.pools.len( ) ];
+
+        let mut pool_update = vec![ BitVec::from_elem( self.cycles.len( ), false );
+                                    self.pools.len( ) ];
+        for i in 0 .. self.cycles.len( ) {
+            for ( idx, _ ) in &self.cycles[ i ].path {
+                pool_update[ *idx ].set( i, true );
+            }
+        }
+
+        println!( "Replaying {} recorded entries.", records.len( ) );
+
+        for entry in records {
+            match entry {
+                RecordEntry::Init{ money, token_amounts, balances } => {
+                    if token_amounts.len( ) != self.pools.len( ) {
+                        error!( "Recording has {} pools, but {} are configured.",
+                                   token_amounts.len( ), self.pools.len( ) );
+                        std::process::exit( 1 );
+                    }
+                    if balances.len( ) == self.currencies.len( ) {
+                        self.money = balances.clone( );
+                    } else {
+                        self.money = vec![ 0; self.currencies.len( ) ];
+                        self.money[ config.start_currency ] = *money;
+                    }
+                    pool_prices = token_amounts.iter( ).map( |t| PoolPrice::from_amounts( *t ) ).collect( );
+                },
+                RecordEntry::Update( response ) => {
+                    if pool_prices.is_empty( ) {
+                        error!( "Recording lacks an initial state." );
+                        std::process::exit( 1 );
+                    }
+                    let ( pool, tkn, result ) = &response.value;
+                    pool_prices[ *pool ].update( &self.pools[ *pool ], &mut raydium_accounts[ *pool ],
+                                                 *tkn, result );
+
+                    for i in 0 .. self.cycles.len( ) {
+                        if !pool_update[ *pool ][ i ] { continue; }
+
+                        let start = self.cycles[ i ].start_currency( &self.pools );
+                        let opt_gamble_money = self.get_best_gamble_money( config, &self.cycles[ i ],
+                                                                           &pool_prices );
+                        if opt_gamble_money < config.minimum_money_in( start ) { continue; }
+                        let rs = self.compute_potential( config, &self.cycles[ i ],
+                                                         &pool_prices, opt_gamble_money );
+                        if config.is_profitable( start, rs, opt_gamble_money ) {
+                            print!( "slot {}: would execute {}:", response.context.slot, i );
+                            print_cycle( &self.cycles[ i ], &self.pools, &self.currencies );
+                            println!( " on {} toys, yielding {}.", opt_gamble_money, rs );
+                        }
+                    }
+                }
+            }
+        }
     }
 
     pub fn run( &mut self, comm: &Communication, comm_send: &Communication,
-                config: &Config, simulate: bool ) {
+                config: &Config, simulate: bool, record_path: Option<&str>,
+                state_path: Option<&str>, pool_path: &str, filter: &PoolFilter ) {
         // check if rpc is good
-        comm_send.get_blockhash( );
+        comm_send.get_blockhash( ).expect( "RPC is garbage" );
 
         // initialize pool prizes
-        let mut pool_prices = Vec::new( );
-        for p in &self.pools {
-            pool_prices.push( PoolPrice::init( comm, &p ) );
+        let ( mut pool_prices, mut raydium_accounts ) = PoolPrice::init_all( comm, &self.pools,
+                                                                             &self.currencies )
+            .unwrap_or_else( |err| {
+                error!( "Initializing pool prices failed: {}", err );
+                std::process::exit( 1 )
+            } );
+
+        let mut cycle_blocked = self.blocked_cycles( comm, config );
+
+        // record the initial state; everything else follows from the updates
+        let mut recorder = record_path.map( |path| {
+            Recorder::create( path ).expect( "Cannot create record file" )
+        } );
+        if let Some( rec ) = recorder.as_mut( ) {
+            rec.record( &RecordEntry::Init {
+                money:         self.money[ config.start_currency ],
+                token_amounts: pool_prices.iter( ).map( |p: &PoolPrice| {
+                    [ p.token_price[ 0 ].token_amount, p.token_price[ 1 ].token_amount ]
+                } ).collect( ),
+                balances:      self.money.clone( ),
+            } );
         }
 
-        // set up subscriptions
+        // set up subscriptions; updates are tagged with the pool's subscription id, which
+        // equals its index until pools are reloaded
         let ( account_sender, account_receiver )
-            = channel::<RpcResponse<( usize, usize, UiAccount )>>( );
-
-        let config_clone = config.clone( );
-        let pools_clone = self.pools.clone( );
+            = channel::<AccountUpdate>( );
+        let ( command_sender, command_receiver )
+            = unbounded_channel::<SubscriptionCommand>( );
 
         // Create the pub sub runtime
         let rt = Runtime::new( ).unwrap( );
-        rt.spawn( async move {
-            let connect = ws::try_connect::<PubsubClient>( &config_clone.cluster_url ).unwrap( );
-            let client = connect.await.unwrap( );
-
-            // Subscribe to account notifications
-
-            let mut idx = 0;
-            for p in pools_clone {
-                match p {
-                    Pool::Swap( pool ) => {
-                        for i in 0 ..= 1 {
-                            let account_sender = account_sender.clone( );
-                            let mut client_sub = client
-                                .account_subscribe(
-                                    pool.get_currency( i ).account.to_string( ),
-                                    Some( RpcAccountInfoConfig {
-                                        commitment: Some( CommitmentConfig::confirmed( ) ),
-                                        encoding: Some( UiAccountEncoding::Base64Zstd ),
-                                        ..RpcAccountInfoConfig::default( )
-                                    } ),
-                                    ).unwrap_or_else( |err| panic!( "acct sub err: {:#?}", err ) );
-                            tokio::spawn( async move {
-                                loop {
-                                    match client_sub.next( ).await {
-                                        Some( response_ab ) => {
-                                            let response = response_ab.unwrap( );
-                                            let n_response = solana_client::rpc_response::Response{
-                                                context: response.context,
-                                                value: ( idx, i, response.value )
-                                            };
-                                            account_sender.send( n_response ).unwrap( );
-                                        }
-                                        None => { }
-                                    }
-                                }
-                            } );
-                        }
-                    },
-                    Pool::Raydium( pool ) => {
-                        // subscribe to pool token account changes
-                        for i in 0 ..= 1 {
-                            let account_sender = account_sender.clone( );
-                            let mut client_sub = client
-                                .account_subscribe(
-                                    pool.get_currency( i ).account.to_string( ),
-                                    Some( RpcAccountInfoConfig {
-                                        commitment: Some( CommitmentConfig::confirmed( ) ),
-                                        encoding: Some( UiAccountEncoding::Base64Zstd ),
-                                        ..RpcAccountInfoConfig::default( )
-                                    } ),
-                                    ).unwrap_or_else( |err| panic!( "acct sub err: {:#?}", err ) );
-                            tokio::spawn( async move {
-                                loop {
-                                    match client_sub.next( ).await {
-                                        Some( response_ab ) => {
-                                            let response = response_ab.unwrap( );
-                                            let n_response = solana_client::rpc_response::Response{
-                                                context: response.context,
-                                                value: ( idx, i, response.value )
-                                            };
-                                            account_sender.send( n_response ).unwrap( );
-                                        }
-                                        None => { }
-                                    }
-                                }
-                            } );
-                        }
-                        // ammOpenOrders
-                        {
-                            let account_sender = account_sender.clone( );
-                            let mut client_sub = client
-                                .account_subscribe(
-                                    pool.open_orders.to_string( ),
-                                    Some( RpcAccountInfoConfig {
-                                        commitment: Some( CommitmentConfig::confirmed( ) ),
-                                        encoding: Some( UiAccountEncoding::Base64Zstd ),
-                                        ..RpcAccountInfoConfig::default( )
-                                    } ),
-                                    ).unwrap_or_else( |err| panic!( "acct sub err: {:#?}", err ) );
-                            tokio::spawn( async move {
-                                loop {
-                                    match client_sub.next( ).await {
-                                        Some( response_ab ) => {
-                                            let response = response_ab.unwrap( );
-                                            let n_response = solana_client::rpc_response::Response{
-                                                context: response.context,
-                                                value: ( idx, 3, response.value )
-                                            };
-                                            account_sender.send( n_response ).unwrap( );
-                                        }
-                                        None => { }
-                                    }
-                                }
-                            } );
-                        }
-                        // serum market
-                        {
-                            let account_sender = account_sender.clone( );
-                            let mut client_sub = client
-                                .account_subscribe(
-                                    pool.serum_market.to_string( ),
-                                    Some( RpcAccountInfoConfig {
-                                        commitment: Some( CommitmentConfig::confirmed( ) ),
-                                        encoding: Some( UiAccountEncoding::Base64Zstd ),
-                                        ..RpcAccountInfoConfig::default( )
-                                    } ),
-                                    ).unwrap_or_else( |err| panic!( "acct sub err: {:#?}", err ) );
-                            tokio::spawn( async move {
-                                loop {
-                                    match client_sub.next( ).await {
-                                        Some( response_ab ) => {
-                                            let response = response_ab.unwrap( );
-                                            let n_response = solana_client::rpc_response::Response{
-                                                context: response.context,
-                                                value: ( idx, 4, response.value )
-                                            };
-                                            account_sender.send( n_response ).unwrap( );
-                                        }
-                                        None => { }
-                                    }
-                                }
-                            } );
-                        }
+        update_source( config ).spawn( &rt, command_receiver, account_sender );
+        let mut sids: Vec<usize> = ( 0 .. self.pools.len( ) ).collect( );
+        let mut sid_pool: HashMap<usize, usize> = sids.iter( ).map( |sid| ( *sid, *sid ) ).collect( );
+        let mut next_sid = self.pools.len( );
+        for ( sid, p ) in self.pools.iter( ).enumerate( ) {
+            command_sender.send( SubscriptionCommand::Subscribe( sid, p.clone( ) ) ).unwrap( );
+        }
+
+        // SIGHUP reloads the pool config
+        let reload_requested = Arc::new( AtomicBool::new( false ) );
+        {
+            let reload_requested = reload_requested.clone( );
+            rt.spawn( async move {
+                let mut hangup = match signal( SignalKind::hangup( ) ) {
+                    Ok( hangup ) => { hangup },
+                    Err( err ) => {
+                        warn!( "Cannot handle SIGHUP, pools won't be reloaded: {:?}", err );
+                        return;
                     }
+                };
+                while hangup.recv( ).await.is_some( ) {
+                    reload_requested.store( true, Ordering::Relaxed );
                 }
-                idx = idx + 1;
-            }
-        } );
+            } );
+        }
 
-        let mut ath = -( self.get_gamble_money( config ) as i128 );
-        let mut ath_cyc = 0;
-        let mut ath_date = SystemTime::now( ).duration_since( UNIX_EPOCH ).unwrap( );
+        // a connection may stay open without delivering anything; max_silence_secs catches that
+        let mut last_update = Instant::now( );
+
+        let mut ath = Ath {
+            gain: -( self.get_gamble_money( config, config.start_currency ) as i128 ),
+            cyc:  ( 0 .. self.cycles.len( ) )
+                .find( |&i| self.cycles[ i ].start_currency( &self.pools ) == config.start_currency ),
+            desc: String::new( ),
+            decs: 0,
+            date: SystemTime::now( ).duration_since( UNIX_EPOCH ).unwrap( ),
+        };
+        if let Some( state ) = state_path.and_then( |path| AthState::load( path, self.cycles.len( ) ) )
+            .filter( |state| self.cycles[ state.ath_cyc ].start_currency( &self.pools ) == config.start_currency ) {
+            ath.gain = state.ath;
+            ath.cyc = Some( state.ath_cyc );
+            ath.date = Duration::from_secs( state.ath_date );
+            self.metrics.ath_gain.store( ath.gain as i64, Ordering::Relaxed );
+        }
+        if let Some( c ) = ath.cyc {
+            let ( desc, decs ) = self.describe_cycle( c );
+            ath.desc = desc;
+            ath.decs = decs;
+        }
 
         let mut cycle_cooldown = vec![ config.cooldown; self.cycles.len( ) ];
         let mut cycle_gain = vec![ 0; self.cycles.len( ) ];
         let mut cycle_money = vec![ 0; self.cycles.len( ) ];
         let mut cycle_needs_update = BitVec::from_elem( self.cycles.len( ), true );
+        // with mirrors deduplicated, each cycle trades whichever direction currently nets more
+        let mut mirrors: Vec<Cycle> = if config.dedup_mirror_cycles {
+            self.cycles.iter( ).map( |c| c.mirror( ) ).collect( )
+        } else {
+            Vec::new( )
+        };
+        let mut cycle_mirrored = BitVec::from_elem( self.cycles.len( ), false );
+        // how often each cycle exhausted its cooldown, for the debug summary
+        let mut cycle_exhausted = vec![ 0u64; self.cycles.len( ) ];
+        let mut submit = SubmitState::new( config, self.cycles.len( ) );
+        // yields observed whenever a cycle is re-evaluated, for the debug summary
+        let mut cycle_yields = vec![ YieldStats::default( ); self.cycles.len( ) ];
+        let mut iteration: u64 = 0;
 
         let mut pool_update = vec![ BitVec::from_elem( self.cycles.len( ), false );
                                     self.pools.len( ) ];
@@ -300,34 +758,174 @@ impl Printer {
             }
         }
 
-        println!( "Initiating print sequence." );
+        // don't trade on the initial (possibly stale) prices until every pool got a consistent
+        // update or the warm-up elapsed
+        let start = Instant::now( );
+        let mut pool_warm = BitVec::from_elem( self.pools.len( ), false );
+        // last consistent price of each pool, to catch implausible jumps
+        let mut reference_prices: Vec<f64> = pool_prices.iter( ).map( |p| p.implied_price( ) ).collect( );
+        let mut armed = config.warmup_secs == 0;
+        // amounts are shown in units of each cycle's start currency
+        let start_decs = self.currencies[ config.start_currency ].decimals;
+        // toys every sent cycle costs, taken off its gain; by start currency
+        let fees: Vec<u128> = self.currencies.iter( ).enumerate( ).map( |( c, currency )| {
+            if config.start_currencies.contains( &c ) { config.estimated_fee( c, currency ) } else { 0 }
+        } ).collect( );
+        for &start in &config.start_currencies {
+            if fees[ start ] == 0 {
+                warn!( "Cannot price fees in {}, set lamport_price to account for them.",
+                       self.currencies[ start ].name );
+            }
+        }
+        // raw units until the oracle says otherwise; with it, there is a single start currency
+        let mut minimum_gains: Vec<u128> = ( 0 .. self.currencies.len( ) ).map( |c| config.minimum_gain_in( c ) ).collect( );
+        let mut oracle_read: Option<Instant> = None;
+        // read endpoint failovers the subscriptions followed
+        let mut failovers = 0;
+
+        info!( "Initiating print sequence." );
 
         // TODO: add counter for scheduled abort to reset pool information to counteract skew
         loop {
+            if reload_requested.swap( false, Ordering::Relaxed ) {
+                match self.load_pools( comm, config, pool_path, filter ) {
+                    Ok( ( pools, cycles ) ) => {
+                        // keep the state of pools that didn't change, fetch that of new ones
+                        let mut kept: Vec<Option<usize>> = Vec::new( );
+                        for p in &pools {
+                            let accounts = p.referenced_accounts( config );
+                            let old = ( 0 .. self.pools.len( ) ).find( |j| {
+                                !kept.contains( &Some( *j ) ) && self.pools[ *j ].get_name( ) == p.get_name( )
+                                    && self.pools[ *j ].referenced_accounts( config ) == accounts
+                            } );
+                            kept.push( old );
+                        }
+                        let added: Vec<Pool> = pools.iter( ).zip( &kept ).filter( |( _, k )| k.is_none( ) )
+                            .map( |( p, _ )| p.clone( ) ).collect( );
+                        let added_state = PoolPrice::init_all( comm, &added, &self.currencies );
+                        let ( added_prices, added_raydium ) = match added_state {
+                            Ok( res ) => { res },
+                            Err( err ) => {
+                                // nothing changed yet, the current pools stay in place
+                                error!( "Reloading pools failed, keeping the current ones: {}", err );
+                                continue;
+                            }
+                        };
+                        let mut added_prices = added_prices.into_iter( );
+                        let mut added_raydium = added_raydium.into_iter( );
+
+                        for j in 0 .. self.pools.len( ) {
+                            if !kept.contains( &Some( j ) ) {
+                                info!( "Removing pool {}.", self.pools[ j ].get_name( ) );
+                                command_sender.send( SubscriptionCommand::Unsubscribe( sids[ j ] ) ).unwrap( );
+                            }
+                        }
+                        let mut new_prices = Vec::new( );
+                        let mut new_raydium = Vec::new( );
+                        let mut new_references = Vec::new( );
+                        let mut new_warm = BitVec::from_elem( pools.len( ), false );
+                        let mut new_sids = Vec::new( );
+                        for k in 0 .. pools.len( ) {
+                            match kept[ k ] {
+                                Some( j ) => {
+                                    new_prices.push( pool_prices[ j ] );
+                                    new_raydium.push( raydium_accounts[ j ] );
+                                    new_references.push( reference_prices[ j ] );
+                                    new_warm.set( k, pool_warm[ j ] );
+                                    new_sids.push( sids[ j ] );
+                                },
+                                None => {
+                                    info!( "Adding pool {}.", pools[ k ].get_name( ) );
+                                    let price = added_prices.next( ).unwrap( );
+                                    new_prices.push( price );
+                                    new_raydium.push( added_raydium.next( ).unwrap( ) );
+                                    new_references.push( price.implied_price( ) );
+                                    new_sids.push( next_sid );
+                                    command_sender.send( SubscriptionCommand::Subscribe( next_sid, pools[ k ].clone( ) ) ).unwrap( );
+                                    next_sid = next_sid + 1;
+                                }
+                            }
+                        }
+                        pool_prices = new_prices;
+                        raydium_accounts = new_raydium;
+                        reference_prices = new_references;
+                        pool_warm = new_warm;
+                        sids = new_sids;
+                        sid_pool = sids.iter( ).enumerate( ).map( |( k, sid )| ( *sid, k ) ).collect( );
+
+                        // the ATH stays; it just moves along with its cycle
+                        let ath_path = ath.cyc.map( |c| self.named_path( &self.cycles[ c ] ) );
+                        self.pools = pools;
+                        self.cycles = cycles;
+                        if let Some( ath_path ) = ath_path {
+                            ath.cyc = ( 0 .. self.cycles.len( ) ).find( |&i| self.named_path( &self.cycles[ i ] ) == ath_path );
+                            if ath.cyc.is_none( ) {
+                                info!( "The cycle of the highest yield so far, {}, was removed.", ath.desc );
+                            }
+                        }
+                        self.metrics.cycles.store( self.cycles.len( ) as u64, Ordering::Relaxed );
+
+                        // cycle indices changed, so the per cycle state starts over
+                        cycle_blocked = self.blocked_cycles( comm, config );
+                        cycle_cooldown = vec![ config.cooldown; self.cycles.len( ) ];
+                        cycle_gain = vec![ 0; self.cycles.len( ) ];
+                        cycle_money = vec![ 0; self.cycles.len( ) ];
+                        cycle_needs_update = BitVec::from_elem( self.cycles.len( ), true );
+                        if config.dedup_mirror_cycles {
+                            mirrors = self.cycles.iter( ).map( |c| c.mirror( ) ).collect( );
+                        }
+                        cycle_mirrored = BitVec::from_elem( self.cycles.len( ), false );
+                        cycle_exhausted = vec![ 0u64; self.cycles.len( ) ];
+                        submit.reset( config, self.cycles.len( ) );
+                        cycle_yields = vec![ YieldStats::default( ); self.cycles.len( ) ];
+                        pool_update = vec![ BitVec::from_elem( self.cycles.len( ), false ); self.pools.len( ) ];
+                        for i in 0 .. self.cycles.len( ) {
+                            for ( idx, _ ) in &self.cycles[ i ].path {
+                                pool_update[ *idx ].set( i, true );
+                            }
+                        }
+                        if recorder.take( ).is_some( ) {
+                            warn!( "Stopped recording, the recording refers to the previous pools." );
+                        }
+                        info!( "Reloaded {} pools, {} cycles.", self.pools.len( ), self.cycles.len( ) );
+                    },
+                    Err( err ) => {
+                        error!( "Reloading pools failed, keeping the current ones: {}", err );
+                    }
+                }
+            }
+
+            if comm.failovers( ) != failovers {
+                failovers = comm.failovers( );
+                self.metrics.ws_reconnects.fetch_add( 1, Ordering::Relaxed );
+                command_sender.send( SubscriptionCommand::Reconnect( comm.cluster_url( ) ) ).unwrap( );
+            }
+
             // Get all updates from the channel
             loop {
                 match account_receiver.try_recv( ) {
-                    Ok( solana_client::rpc_response::Response{ value: ( pool, tkn, result ), ..} ) => {
+                    Ok( response ) => {
+                        last_update = Instant::now( );
+                        let response = match sid_pool.get( &response.value.0 ) {
+                            Some( pool ) => { RpcResponse{ context: response.context,
+                                                           value: ( *pool, response.value.1, response.value.2 ) } },
+                            None => { continue; } // pool was removed
+                        };
+                        if let Some( rec ) = recorder.as_mut( ) {
+                            rec.record( &RecordEntry::Update( response.clone( ) ) );
+                        }
+                        let ( pool, tkn, result ) = response.value;
                         cycle_needs_update.or( &pool_update[ pool ] );
                         // update / recalculate costs
-                        match self.pools[ pool ] {
-                            Pool::Swap( _ ) => {
-                                pool_prices[ pool ].token_price[ tkn ].update(
-                                    &self.pools[ pool ].get_currency( tkn ), &result );
-
-                                if pool_prices[ pool ].token_updated[ 1 - tkn ] {
-                                    pool_prices[ pool ].token_updated[ tkn ] = false;
-                                    pool_prices[ pool ].token_updated[ 1 - tkn ] = false;
-                                    pool_prices[ pool ].sanity = true;
-                                } else {
-                                    pool_prices[ pool ].token_updated[ tkn ] = true;
-                                    pool_prices[ pool ].sanity = false;
-                                }
-                            },
-                            Pool::Raydium( _ ) => {
-                                // TODO
+                        pool_prices[ pool ].update( &self.pools[ pool ], &mut raydium_accounts[ pool ],
+                                                    tkn, &result );
+                        if let Some( max_jump ) = config.max_price_jump {
+                            if pool_prices[ pool ].check_jump( &mut reference_prices[ pool ], max_jump ) {
+                                warn!( "Price of pool {} jumped, waiting for confirmation.",
+                                       self.pools[ pool ].get_name( ) );
                             }
                         }
+                        if pool_prices[ pool ].sanity { pool_warm.set( pool, true ); }
                     },
                     Err( _err ) => {
                         // nothing new anymore
@@ -337,16 +935,46 @@ impl Printer {
             }
 
 
+            if !armed && ( pool_warm.all( )
+                           || start.elapsed( ) >= Duration::from_secs( config.warmup_secs ) ) {
+                armed = true;
+                info!( "Armed after {} s of warm-up.", start.elapsed( ).as_secs( ) );
+            }
+
+            if let ( Some( gain_usd ), Some( oracle ) ) = ( config.minimum_gain_usd, config.start_currency_oracle ) {
+                if oracle_read.map_or( true, |t| t.elapsed( ) >= Duration::from_secs( ORACLE_REFRESH_INTERVAL ) ) {
+                    oracle_read = Some( Instant::now( ) );
+                    match comm.get_oracle_price( &oracle ) {
+                        Ok( price ) if price > 0.0 => {
+                            let decs = self.currencies[ config.start_currency ].decimals as usize;
+                            minimum_gains[ config.start_currency ] = ( gain_usd / price * pow10( decs ) ) as u128;
+                            debug!( "Minimum gain of {} USD is {} at {} USD.", gain_usd,
+                                    format_amount( minimum_gains[ config.start_currency ] as i128, start_decs ),
+                                    price );
+                        },
+                        Ok( price ) => {
+                            warn!( "Ignoring oracle price {}.", price );
+                        },
+                        Err( err ) => {
+                            warn!( "Reading oracle price failed: {:?}", err );
+                        }
+                    }
+                }
+            }
+
             let mut cng = false;
             for i in 0 .. self.cycles.len( ) {
+                if cycle_blocked[ i ] { continue; }
                 if !cycle_needs_update[ i ]
                     && cycle_cooldown[ i ] == 0 { continue; }
                 else if !cycle_needs_update[ i ] {
                     let opt_gamble_money = cycle_money[ i ];
                     let rs = cycle_gain[ i ];
+                    let start = self.cycles[ i ].start_currency( &self.pools );
 
-                    if opt_gamble_money >= config.minimum_money
-                        &&  rs > opt_gamble_money + config.minimum_gain as u64 {
+                    if opt_gamble_money >= config.minimum_money_in( start )
+                        && config.is_profitable_for( ( rs as u128 ).saturating_sub( fees[ start ] ),
+                                                     opt_gamble_money, minimum_gains[ start ] ) {
                             cng = true;
                     }
                 } else {
@@ -355,123 +983,600 @@ impl Printer {
             }
 
             if cng {
-                let hash = comm_send.get_blockhash( );
+                let mut hash = match comm_send.get_blockhash( ) {
+                    Ok( hash ) => { hash },
+                    Err( err ) => {
+                        error!( "Fetching blockhash failed: {:?}", err );
+                        continue;
+                    }
+                };
+                let mut hash_fetched = Instant::now( );
+                // evaluating only reads the prices, so it runs in parallel up front; balance
+                // changes from submissions of this pass are picked up by the next one
+                let printer = &*self;
+                let evaluations: Vec<Option<( bool, u64, u128 )>> = ( 0 .. self.cycles.len( ) )
+                    .into_par_iter( )
+                    .map( |i| {
+                        if cycle_blocked[ i ] || !cycle_needs_update[ i ] { return None; }
+                        Some( printer.evaluate_directions( config, &printer.cycles[ i ],
+                                                           mirrors.get( i ), &pool_prices ) )
+                    } ).collect( );
                 for i in 0 .. self.cycles.len( ) {
+                    if cycle_blocked[ i ] { continue; }
                     if !cycle_needs_update[ i ]
                         && cycle_cooldown[ i ] == 0 { continue; }
                     else if !cycle_needs_update[ i ] {
                         cycle_cooldown[ i ] = cycle_cooldown[ i ] - 1;
+                        if cycle_cooldown[ i ] == 0 {
+                            cycle_exhausted[ i ] = cycle_exhausted[ i ] + 1;
+                        }
                         let opt_gamble_money = cycle_money[ i ];
                         let rs = cycle_gain[ i ];
-
-                        if opt_gamble_money >= config.minimum_money
-                            &&  rs > opt_gamble_money + config.minimum_gain as u64 {
-                                // ensure that a cycle is executed only a limited number of times to avoid
-                                // losses due to too many failed transactions.
-                                self.execute_path( comm_send, &self.cycles[ i ],
-                                                   opt_gamble_money as u64,
-                                                   config, &pool_prices, simulate, hash );
+                        let start = self.cycles[ i ].start_currency( &self.pools );
+                        let ( minimum_gain, fee ) = ( minimum_gains[ start ], fees[ start ] );
+
+                        if armed && opt_gamble_money >= config.minimum_money_in( start )
+                            && is_profitable_net( config, i, rs as u128, opt_gamble_money, minimum_gain, fee )
+                            && config.has_positive_ev( start, ( rs as u128 ).saturating_sub( fee ), opt_gamble_money,
+                                                       submit.successes[ i ], submit.attempts[ i ] ) {
+                                let mirror = if cycle_mirrored[ i ] { Some( &mirrors[ i ] ) } else { None };
+                                self.try_submit( comm, comm_send, config, &mut submit, i, mirror,
+                                                 opt_gamble_money as u64, &pool_prices, simulate,
+                                                 &mut hash, &mut hash_fetched );
                             }
                     } else {
                         cycle_needs_update.set( i, false );
-                        let opt_gamble_money =  self.get_best_gamble_money( config, &self.cycles[ i ],
-                                                                            &pool_prices );
-                        if opt_gamble_money == cycle_money[ i ] { continue; }
+                        let ( mirrored, opt_gamble_money, rs ) = evaluations[ i ].unwrap( );
+                        if opt_gamble_money == cycle_money[ i ] && mirrored == cycle_mirrored[ i ] { continue; }
                         cycle_money[ i ] = opt_gamble_money;
-                        if opt_gamble_money < config.minimum_money { continue; }
-                        let rs = self.compute_potential( config, &self.cycles[ i ],
-                                                         &pool_prices, opt_gamble_money );
+                        cycle_mirrored.set( i, mirrored );
+                        if log_enabled!( Level::Debug ) {
+                            cycle_yields[ i ].add( rs as i128 - opt_gamble_money as i128 );
+                        }
+                        let start = self.cycles[ i ].start_currency( &self.pools );
+                        if opt_gamble_money < config.minimum_money_in( start ) { continue; }
                         if cycle_gain[ i ] == rs as u64 { continue; }
                         cycle_gain[ i ] = rs as u64;
                         cycle_cooldown[ i ] = config.cooldown;
-                        if rs > opt_gamble_money as u128  + config.minimum_gain {
-                            // ensure that a cycle is executed only a limited number of times to avoid
-                            // losses due to too many failed transactions.
-                            self.execute_path( comm_send, &self.cycles[ i ],
-                                               opt_gamble_money as u64,
-                                               config, &pool_prices, simulate, hash );
+                        let ( minimum_gain, fee ) = ( minimum_gains[ start ], fees[ start ] );
+                        if armed && is_profitable_net( config, i, rs, opt_gamble_money, minimum_gain, fee )
+                            && config.has_positive_ev( start, rs.saturating_sub( fee ), opt_gamble_money,
+                                                       submit.successes[ i ], submit.attempts[ i ] ) {
+                            let mirror = if cycle_mirrored[ i ] { Some( &mirrors[ i ] ) } else { None };
+                            self.try_submit( comm, comm_send, config, &mut submit, i, mirror,
+                                             opt_gamble_money as u64, &pool_prices, simulate,
+                                             &mut hash, &mut hash_fetched );
                         }
                     }
                 }
 
-                if  self.debug {
-                    for i in 0 .. self.cycles.len( ) {
-                        if cycle_gain[ i ] > ( cycle_money[ i ] as f64 / config.minimum_display ) as u64 {
-                            print!( "{}:", i );
-                            print_cycle( &self.cycles[ i ], &self.pools, &self.currencies );
-                            println!( " yields {} ({}) for {}.  cooldown {}.", cycle_gain[ i ], ( cycle_gain[ i ] as i128 )
-                                      - ( cycle_money[ i ] as i128 ), cycle_money[ i ], cycle_cooldown[ i ] );
-
-                            if cycle_gain[ i ] as i128 - cycle_money[ i ] as i128 > ath {
-                                ath = cycle_gain[ i ] as i128 - cycle_money[ i ] as i128;
-                                ath_cyc = i;
-                                ath_date = SystemTime::now( ).duration_since( UNIX_EPOCH ).unwrap( );
-                            }
-                        }
+                // the metric and the state file need the ATH regardless of the log level
+                self.update_ath( config, &mut ath, &cycle_gain, &cycle_money, state_path );
+
+                if log_enabled!( Level::Debug ) {
+                    let net = |i: usize| cycle_gain[ i ] as i128 - cycle_money[ i ] as i128;
+                    let mut shown: Vec<usize> = ( 0 .. self.cycles.len( ) ).filter( |&i| {
+                        cycle_gain[ i ] > ( cycle_money[ i ] as f64 / config.minimum_display ) as u64
+                    } ).collect( );
+                    // the cycles netting the most come last, at the bottom of the terminal
+                    shown.sort_by_key( |&i| net( i ) );
+                    if let Some( max ) = config.max_display {
+                        shown.drain( .. shown.len( ).saturating_sub( max ) );
                     }
-                    print!( "Highest yield observed so far: {} on cycle {} ", ath, ath_cyc );
-                    print_cycle( &self.cycles[ ath_cyc ], &self.pools, &self.currencies );
-                    println!( " at {:?}.", ath_date.as_secs( ) );
+                    for i in shown {
+                        let start_decs = self.currencies[ self.cycles[ i ].start_currency( &self.pools ) ].decimals;
+                        debug!( "{}:{} yields {} ({}) for {}.  cooldown {}.", i,
+                                format_cycle( &self.cycles[ i ], &self.pools, &self.currencies ),
+                                format_amount( cycle_gain[ i ] as i128, start_decs ),
+                                format_amount( net( i ), start_decs ),
+                                format_amount( cycle_money[ i ] as i128, start_decs ), cycle_cooldown[ i ] );
+                    }
+                    debug!( "Highest yield observed so far: {} {} on cycle {} {} at {:?}.",
+                            format_amount( ath.gain, ath.decs ), self.currencies[ config.start_currency ].name,
+                            ath.cyc.map_or( "(removed)".to_string( ), |c| c.to_string( ) ), ath.desc,
+                            ath.date.as_secs( ) );
                 }
             }
 
-            match account_receiver.recv( ) {
-                Ok( solana_client::rpc_response::Response{ value: ( pool, tkn, result ), ..} ) => {
+            iteration = iteration + 1;
+            for b in submit.backoff.iter_mut( ) {
+                if *b > 0 { *b = *b - 1; }
+            }
+            if log_enabled!( Level::Debug ) && iteration % COOLDOWN_SUMMARY_INTERVAL == 0 {
+                self.print_cooldown_summary( &cycle_exhausted, &submit.submissions );
+                self.print_yield_summary( &cycle_yields );
+            }
+
+            // SIGHUP only sets a flag, so wake up now and then to notice it without updates
+            let mut silent = false;
+            let received = loop {
+                let mut wait = Duration::from_millis( RELOAD_POLL_INTERVAL );
+                if let Some( secs ) = config.max_silence_secs {
+                    let left = Duration::from_secs( secs ).saturating_sub( last_update.elapsed( ) );
+                    silent = left == Duration::ZERO;
+                    wait = min( wait, left );
+                }
+                match account_receiver.recv_timeout( wait ) {
+                    Err( RecvTimeoutError::Timeout ) if !silent && !reload_requested.load( Ordering::Relaxed ) => { },
+                    res => { break res; }
+                }
+            };
+            match received {
+                Ok( response ) => {
+                    last_update = Instant::now( );
+                    let response = match sid_pool.get( &response.value.0 ) {
+                        Some( pool ) => { RpcResponse{ context: response.context,
+                                                       value: ( *pool, response.value.1, response.value.2 ) } },
+                        None => { continue; } // pool was removed
+                    };
+                    if let Some( rec ) = recorder.as_mut( ) {
+                        rec.record( &RecordEntry::Update( response.clone( ) ) );
+                    }
+                    let ( pool, tkn, result ) = response.value;
                     // update / recalculate costs
                     cycle_needs_update.or( &pool_update[ pool ] );
-                    match self.pools[ pool ] {
-                        Pool::Swap( _ ) => {
-                            pool_prices[ pool ].token_price[ tkn ].update(
-                                &self.pools[ pool ].get_currency( tkn ), &result );
-
-                            if pool_prices[ pool ].token_updated[ 1 - tkn ] {
-                                pool_prices[ pool ].token_updated[ tkn ] = false;
-                                pool_prices[ pool ].token_updated[ 1 - tkn ] = false;
-                                pool_prices[ pool ].sanity = true;
-                            } else {
-                                pool_prices[ pool ].token_updated[ tkn ] = true;
-                                pool_prices[ pool ].sanity = false;
-                            }
-                        },
-                        Pool::Raydium( _ ) => {
-                            // TODO
+                    pool_prices[ pool ].update( &self.pools[ pool ], &mut raydium_accounts[ pool ],
+                                                tkn, &result );
+                    if let Some( max_jump ) = config.max_price_jump {
+                        if pool_prices[ pool ].check_jump( &mut reference_prices[ pool ], max_jump ) {
+                            warn!( "Price of pool {} jumped, waiting for confirmation.",
+                                   self.pools[ pool ].get_name( ) );
                         }
                     }
+                    if pool_prices[ pool ].sanity { pool_warm.set( pool, true ); }
+                },
+                Err( RecvTimeoutError::Timeout ) if !silent => {
+                    // the reload is done at the top of the loop
+                },
+                Err( RecvTimeoutError::Timeout ) => {
+                    // the connection is alive but silent; subscribe again instead of waiting forever
+                    warn!( "No updates for {} s, reconnecting.", config.max_silence_secs.unwrap_or( 0 ) );
+                    self.metrics.ws_reconnects.fetch_add( 1, Ordering::Relaxed );
+                    command_sender.send( SubscriptionCommand::Reconnect( comm.cluster_url( ) ) ).unwrap( );
+                    last_update = Instant::now( );
                 },
                 Err( err ) => {
-                    println!( "Error: {:?}; reinit", err.to_string( ) );
+                    error!( "Error: {:?}; reinit", err );
+                    ath.save( self.cycles.len( ), state_path );
+                    if log_enabled!( Level::Debug ) {
+                        self.print_yield_summary( &cycle_yields );
+                    }
                     std::process::exit( 1 )
                 }
             }
         }
     }
 
+    /* Sends cycle i (or its mirror) unless it backs off, has too many transactions in flight or
+       hits the rate limit, and books the outcome */
+    fn try_submit( &mut self, comm: &Communication, comm_send: &Communication, config: &Config,
+                   submit: &mut SubmitState, i: usize, mirror: Option<&Cycle>, gamble_money: u64,
+                   pool_prices: &Vec<PoolPrice>, simulate: bool, hash: &mut Hash, hash_fetched: &mut Instant ) {
+        if submit.backoff[ i ] > 0 {
+            debug!( "Cycle {} backs off for {} more iterations.", i, submit.backoff[ i ] );
+            return;
+        }
+        if !submit.in_flight.try_reserve( comm_send, i ) {
+            debug!( "Cycle {} has too many transactions in flight.", i );
+            return;
+        }
+        if !submit.rate_limiter.try_acquire( ) {
+            warn!( "Transaction rate limit reached, dropping cycle {}.", i );
+            return;
+        }
+        if !refresh_blockhash( comm_send, config, hash, hash_fetched ) {
+            return;
+        }
+        // ensure that a cycle is executed only a limited number of times to avoid
+        // losses due to too many failed transactions.
+        submit.submissions[ i ] = submit.submissions[ i ] + 1;
+        let start = self.cycles[ i ].start_currency( &self.pools );
+        // sending failed, unless only simulating
+        let mut failed = !simulate;
+        if let Some( sig ) = self.execute_path( comm_send, mirror.unwrap_or( &self.cycles[ i ] ), gamble_money,
+                                                config, pool_prices, simulate, *hash, None ) {
+            failed = false;
+            submit.in_flight.add( i, sig );
+            if let Some( landed ) = self.confirm_execution( comm, comm_send, config, &sig, start ) {
+                submit.attempts[ i ] = submit.attempts[ i ] + 1;
+                if landed { submit.successes[ i ] = submit.successes[ i ] + 1; } else { failed = true; }
+            }
+        }
+        if failed {
+            submit.failures[ i ] = submit.failures[ i ] + 1;
+            submit.backoff[ i ] = config.failure_backoff( submit.failures[ i ] );
+        } else {
+            submit.failures[ i ] = 0;
+        }
+    }
+
+    /* Raises the ATH to the best cycle shown this pass and saves it right away */
+    fn update_ath( &self, config: &Config, ath: &mut Ath, cycle_gain: &Vec<u64>, cycle_money: &Vec<u64>,
+                   state_path: Option<&str> ) {
+        for i in 0 .. self.cycles.len( ) {
+            if cycle_gain[ i ] <= ( cycle_money[ i ] as f64 / config.minimum_display ) as u64 { continue; }
+            if self.cycles[ i ].start_currency( &self.pools ) != config.start_currency { continue; }
+            let net = cycle_gain[ i ] as i128 - cycle_money[ i ] as i128;
+            if net > ath.gain {
+                let ( desc, decs ) = self.describe_cycle( i );
+                *ath = Ath {
+                    gain: net,
+                    cyc:  Some( i ),
+                    desc: desc,
+                    decs: decs,
+                    date: SystemTime::now( ).duration_since( UNIX_EPOCH ).unwrap( ),
+                };
+                self.metrics.ath_gain.store( net as i64, Ordering::Relaxed );
+                ath.save( self.cycles.len( ), state_path );
+            }
+        }
+    }
+
+    /* The cycle's path and the decimals of its start currency */
+    fn describe_cycle( &self, i: usize ) -> ( String, u8 ) {
+        let cycle = &self.cycles[ i ];
+        ( format_cycle( cycle, &self.pools, &self.currencies ),
+          self.currencies[ cycle.start_currency( &self.pools ) ].decimals )
+    }
+
+    /* The cycle's path by pool name, which identifies it across reloads */
+    fn named_path( &self, cycle: &Cycle ) -> Vec<( String, Direction )> {
+        cycle.path.iter( ).map( |( p, d )| ( self.pools[ *p ].get_name( ).clone( ), *d ) ).collect( )
+    }
+
+    /* Cycles passing through a currency without token account would only fail on-chain;
+     * a wrapped start currency's account is created on demand by the cycles starting in it. */
+    fn blocked_cycles( &self, comm: &Communication, config: &Config ) -> BitVec {
+        let mut res = BitVec::from_elem( self.cycles.len( ), false );
+        match comm.missing_currency_accounts( &self.currencies ) {
+            Ok( missing ) => {
+                for c in missing {
+                    let wrapped = config.wrap_sol && self.currencies[ c ].is_native( );
+                    let mut skipped = false;
+                    for i in 0 .. self.cycles.len( ) {
+                        if wrapped && self.cycles[ i ].start_currency( &self.pools ) == c { continue; }
+                        if self.cycles[ i ].path.iter( ).any( |( p, _ )| {
+                            self.pools[ *p ].get_currency( Direction::AtoB ).currency_idx == c
+                                || self.pools[ *p ].get_currency( Direction::BtoA ).currency_idx == c
+                        } ) {
+                            res.set( i, true );
+                            skipped = true;
+                        }
+                    }
+                    if skipped {
+                        warn!( "Token account {} of {} does not exist, skipping cycles using it.",
+                               self.currencies[ c ].account, self.currencies[ c ].name );
+                    }
+                }
+            },
+            Err( err ) => {
+                warn!( "Checking token accounts failed: {:?}", err );
+            }
+        }
+        res
+    }
+
+    /* Re-reads the pool config with the same validation and filter as at start up */
+    fn load_pools( &self, comm: &Communication, config: &Config, pool_path: &str,
+                   filter: &PoolFilter ) -> Result<( Vec<Pool>, Vec<Cycle> ), Box<dyn Error>> {
+        let mut pools = PoolConfig::read_from_file( pool_path, config )?;
+        PoolConfig::validate( &pools, &self.currencies )?;
+        PoolConfig::filter( &mut pools, filter );
+        PoolConfig::read_chain_state( comm, &mut pools )?;
+
+        // pools only appended to the current ones just add the cycles trading them
+        let appended = pools.len( ) > self.pools.len( )
+            && self.pools.iter( ).zip( pools.iter( ) ).all( |( old, new )| {
+                old.get_name( ) == new.get_name( )
+                    && old.referenced_accounts( config ) == new.referenced_accounts( config )
+            } );
+        if appended && !config.cycles_need_prices( ) && config.max_cycles.is_none( ) {
+            let mut cycles = self.cycles.clone( );
+            for k in self.pools.len( ) .. pools.len( ) {
+                let added = extend_cycles( &cycles, k, config, &pools );
+                cycles.extend( added );
+            }
+            return Ok( ( pools, cycles ) );
+        }
+
+        let pool_prices = if config.cycles_need_prices( ) {
+            Some( PoolPrice::init_all( comm, &pools, &self.currencies )?.0 )
+        } else {
+            None
+        };
+        let cycles = construct_cycles( config, &pools, pool_prices.as_ref( ) )?;
+        Ok( ( pools, cycles ) )
+    }
+
+    fn print_cooldown_summary( &self, cycle_exhausted: &Vec<u64>, cycle_submissions: &Vec<u64> ) {
+        let mut order: Vec<usize> = ( 0 .. self.cycles.len( ) ).collect( );
+        order.sort_by( |a, b| {
+            ( cycle_exhausted[ *b ], cycle_submissions[ *b ] )
+                .cmp( &( cycle_exhausted[ *a ], cycle_submissions[ *a ] ) )
+        } );
+
+        debug!( "Cycles exhausting their cooldown most often:" );
+        for i in order.into_iter( ).take( COOLDOWN_SUMMARY_LENGTH ) {
+            if cycle_exhausted[ i ] == 0 && cycle_submissions[ i ] == 0 { break; }
+            debug!( "{}:{} cooled down {} times, submitted {} times.", i,
+                    format_cycle( &self.cycles[ i ], &self.pools, &self.currencies ),
+                    cycle_exhausted[ i ], cycle_submissions[ i ] );
+        }
+    }
+
+    fn print_yield_summary( &self, cycle_yields: &Vec<YieldStats> ) {
+        // yields are toys of each cycle's start currency, so only those of one are ranked together
+        let start = |i: usize| self.cycles[ i ].start_currency( &self.pools );
+        let mut order: Vec<usize> = ( 0 .. self.cycles.len( ) ).filter( |i| cycle_yields[ *i ].count > 0 ).collect( );
+        order.sort_by( |a, b| start( *a ).cmp( &start( *b ) )
+                       .then( cycle_yields[ *b ].mean( ).partial_cmp( &cycle_yields[ *a ].mean( ) ).unwrap( ) ) );
+
+        debug!( "Observed yields per cycle, histogram buckets split at {:?} toys:", YIELD_BUCKETS );
+        for i in order {
+            let stats = &cycle_yields[ i ];
+            let currency = &self.currencies[ start( i ) ];
+            debug!( "{}:{} {} samples, min {}, max {}, mean {} {}, histogram {:?}.", i,
+                    format_cycle( &self.cycles[ i ], &self.pools, &self.currencies ), stats.count,
+                    format_amount( stats.min, currency.decimals ), format_amount( stats.max, currency.decimals ),
+                    format_amount( stats.mean( ).round( ) as i128, currency.decimals ), currency.name,
+                    stats.histogram );
+        }
+    }
+
+    /* Waits for a sent cycle to land and re-reads the balance of its start currency once it
+     * did. Returns whether the transaction succeeded, None if that is unknown. */
+    fn confirm_execution( &mut self, comm: &Communication, comm_send: &Communication,
+                          config: &Config, signature: &Signature, start: usize ) -> Option<bool> {
+        let timeout = match config.confirm_timeout {
+            Some( timeout ) => { Duration::from_millis( timeout ) },
+            None => { return None; }
+        };
+
+        match comm_send.confirm_signature( signature, timeout ) {
+            Ok( Some( status ) ) => {
+                if let Some( err ) = status.err {
+                    warn!( "Transaction {} failed: {:?}", signature, err );
+                    return Some( false );
+                }
+                info!( "Transaction {} confirmed in slot {}.", signature, status.slot );
+            },
+            Ok( None ) => {
+                warn!( "Transaction {} not confirmed in time.", signature );
+                return Some( false );
+            },
+            Err( err ) => {
+                error!( "Confirming transaction {} failed: {:?}", signature, err );
+                return None;
+            }
+        }
+
+        match comm.get_current_balance( config, &self.currencies, start ) {
+            Ok( money ) => {
+                self.money[ start ] = money;
+                if start == config.start_currency {
+                    self.metrics.balance.store( money, Ordering::Relaxed );
+                }
+                self.check_reserve( config, start );
+            },
+            Err( err ) => {
+                error!( "Fetching balance failed: {:?}", err );
+            }
+        }
+        Some( true )
+    }
+
     fn execute_path( &self, comm: &Communication, cycle: &Cycle, gamble_money: u64, config: &Config,
                      pool_prices: &Vec<PoolPrice>, simulate: bool,
-                     hash: Hash ) -> Option<Signature> {
-        if self.debug {
-            print!( "Executing " );
-            print_cycle( cycle, &self.pools, &self.currencies );
-            println!( " on {} SOL toy money.", gamble_money as f64 / POWERS_OF_TEN[ 9 ] );
+                     hash: Hash, log_path: Option<&Path> ) -> Option<Signature> {
+        let start = cycle.start_currency( &self.pools );
+        let start_currency = &self.currencies[ start ];
+        info!( "Executing {} on {} {}.", format_cycle( cycle, &self.pools, &self.currencies ),
+               format_amount( gamble_money as i128, start_currency.decimals ), start_currency.name );
+
+        // extra signer required for some marketplaces. Only used if required.
+        let extra_signer = extra_signer( self.extra_signer_seed );
+
+        let instructions = self.build_path( comm, cycle, gamble_money, Some( gamble_money as u128 ),
+                                            config, pool_prices, &extra_signer )?;
+        if self.dump_instructions {
+            dump_instructions( &instructions );
+            return None;
+        }
+
+        // don't waste a request on a transaction the network will reject anyway
+        if let Err( reason ) = comm.check_transaction_limits( &instructions ) {
+            warn!( "Not sending {}: {}.", format_cycle( cycle, &self.pools, &self.currencies ), reason );
+            return None;
+        }
+        /*
+           if toys_in < gamble_money as u128 {
+           println!( "This cycle kinda sucks, you know…" );
+           false
+           } else {*/
+        // actually run the transaction
+        let signers = if cycle.needs_approval {
+            vec![ &comm.wallet, &extra_signer ]
+        } else {
+            vec![ &comm.wallet ]
+        };
+        if simulate && self.simulate_confirm {
+            self.report_simulation( comm, cycle, config, &instructions, &signers, hash );
+            return None;
+        }
+        if config.verify_before_send && !simulate {
+            let change = self.simulate_changes( comm, cycle, config, &instructions, &signers, hash )
+                .and_then( |changes| changes.into_iter( ).find( |( c, _ )| *c == start ) )
+                .and_then( |( _, change )| change );
+            match change {
+                Some( change ) if change > 0 => {
+                    debug!( "Simulation nets {} toys, sending.", change );
+                },
+                _ => {
+                    info!( "Not sending {}: simulation nets {:?} toys.",
+                           format_cycle( cycle, &self.pools, &self.currencies ), change );
+                    return None;
+                }
+            }
+        }
+        let res = match ( config.send_mode, config.jito_tip_account ) {
+            ( SendMode::Jito, Some( tip_account ) ) if !simulate => {
+                comm.send_bundle( &instructions, &signers, hash, &tip_account, config.jito_tip )
+            },
+            _ => { comm.send_transaction( &instructions, &signers, simulate, hash, log_path ) }
+        };
+        match res {
+            Ok( signature ) => {
+                self.metrics.tx_sent( true );
+                debug!( "===== transaction completed =====" );
+                if !simulate && comm.webhook.is_some( ) {
+                    let description = format_cycle( cycle, &self.pools, &self.currencies );
+                    let predicted = self.compute_potential( config, cycle, pool_prices, gamble_money );
+                    let summary = format!( "Sent {} on {} {}, predicted yield {}: {}",
+                                           description, format_amount( gamble_money as i128, start_currency.decimals ),
+                                           start_currency.name,
+                                           format_amount( predicted as i128, start_currency.decimals ), signature );
+                    comm.notify( &json!( {
+                        "text":            summary,
+                        "content":         summary,
+                        "cycle":           description,
+                        "gamble":          gamble_money,
+                        "predicted_yield": predicted.to_string( ),
+                        "signature":       signature.to_string( ),
+                    } ) );
+                }
+                Some( signature )
+            },
+            Err( err ) => {
+                // a clean simulation ends up here too, it sent nothing
+                if !simulate {
+                    self.metrics.tx_sent( false );
+                }
+                warn!( "Sending transaction failed: {:?}", err );
+                None
+            }
+        }
+        //        }
+    }
+
+    /* Simulates the cycle's transaction and prints the resulting change of every currency
+     * it passes through. */
+    fn report_simulation( &self, comm: &Communication, cycle: &Cycle, config: &Config,
+                          instructions: &Vec<Instruction>, signers: &Vec<&Keypair>, hash: Hash ) {
+        let changes = match self.simulate_changes( comm, cycle, config, instructions, signers, hash ) {
+            Some( changes ) => { changes },
+            None => { return; }
+        };
+
+        info!( "Simulated balance changes:" );
+        for ( c, change ) in changes {
+            let currency = &self.currencies[ c ];
+            match change {
+                Some( change ) => {
+                    info!( "{}: {:+} toys ({:+})", currency.name, change,
+                           change as f64 / pow10( currency.decimals as usize ) );
+                },
+                None => {
+                    warn!( "{}: balance unavailable", currency.name );
+                }
+            }
+        }
+    }
+
+    /* Simulates the cycle's transaction; returns the change of every currency it passes
+     * through, or None if simulating failed. */
+    fn simulate_changes( &self, comm: &Communication, cycle: &Cycle, config: &Config,
+                         instructions: &Vec<Instruction>, signers: &Vec<&Keypair>,
+                         hash: Hash ) -> Option<Vec<( usize, Option<i128> )>> {
+        let mut involved: Vec<usize> = Vec::new( );
+        for ( p, w ) in &cycle.path {
+            for i in [ *w, w.flip( ) ] {
+                let idx = self.pools[ *p ].get_currency( i ).currency_idx;
+                if !involved.contains( &idx ) {
+                    involved.push( idx );
+                }
+            }
+        }
+
+        // a wrapped start currency only exists during the transaction; its lamports are
+        // what actually changes
+        let start = cycle.start_currency( &self.pools );
+        let wrap = config.wrap_sol && self.currencies[ start ].is_native( );
+        let is_lamports = |c: usize| wrap && c == start;
+        let accounts: Vec<Pubkey> = involved.iter( ).map( |c| {
+            if is_lamports( *c ) { comm.wallet.pubkey( ) } else { self.currencies[ *c ].account }
+        } ).collect( );
+        let balance = |c: usize, acc: &SdkAccount| {
+            if is_lamports( c ) { Some( acc.lamports ) } else { unpack_token_amount( acc ) }
+        };
+
+        let pre = match comm.with_retries( || comm.rpc_client( ).get_multiple_accounts( &accounts ) ) {
+            Ok( pre ) => { pre },
+            Err( err ) => {
+                warn!( "Fetching balances failed: {:?}", err );
+                return None;
+            }
+        };
+        let res = match comm.simulate_transaction_with_accounts( instructions, signers, hash, &accounts ) {
+            Ok( res ) => { res },
+            Err( err ) => {
+                warn!( "Simulating transaction failed: {:?}", err );
+                return None;
+            }
+        };
+
+        if let Some( logs ) = &res.logs {
+            for l in logs {
+                debug!( "{}", l );
+            }
         }
+        if let Some( err ) = res.err {
+            warn!( "Simulated transaction failed: {:?}", err );
+            return None;
+        }
+
+        let post = res.accounts.unwrap_or_default( );
+        Some( involved.iter( ).enumerate( ).map( |( i, c )| {
+            let before = pre.get( i ).and_then( |a| a.as_ref( ) ).and_then( |a| balance( *c, a ) );
+            let after = post.get( i ).and_then( |a| a.as_ref( ) )
+                .and_then( |a| a.decode::<SdkAccount>( ) ).and_then( |a| balance( *c, &a ) );
+            match ( before, after ) {
+                ( Some( before ), Some( after ) ) => { ( *c, Some( after as i128 - before as i128 ) ) },
+                _ => { ( *c, None ) }
+            }
+        } ).collect( ) )
+    }
 
+    fn build_path( &self, comm: &Communication, cycle: &Cycle, gamble_money: u64,
+                   minimum_out: Option<u128>, config: &Config, pool_prices: &Vec<PoolPrice>,
+                   extra_signer: &Keypair ) -> Option<Vec<Instruction>> {
         /*
          * Execute path by creating a single transaction with all steps.
          * Estimate money obtained in each step to use as amount in for next transaction
+         *
+         * Slippage is per hop: every swap has to yield at least (1 - slippage) of its
+         * predicted output, which is also what it passes on to the next swap. The final swap
+         * additionally has to return minimum_out; without minimum_out no swap insists on
+         * any output.
          */
 
         let mut toys_in = gamble_money as u128;
         let mut instructions: Vec<Instruction> = Vec::new( );
         let mut decs = 0;
 
-        // compute budget
-        if config.extra_budget > 0 {
-            // cook up extra budget instruction
-            instructions.push( ComputeBudgetInstruction::set_compute_unit_price( config.extra_budget ) );
+        // compute budget; needs to precede the swap instructions
+        if let Some( limit ) = config.compute_unit_limit {
+            instructions.push( ComputeBudgetInstruction::set_compute_unit_limit( limit ) );
+        }
+        // the price depends on the swap accounts in dynamic mode, so it is inserted later
+        let budget_idx = instructions.len( );
+
+        // trade from native lamports; the swaps only ever see the wrapped account
+        let start_currency = &self.currencies[ cycle.start_currency( &self.pools ) ];
+        let wrap = config.wrap_sol && start_currency.is_native( );
+        if wrap && !wrap_sol( &mut instructions, &comm.wallet.pubkey( ), start_currency,
+                              gamble_money, config ) {
+            return None;
         }
-
-        // extra signer required for some marketplaces. Only used if required.
-        let extra_signer = Keypair::new( );
 
         let path = &cycle.path;
         for i in 0 .. path.len( ) {
@@ -481,7 +1586,7 @@ impl Printer {
             let pool = &self.pools[ curr_pool ];
 
             let curr_a = &self.currencies[ pool.get_currency( dir ).currency_idx ];
-            let curr_b = &self.currencies[ pool.get_currency( 1 - dir ).currency_idx ];
+            let curr_b = &self.currencies[ pool.get_currency( dir.flip( ) ).currency_idx ];
 
             let ndecs = curr_b.decimals as usize;
             if decs == 0 {
@@ -494,80 +1599,166 @@ impl Printer {
 //            println!( "After sl {}", toys_out );
 
 
-            if traded > toys_in {
-                println!( "Ran out of toys. The evil squids must have eaten them." );
-                break;
+            if traded > toys_in || toys_out == 0 {
+                // a truncated cycle must never be sent, nor one whose later legs swap nothing
+                warn!( "Ran out of toys on leg {} ({}): trades {} of {} {} for {}. The evil squids must have eaten them.",
+                       i, pool.get_name( ), traded, toys_in, curr_a.name, toys_out );
+                return None;
             }
 
             /*
                println!( "Swapping {} ({}) {} for {} ({}) {}.",
                toys_in_ui, toys_in, config.currencies[ path.nodes[ i - 1 ] ].name,
-               toys_out as f64 / POWERS_OF_TEN[ out_decs as usize ],
+               toys_out as f64 / pow10( out_decs as usize ),
                toys_out, config.currencies[ path.nodes[ i ] ].name );
                */
             let mut nout = toys_out as u128;
 
             if decs != 0 && decs != ndecs {
-                nout = ( ( nout as f64 ) / POWERS_OF_TEN[ decs ] * POWERS_OF_TEN[ ndecs ] )
-                    as u128;
+                nout = rescale_toys( nout, decs, ndecs );
             }
             decs = ndecs;
 
-            let out = if i + 1 < path.len( ) {
-                0
-            } else  {
-                gamble_money as u128
+            let out = match minimum_out {
+                None => { 0 },
+                Some( _ ) if i + 1 < path.len( ) => { nout },
+                Some( mo ) => { max( nout, mo ) },
             };
 
-            if self.debug {
-                println!( "step {:?}: in {:?} (traded {:?}) out {:?}", i, toys_in, traded, nout );
-            }
+            debug!( "step {:?}: in {:?} (traded {:?}) out {:?}", i, toys_in, traded, nout );
 
             if !self.pools[ curr_pool ].swap( &mut instructions,
                                               &comm.wallet.pubkey( ),
                                               &extra_signer.pubkey( ),
                                               toys_in as u128,
-                                              out, dir, config, &self.currencies ) {
+                                              out, dir, &pool_prices[ curr_pool ], config, &self.currencies ) {
                 return None;
             }
 
             toys_in = nout;
         }
-        /*
-           if toys_in < gamble_money as u128 {
-           println!( "This cycle kinda sucks, you know…" );
-           false
-           } else {*/
-        // actually run the transaction
-        let signers = if cycle.needs_approval {
-            vec![ &comm.wallet, &extra_signer ]
-        } else {
-            vec![ &comm.wallet ]
-        };
-        match comm.send_transaction( &instructions, &signers, simulate, hash ) {
-            Ok( signature ) => {
-                if self.debug {
-                    println!( "===== transaction completed =====" );
+
+        if wrap {
+            unwrap_sol( &mut instructions, &comm.wallet.pubkey( ), start_currency, config );
+        }
+
+        let extra_budget = match config.extra_budget_mode {
+            ExtraBudgetMode::Fixed => { config.extra_budget },
+            ExtraBudgetMode::Dynamic( percentile ) => {
+                let mut accounts: Vec<Pubkey> = Vec::new( );
+                for ix in &instructions {
+                    for meta in &ix.accounts {
+                        if meta.is_writable && !accounts.contains( &meta.pubkey ) {
+                            accounts.push( meta.pubkey );
+                        }
+                    }
                 }
-                Some( signature )
-            },
-            Err( err ) => {
-                if self.debug {
-                    println!( "Error: {:?}", err );
+                match comm.estimate_priority_fee( &accounts, percentile ) {
+                    Ok( fee ) if config.extra_budget > 0 => { min( fee, config.extra_budget ) },
+                    Ok( fee ) => { fee },
+                    Err( err ) => {
+                        warn!( "Estimating priority fee failed: {:?}", err );
+                        config.extra_budget
+                    }
                 }
-                None
             }
+        };
+        if extra_budget > 0 {
+            // cook up extra budget instruction
+            instructions.insert( budget_idx,
+                                 ComputeBudgetInstruction::set_compute_unit_price( extra_budget ) );
         }
-        //        }
+        Some( instructions )
     }
 
-    fn get_gamble_money( &self, config: &Config ) -> u64 {
-        return ( self.money as f64 * config.safety_percentage ) as u64;
+    fn get_gamble_money( &self, config: &Config, start: usize ) -> u64 {
+        let mut money = self.money[ start ];
+        // the fees are paid in lamports, so those stay in the wallet
+        if self.currencies[ start ].is_native( ) {
+            money = money.saturating_sub( config.reserve_sol );
+        }
+        return ( money as f64 * config.safety_percentage ) as u64;
+    }
+
+    /* Warns if a sol start currency's balance doesn't even cover reserve_sol */
+    fn check_reserve( &self, config: &Config, start: usize ) {
+        if self.currencies[ start ].is_native( ) && self.money[ start ] < config.reserve_sol {
+            warn!( "Balance of {} {} is below reserve_sol of {}, nothing is gambled and fees may soon fail!",
+                   format_amount( self.money[ start ] as i128, self.currencies[ start ].decimals ),
+                   self.currencies[ start ].name,
+                   format_amount( config.reserve_sol as i128, self.currencies[ start ].decimals ) );
+        }
+    }
+
+    /* Optimal gamble money and resulting yield of the cycle, or of its mirror (if given) when
+     * that nets more. The first value tells whether the mirror won. */
+    fn evaluate_directions( &self, config: &Config, cycle: &Cycle, mirror: Option<&Cycle>,
+                            pool_prices: &Vec<PoolPrice> ) -> ( bool, u64, u128 ) {
+        let money = self.get_best_gamble_money( config, cycle, pool_prices );
+        let rs = self.compute_potential( config, cycle, pool_prices, money );
+
+        if let Some( mirror ) = mirror {
+            let m_money = self.get_best_gamble_money( config, mirror, pool_prices );
+            let m_rs = self.compute_potential( config, mirror, pool_prices, m_money );
+            if m_rs as i128 - m_money as i128 > rs as i128 - money as i128 {
+                return ( true, m_money, m_rs );
+            }
+        }
+        ( false, money, rs )
     }
 
     fn get_best_gamble_money( &self, config: &Config, cycle: &Cycle,
                               pool_prices: &Vec<PoolPrice> ) -> u64 {
-        let max_gamble_money = self.get_gamble_money( config );
+        let gamble_money = self.get_gamble_money( config, cycle.start_currency( &self.pools ) );
+        let max_gamble_money = match cycle.max_gamble {
+            Some( cap ) => { min( cap, gamble_money ) },
+            None => { gamble_money }
+        };
+        if let Some( amount ) = self.fixed_gamble {
+            return min( amount, max_gamble_money );
+        }
+        match config.gamble_optimizer {
+            GambleOptimizer::ClosedForm => {
+                self.get_best_gamble_money_closed_form( config, cycle, pool_prices, max_gamble_money )
+            },
+            GambleOptimizer::Sampled( samples ) => {
+                self.get_best_gamble_money_sampled( config, cycle, pool_prices, max_gamble_money, samples )
+            }
+        }
+    }
+
+    /* Predicts the cycle for gamble sizes spaced geometrically between minimum_money and
+     * max_gamble_money and picks the one netting the most. */
+    fn get_best_gamble_money_sampled( &self, config: &Config, cycle: &Cycle, pool_prices: &Vec<PoolPrice>,
+                                      max_gamble_money: u64, samples: u32 ) -> u64 {
+        let minimum_money = config.minimum_money_in( cycle.start_currency( &self.pools ) );
+        let lo = max( minimum_money, 1 );
+        if max_gamble_money <= lo || samples < 2 {
+            return max_gamble_money;
+        }
+        let ratio = max_gamble_money as f64 / lo as f64;
+
+        let mut best = max_gamble_money;
+        let mut best_gain = i128::MIN;
+        for k in 0 .. samples {
+            let money = if k == samples - 1 {
+                max_gamble_money
+            } else {
+                ( lo as f64 * ratio.powf( k as f64 / ( samples - 1 ) as f64 ) ) as u64
+            };
+            let gain = self.compute_potential( config, cycle, pool_prices, money ) as i128 - money as i128;
+            if gain > best_gain {
+                best_gain = gain;
+                best = money;
+            }
+        }
+
+        let gamble_money = ( best as f64 * config.greed ) as u64;
+        if gamble_money < minimum_money { best } else { gamble_money }
+    }
+
+    fn get_best_gamble_money_closed_form( &self, config: &Config, cycle: &Cycle,
+                                          pool_prices: &Vec<PoolPrice>, max_gamble_money: u64 ) -> u64 {
         let path = &cycle.path;
 
         // assumes constant product
@@ -583,11 +1774,9 @@ impl Printer {
             let pp = &pool_prices[ pool ];
             let pi = &self.pools[ pool ];
             let a = pp.token_amount( dir ); // pool in
-            let b = pp.token_amount( 1 - dir ); // pool out
-            let mut f = pi.fees( ) * ( 1.0 - config.slippage ); // pool fees
-            for _j in 0 .. i {
-                f = f * ( 1.0 - config.slippage );
-            }
+            let b = pp.token_amount( dir.flip( ) ); // pool out
+            // pool fees, plus the per-hop slippage also assumed by compute_potential
+            let f = pi.fees( ) * ( 1.0 - config.slippage );
 
             gamma = gamma * a + alpha * f;
             alpha = alpha * b * f;
@@ -612,7 +1801,7 @@ impl Printer {
         // print!( "Not using optimal value {} for cycle ", gamble_money );
         // print_cycle( path, pools, currencies );
 
-        if gamble_money < config.minimum_money as i64
+        if gamble_money < config.minimum_money_in( cycle.start_currency( &self.pools ) ) as i64
             || gamble_money as u64 > max_gamble_money {
                 max_gamble_money
             } else {
@@ -622,6 +1811,14 @@ impl Printer {
 
     fn compute_potential( &self, config: &Config,
                           cycle: &Cycle, pool_prices: &Vec<PoolPrice>, gamble_money: u64 ) -> u128 {
+        self.compute_legs( config, cycle, pool_prices, gamble_money, None )
+    }
+
+    /* Like compute_potential, additionally collecting the price impact of each leg: the
+     * fraction by which its rate falls short of the rate for a small amount. Yields 0 if
+     * any leg exceeds max_leg_impact. */
+    fn compute_legs( &self, config: &Config, cycle: &Cycle, pool_prices: &Vec<PoolPrice>,
+                     gamble_money: u64, mut impacts: Option<&mut Vec<f64>> ) -> u128 {
         // directly comput how much toys this path will yield.
 
 //        print!( "Computing potential for {} toys along ", gamble_money );
@@ -631,6 +1828,7 @@ impl Printer {
 
         let mut toys_in = gamble_money as u128;
         let mut decs = 0;
+        let mut too_deep = false;
         for i in 0 .. path.len( ) {
             let ( curr_pool, dir ) = path[ i ];
             let pool_price = pool_prices[ curr_pool ];
@@ -643,7 +1841,7 @@ impl Printer {
             let pool = &self.pools[ curr_pool ];
 
             let curr_a = &self.currencies[ pool.get_currency( dir ).currency_idx ];
-            let curr_b = &self.currencies[ pool.get_currency( 1 - dir ).currency_idx ];
+            let curr_b = &self.currencies[ pool.get_currency( dir.flip( ) ).currency_idx ];
 
             let ndecs = curr_b.decimals as usize;
             if decs == 0 {
@@ -651,12 +1849,31 @@ impl Printer {
             }
 
             let ( toys_out, _ ) = pool_price.swap( toys_in, dir, &self.pools[ curr_pool ] );
+
+            if impacts.is_some( ) || config.max_leg_impact.is_some( ) {
+                // large enough for rounding not to matter, small enough not to move the price
+                let probe = max( toys_in / 1000, min( toys_in, 100_000 ) );
+                let ( probe_out, _ ) = pool_price.swap( probe, dir, &self.pools[ curr_pool ] );
+                let impact = if probe_out == 0 || toys_in == 0 {
+                    1.0
+                } else {
+                    let marginal = probe_out as f64 / probe as f64;
+                    let effective = toys_out as f64 / toys_in as f64;
+                    ( 1.0 - effective / marginal ).max( 0.0 )
+                };
+                if let Some( max_impact ) = config.max_leg_impact {
+                    too_deep = too_deep || impact > max_impact;
+                }
+                if let Some( impacts ) = impacts.as_mut( ) {
+                    impacts.push( impact );
+                }
+            }
+
             let toys_out = ( toys_out as f64 * ( 1.0 - config.slippage ) ) as u128;
 
             toys_in = toys_out as u128;
             if decs != 0 && decs != ndecs {
-                toys_in = ( ( toys_in as f64 ) / POWERS_OF_TEN[ decs ] * POWERS_OF_TEN[ ndecs ] )
-                    as u128;
+                toys_in = rescale_toys( toys_in, decs, ndecs );
             }
             decs = ndecs;
 
@@ -665,6 +1882,9 @@ impl Printer {
             // adjust for different decimals
         }
 //        println!( "" );
+        if too_deep {
+            return 0;
+        }
         toys_in
     }
 
@@ -692,3 +1912,242 @@ impl Printer {
     */
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ config::tests::*, price::tests::* };
+
+    /* A triangle over three currencies whose last pool pays 5% too much for C2 */
+    fn mispriced_triangle( config: &Config ) -> ( Printer, Vec<PoolPrice>, Cycle ) {
+        let pools = vec![ test_pool( config, 0, 0, 1 ), test_pool( config, 1, 1, 2 ),
+                          test_pool( config, 2, 2, 0 ) ];
+        let prices = test_prices( &pools, &[ [ 1_000_000_000_000, 1_000_000_000_000 ],
+                                             [ 1_000_000_000_000, 1_000_000_000_000 ],
+                                             [ 1_000_000_000_000, 1_050_000_000_000 ] ] );
+        let cycle = Cycle {
+            needs_approval: false,
+            path:           vec![ ( 0, Direction::AtoB ), ( 1, Direction::AtoB ), ( 2, Direction::AtoB ) ],
+            max_gamble:     None,
+        };
+        let printer = Printer::new( vec![ 10_000_000_000_000, 0, 0 ], &test_currencies( 3 ), &pools,
+                                    &vec![ cycle.clone( ) ] );
+        ( printer, prices, cycle )
+    }
+
+    fn gain( printer: &Printer, config: &Config, prices: &Vec<PoolPrice>, cycle: &Cycle, money: u64 ) -> i128 {
+        printer.compute_potential( config, cycle, prices, money ) as i128 - money as i128
+    }
+
+    /* Talks to nowhere; the wallet is a fixed keypair handed over through the environment */
+    fn test_comm( config: &Config ) -> Communication {
+        let wallet = solana_sdk::signature::keypair_from_seed( &[ 7; 32 ] ).unwrap( );
+        std::env::set_var( "HIKARU_TEST_WALLET", wallet.to_base58_string( ) );
+        let crcy_cfg = CurrencyConfig {
+            wallet_path:    String::new( ),
+            wallet_env:     Some( "HIKARU_TEST_WALLET".to_string( ) ),
+            fee_payer_path: None,
+            currencies:     test_currencies( 3 ),
+        };
+        Communication::init( &config.cluster_url, &crcy_cfg, config )
+    }
+
+    /* Amount in and minimum amount out of each token swap instruction */
+    fn swap_amounts( instructions: &Vec<Instruction> ) -> Vec<( u64, u64 )> {
+        instructions.iter( ).filter_map( |ix| {
+            match spl_token_swap::instruction::SwapInstruction::unpack( &ix.data ) {
+                Ok( spl_token_swap::instruction::SwapInstruction::Swap( s ) ) => {
+                    Some( ( s.amount_in, s.minimum_amount_out ) )
+                },
+                _ => { None }
+            }
+        } ).collect( )
+    }
+
+    #[test]
+    fn closed_form_gamble_is_optimal( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let ( printer, prices, cycle ) = mispriced_triangle( &config );
+
+        let best = printer.get_best_gamble_money( &config, &cycle, &prices );
+        let best_gain = gain( &printer, &config, &prices, &cycle, best );
+        assert!( best_gain > 0 );
+        assert!( best < printer.get_gamble_money( &config, 0 ) );
+        assert!( best_gain >= gain( &printer, &config, &prices, &cycle, best * 4 / 5 ) );
+        assert!( best_gain >= gain( &printer, &config, &prices, &cycle, best * 6 / 5 ) );
+    }
+
+    #[test]
+    fn sampled_gamble_agrees_with_closed_form( ) {
+        let closed = test_config( serde_json::json!( { } ) );
+        let sampled = test_config( serde_json::json!( { "gamble_optimizer": { "Sampled": 200 } } ) );
+        let ( printer, prices, cycle ) = mispriced_triangle( &closed );
+
+        let closed_gain = gain( &printer, &closed, &prices, &cycle,
+                                printer.get_best_gamble_money( &closed, &cycle, &prices ) );
+        let best = printer.get_best_gamble_money( &sampled, &cycle, &prices );
+        let sampled_gain = gain( &printer, &sampled, &prices, &cycle, best );
+        assert!( sampled_gain > 0 );
+        assert!( ( sampled_gain - closed_gain ).abs( ) * 100 <= closed_gain );
+        assert!( sampled_gain >= gain( &printer, &sampled, &prices, &cycle, best * 4 / 5 ) );
+        assert!( sampled_gain >= gain( &printer, &sampled, &prices, &cycle, best * 6 / 5 ) );
+    }
+
+    #[test]
+    fn balanced_triangle_gambles_nothing_worthwhile( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let ( printer, _, cycle ) = mispriced_triangle( &config );
+        let prices = test_prices( &printer.pools, &[ [ 1_000_000_000_000, 1_000_000_000_000 ]; 3 ] );
+
+        // fees make every amount a loss
+        let best = printer.get_best_gamble_money( &config, &cycle, &prices );
+        assert!( gain( &printer, &config, &prices, &cycle, best ) < 0 );
+    }
+
+    #[test]
+    fn final_minimum_out_matches_the_prediction( ) {
+        let config = test_config( serde_json::json!( { "slippage": 0.001 } ) );
+        let ( printer, prices, cycle ) = mispriced_triangle( &config );
+        let comm = test_comm( &config );
+        let extra_signer = Keypair::new( );
+
+        let gamble = printer.get_best_gamble_money( &config, &cycle, &prices );
+        let predicted = printer.compute_potential( &config, &cycle, &prices, gamble );
+        assert!( predicted > gamble as u128 );
+
+        let instructions = printer.build_path( &comm, &cycle, gamble, Some( gamble as u128 ), &config,
+                                               &prices, &extra_signer ).unwrap( );
+        let amounts = swap_amounts( &instructions );
+        assert_eq!( amounts.len( ), 3 );
+        assert_eq!( amounts[ 0 ].0, gamble );
+        // each hop passes on what the one before it has to yield at least
+        assert_eq!( amounts[ 1 ].0, amounts[ 0 ].1 );
+        assert_eq!( amounts[ 2 ].0, amounts[ 1 ].1 );
+        let final_out = amounts[ 2 ].1 as f64;
+        assert!( ( final_out - predicted as f64 ).abs( ) <= predicted as f64 * 1e-9 + 1.0 );
+    }
+
+    #[test]
+    fn shortfall_on_a_middle_leg_builds_nothing( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let ( printer, _, cycle ) = mispriced_triangle( &config );
+        let comm = test_comm( &config );
+        let extra_signer = Keypair::new( );
+
+        // the middle pool has nothing left to pay out
+        let drained = test_prices( &printer.pools, &[ [ 1_000_000_000_000, 1_000_000_000_000 ],
+                                                     [ 1_000_000_000_000, 0 ],
+                                                     [ 1_000_000_000_000, 1_050_000_000_000 ] ] );
+        assert!( printer.build_path( &comm, &cycle, 1_000_000, None, &config, &drained, &extra_signer ).is_none( ) );
+        assert!( printer.build_path( &comm, &cycle, 1_000_000, Some( 1_000_000 ), &config, &drained,
+                                     &extra_signer ).is_none( ) );
+
+        let ( _, prices, _ ) = mispriced_triangle( &config );
+        assert!( printer.build_path( &comm, &cycle, 1_000_000, None, &config, &prices, &extra_signer ).is_some( ) );
+    }
+
+    /* Account metas as ( pubkey, is_signer, is_writable ), for comparing against a snapshot */
+    fn metas( ix: &Instruction ) -> Vec<( String, bool, bool )> {
+        ix.accounts.iter( ).map( |m| ( m.pubkey.to_string( ), m.is_signer, m.is_writable ) ).collect( )
+    }
+
+    fn meta( pubkey: &str, is_signer: bool, is_writable: bool ) -> ( String, bool, bool ) {
+        ( pubkey.to_string( ), is_signer, is_writable )
+    }
+
+    #[test]
+    fn approval_cycle_snapshot( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let currencies = test_currencies( 2 );
+        let pools = vec![ test_swap_pool( &config, 0, 0, 1, "constant-product", true ), test_pool( &config, 1, 1, 0 ) ];
+        let cycle = Cycle {
+            needs_approval: true,
+            path:           vec![ ( 0, Direction::AtoB ), ( 1, Direction::AtoB ) ],
+            max_gamble:     None,
+        };
+        let mut printer = Printer::new( vec![ 1_000_000_000, 0 ], &currencies, &pools, &vec![ cycle.clone( ) ] );
+        printer.extra_signer_seed = Some( [ 9; 32 ] );
+        let prices = test_prices( &pools, &[ [ 1_000_000_000_000, 1_000_000_000_000 ]; 2 ] );
+        let comm = test_comm( &config );
+
+        let build = || {
+            printer.build_path( &comm, &cycle, 1_000_000, None, &config, &prices,
+                                &extra_signer( printer.extra_signer_seed ) ).unwrap( )
+        };
+        let instructions = build( );
+        assert_eq!( instructions, build( ) );
+        assert_eq!( instructions.len( ), 3 );
+
+        // approve 1_000_000 of the wallet's C0 for the extra signer
+        assert_eq!( instructions[ 0 ].program_id.to_string( ), "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA" );
+        assert_eq!( instructions[ 0 ].data, vec![ 4, 64, 66, 15, 0, 0, 0, 0, 0 ] );
+        assert_eq!( metas( &instructions[ 0 ] ), vec![
+            meta( "8opHzTAnfzRpPEx21XtnrVTX28YQuCpAjcn1PczScKh", false, true ),
+            meta( "J2xccRtuG43drESLYznHhLhQkLTdfepcKYbiQ9BsJVaf", false, false ),
+            meta( "GmaDrppBC7P5ARKV8g3djiwP89vz1jLK23V2GBjuAEGB", true, false ) ] );
+
+        // swap 1_000_000 C0 for C1 on the approval pool, signed by the extra signer
+        assert_eq!( instructions[ 1 ].program_id.to_string( ), "SwapsVeCiPHMUAtzQWZw7RjsKjgCjhwU55QGu4U1Szw" );
+        assert_eq!( instructions[ 1 ].data, vec![ 1, 64, 66, 15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0 ] );
+        assert_eq!( metas( &instructions[ 1 ] ), vec![
+            meta( "CiDwVBFgWV9E5MvXWoLgnEgn2hK7rJikbvfWavzAQz3", false, false ),
+            meta( "FxJi4aJDMQVxw3wQXsvL7mq7TAjyXuECCMeiYVokvRAf", false, false ),
+            meta( "J2xccRtuG43drESLYznHhLhQkLTdfepcKYbiQ9BsJVaf", true, false ),
+            meta( "8opHzTAnfzRpPEx21XtnrVTX28YQuCpAjcn1PczScKh", false, true ),
+            meta( "QRSsyMWN1yHT9ir42bgNZUNZ4PdEhcSWCrL2AryKpy5", false, true ),
+            meta( "UKrXU5bFrTzrqqpZXs8GVDbp4xPweiM65ADXNAy3ddR", false, true ),
+            meta( "8phb8VbjFV63PdBNy9yeg42RR1scqo3jwpdNaKAQjXd", false, true ),
+            meta( "GcdayuLaLyrdmUu324nahyv33G5poQdLUEZ1nEytDeP", false, true ),
+            meta( "LX3EUdRUBUa3TbsYXLEUdj9J3prXkWXvLYSWyYyc2Jj", false, true ),
+            meta( "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA", false, false ) ] );
+
+        // swap the predicted 996_789 C1 back, signed by the wallet
+        assert_eq!( instructions[ 2 ].program_id.to_string( ), "SwapsVeCiPHMUAtzQWZw7RjsKjgCjhwU55QGu4U1Szw" );
+        assert_eq!( instructions[ 2 ].data, vec![ 1, 181, 53, 15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0 ] );
+        assert_eq!( metas( &instructions[ 2 ] ), vec![
+            meta( "Cj7EdDgd5yoT5k9tURRYboFgRaeKntxKp8WsmdA8YBy", false, false ),
+            meta( "Eyqt7iSj1pEpYXpk4N1VMfpR3S83zJDgK7637PFEzNyC", false, false ),
+            meta( "GmaDrppBC7P5ARKV8g3djiwP89vz1jLK23V2GBjuAEGB", true, false ),
+            meta( "8phb8VbjFV63PdBNy9yeg42RR1scqo3jwpdNaKAQjXd", false, true ),
+            meta( "QSLB7PwJbTwgA75QzDmEP2wTTGxSeCg5R4BPMZ9HxB1", false, true ),
+            meta( "ULjpc82CRxf5rE3vVVD8JnAiTqj9bJafHN4tYs91kqM", false, true ),
+            meta( "8opHzTAnfzRpPEx21XtnrVTX28YQuCpAjcn1PczScKh", false, true ),
+            meta( "GdWt7wmWvUWrms8PygsSXYUwS9R2jzrugSQNxw9rLrK", false, true ),
+            meta( "LXvXcfrQkyEGTz6uUxKLTHiCSiBjh6mVYkHtAF9a9Wf", false, true ),
+            meta( "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA", false, false ) ] );
+    }
+
+    #[test]
+    fn new_ath_is_saved_without_debug_logging( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let ( printer, _, _ ) = mispriced_triangle( &config );
+        let path = std::env::temp_dir( ).join( format!( "hikaru-{}-ath.json", std::process::id( ) ) );
+        let path = path.to_str( ).unwrap( );
+        let mut ath = Ath{ gain: -1_000, cyc: None, desc: String::new( ), decs: 0, date: Duration::ZERO };
+
+        assert!( !log_enabled!( Level::Debug ) );
+        printer.update_ath( &config, &mut ath, &vec![ 1_100 ], &vec![ 1_000 ], Some( path ) );
+        let state = AthState::load( path, 1 );
+        std::fs::remove_file( path ).ok( );
+        let state = state.expect( "The new ATH was not saved" );
+        assert_eq!( ( state.ath, state.ath_cyc ), ( 100, 0 ) );
+        assert_eq!( printer.metrics.ath_gain.load( Ordering::Relaxed ), 100 );
+    }
+
+    #[test]
+    fn parallel_evaluation_matches_serial( ) {
+        let config = test_config( serde_json::json!( { } ) );
+        let ( printer, prices, cycle ) = mispriced_triangle( &config );
+        // both directions, each with the other as its mirror
+        let cycles = vec![ cycle.clone( ), cycle.mirror( ) ];
+        let mirrors: Vec<Cycle> = cycles.iter( ).map( |c| c.mirror( ) ).collect( );
+
+        let evaluate = |i: usize| printer.evaluate_directions( &config, &cycles[ i ], mirrors.get( i ), &prices );
+        let parallel: Vec<( bool, u64, u128 )> = ( 0 .. cycles.len( ) ).into_par_iter( ).map( evaluate ).collect( );
+        let serial: Vec<( bool, u64, u128 )> = ( 0 .. cycles.len( ) ).map( evaluate ).collect( );
+        assert_eq!( parallel, serial );
+        // the mispriced direction wins either way
+        assert_eq!( ( parallel[ 0 ].0, parallel[ 1 ].0 ), ( false, true ) );
+        assert_eq!( parallel[ 0 ].1, parallel[ 1 ].1 );
+    }
+}
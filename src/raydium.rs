@@ -259,3 +259,69 @@ pub fn swap_base_out(
         data,
     } )
 }
+
+/// Derives the vault signer of a serum market from the market's vault signer nonce.
+pub fn serum_vault_signer(
+    serum_program_id: &Pubkey,
+    serum_market: &Pubkey,
+    vault_signer_nonce: u64,
+) -> Result<Pubkey, ProgramError> {
+    let seeds: [ &[ u8 ]; 2 ] = [ serum_market.as_ref( ), &vault_signer_nonce.to_le_bytes( ) ];
+    Ok( Pubkey::create_program_address( &seeds, serum_program_id )? )
+}
+
+/// Anchor discriminator of the cpmm swap instruction, sha256( "global:swap_base_input" )[ ..8 ]
+const CPMM_SWAP_BASE_INPUT_DISCRIMINATOR: [ u8; 8 ] = [ 143, 190, 90, 218, 196, 30, 51, 222 ];
+
+/// Derives the authority owning the vaults of every pool of the cpmm program.
+pub fn cpmm_authority( program_id: &Pubkey ) -> Pubkey {
+    Pubkey::find_program_address( &[ b"vault_and_lp_mint_auth_seed" ], program_id ).0
+}
+
+/// Creates a cpmm 'swap base input' instruction; unlike the v4 amm there is no serum market.
+pub fn cpmm_swap_base_input(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    authority: &Pubkey,
+    amm_config: &Pubkey,
+    pool_state: &Pubkey,
+    user_input_token_account: &Pubkey,
+    user_output_token_account: &Pubkey,
+    input_vault: &Pubkey,
+    output_vault: &Pubkey,
+    input_token_program: &Pubkey,
+    output_token_program: &Pubkey,
+    input_token_mint: &Pubkey,
+    output_token_mint: &Pubkey,
+    observation_state: &Pubkey,
+
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut data = Vec::with_capacity( 24 );
+    data.extend_from_slice( &CPMM_SWAP_BASE_INPUT_DISCRIMINATOR );
+    data.extend_from_slice( &amount_in.to_le_bytes( ) );
+    data.extend_from_slice( &minimum_amount_out.to_le_bytes( ) );
+
+    let accounts = vec![
+        AccountMeta::new_readonly( *payer, true ),
+        AccountMeta::new_readonly( *authority, false ),
+        AccountMeta::new_readonly( *amm_config, false ),
+        AccountMeta::new( *pool_state, false ),
+        AccountMeta::new( *user_input_token_account, false ),
+        AccountMeta::new( *user_output_token_account, false ),
+        AccountMeta::new( *input_vault, false ),
+        AccountMeta::new( *output_vault, false ),
+        AccountMeta::new_readonly( *input_token_program, false ),
+        AccountMeta::new_readonly( *output_token_program, false ),
+        AccountMeta::new_readonly( *input_token_mint, false ),
+        AccountMeta::new_readonly( *output_token_mint, false ),
+        AccountMeta::new( *observation_state, false ),
+    ];
+
+    Ok( Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    } )
+}
@@ -1,20 +1,12 @@
 //! State transition types
 
-use crate::error::AmmError;
 use solana_program::{
-    entrypoint::ProgramResult,
     program_error::ProgramError,
     pubkey::Pubkey,
-    account_info::AccountInfo,
     program_pack::{IsInitialized, Pack, Sealed},
 };
 
-use safe_transmute::{self, trivial::TriviallyTransmutable};
-use bytemuck::{
-     Pod, Zeroable, cast_slice_mut, from_bytes_mut,
-};
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
-use std::{cell::RefMut};
 
 #[repr(u64)]
 pub enum AmmStatus {
@@ -100,14 +92,6 @@ impl AmmState {
     }
 }
 
-fn validate_fraction(numerator: u64, denominator: u64) -> Result<(), AmmError> {
-    if numerator >= denominator || denominator == 0 {
-        Err(AmmError::InvalidFee)
-    } else {
-        Ok(())
-    }
-}
-
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Fees {
@@ -133,17 +117,6 @@ pub struct Fees {
     pub swap_fee_denominator: u64,
 }
 
-impl Fees {
-    /// Validate that the fees are reasonable
-    pub fn validate(&self) -> Result<(), AmmError> {
-        validate_fraction(self.min_separate_numerator, self.min_separate_denominator)?;
-        validate_fraction(self.trade_fee_numerator, self.trade_fee_denominator)?;
-        validate_fraction(self.pnl_numerator, self.pnl_denominator)?;
-        validate_fraction(self.swap_fee_numerator, self.swap_fee_denominator)?;
-        Ok(())
-    }
-}
-
 /// IsInitialized is required to use `Pack::pack` and `Pack::unpack`
 impl IsInitialized for Fees {
     fn is_initialized(&self) -> bool {
@@ -233,27 +206,49 @@ pub struct OutPutData {
      pub swap_pc2coin_fee: u64,
 }
 
+
 impl OutPutData {
-    pub fn initialize(&mut self) -> Result<(), AmmError> {
-        self.need_take_pnl_coin = 0u64;
-        self.need_take_pnl_pc = 0u64;
-        self.total_pnl_pc = 0u64;
-        self.total_pnl_coin = 0u64;
-        self.pool_total_deposit_pc = 1000000000u128;
-        self.pool_total_deposit_coin = 1000000000u128;
-        self.swap_coin_in_amount = 0u128;
-        self.swap_pc_out_amount = 0u128;
-        self.swap_coin2pc_fee = 0u64;
-        self.swap_pc_in_amount = 0u128;
-        self.swap_coin_out_amount = 0u128;
-        self.swap_pc2coin_fee = 0u64;
-        Ok(())
+    pub const LEN: usize = 144;
+
+    pub fn unpack_from_slice( input: &[ u8 ] ) -> Result<OutPutData, ProgramError> {
+        if input.len( ) < Self::LEN {
+            return Err( ProgramError::InvalidAccountData );
+        }
+        let input = array_ref![ input, 0, 144 ];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (
+            need_take_pnl_coin,
+            need_take_pnl_pc,
+            total_pnl_pc,
+            total_pnl_coin,
+            pool_total_deposit_pc,
+            pool_total_deposit_coin,
+            swap_coin_in_amount,
+            swap_pc_out_amount,
+            swap_coin2pc_fee,
+            swap_pc_in_amount,
+            swap_coin_out_amount,
+            swap_pc2coin_fee,
+        ) = array_refs![ input, 8, 8, 8, 8, 16, 16, 16, 16, 8, 16, 16, 8 ];
+        Ok( Self {
+            need_take_pnl_coin:      u64::from_le_bytes( *need_take_pnl_coin ),
+            need_take_pnl_pc:        u64::from_le_bytes( *need_take_pnl_pc ),
+            total_pnl_pc:            u64::from_le_bytes( *total_pnl_pc ),
+            total_pnl_coin:          u64::from_le_bytes( *total_pnl_coin ),
+            pool_total_deposit_pc:   u128::from_le_bytes( *pool_total_deposit_pc ),
+            pool_total_deposit_coin: u128::from_le_bytes( *pool_total_deposit_coin ),
+            swap_coin_in_amount:     u128::from_le_bytes( *swap_coin_in_amount ),
+            swap_pc_out_amount:      u128::from_le_bytes( *swap_pc_out_amount ),
+            swap_coin2pc_fee:        u64::from_le_bytes( *swap_coin2pc_fee ),
+            swap_pc_in_amount:       u128::from_le_bytes( *swap_pc_in_amount ),
+            swap_coin_out_amount:    u128::from_le_bytes( *swap_coin_out_amount ),
+            swap_pc2coin_fee:        u64::from_le_bytes( *swap_pc2coin_fee ),
+        } )
     }
 }
 
-#[cfg_attr(feature = "client", derive(Debug))]
 #[repr(C)]
-#[derive(Clone, Copy, Default, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct AmmInfo {
     /// 1 Initialized status.
     pub status: u64,
@@ -323,37 +318,183 @@ pub struct AmmInfo {
     pub pnl_owner: Pubkey,
 }
 
-#[cfg(target_endian = "little")]
-unsafe impl Zeroable for AmmInfo {}
-#[cfg(target_endian = "little")]
-unsafe impl Pod for AmmInfo {}
-#[cfg(target_endian = "little")]
-unsafe impl TriviallyTransmutable for AmmInfo {}
-
 impl AmmInfo {
-    /// Helper function to get the more efficient packed size of the struct
-    #[inline]
-    pub fn load_amm_mut<'a>(amm_account:&'a AccountInfo, check_status: bool) -> Result<RefMut<'a, AmmInfo>, ProgramError> {
-        let account_data: RefMut<'a, [u8]>;
-        let amm_data: RefMut<'a, AmmInfo>;
+    /// Size of the (packed) amm account as stored on chain.
+    pub const LEN: usize = 752;
 
-        account_data = RefMut::map(amm_account.try_borrow_mut_data()?, |data| *data);
-        amm_data = RefMut::map(account_data, |data| {
-            from_bytes_mut(cast_slice_mut(data))
-        });
-        if check_status {
-            amm_data.check_status()?;
+    /// Decodes the data of a raydium v4 amm account.
+    pub fn unpack_from_slice( input: &[ u8 ] ) -> Result<AmmInfo, ProgramError> {
+        if input.len( ) < Self::LEN {
+            return Err( ProgramError::InvalidAccountData );
         }
-        Ok(amm_data)
+        let input = array_ref![ input, 0, 752 ];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (
+            status,
+            nonce,
+            order_num,
+            depth,
+            coin_decimals,
+            pc_decimals,
+            state,
+            reset_flag,
+            min_size,
+            vol_max_cut_ratio,
+            amount_wave,
+            coin_lot_size,
+            pc_lot_size,
+            min_price_multiplier,
+            max_price_multiplier,
+            sys_decimal_value,
+            fees,
+            out_put,
+            token_coin,
+            token_pc,
+            coin_mint,
+            pc_mint,
+            lp_mint,
+            open_orders,
+            market,
+            serum_dex,
+            target_orders,
+            withdraw_queue,
+            token_temp_lp,
+            amm_owner,
+            pnl_owner,
+        ) = array_refs![ input, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 64, 144,
+                         32, 32, 32, 32, 32, 32, 32, 32, 32, 32, 32, 32, 32 ];
+
+        let status = u64::from_le_bytes( *status );
+        if status == AmmStatus::Uninitialized as u64 {
+            return Err( ProgramError::UninitializedAccount );
+        }
+
+        Ok( Self {
+            status,
+            nonce:                u64::from_le_bytes( *nonce ),
+            order_num:            u64::from_le_bytes( *order_num ),
+            depth:                u64::from_le_bytes( *depth ),
+            coin_decimals:        u64::from_le_bytes( *coin_decimals ),
+            pc_decimals:          u64::from_le_bytes( *pc_decimals ),
+            state:                u64::from_le_bytes( *state ),
+            reset_flag:           u64::from_le_bytes( *reset_flag ),
+            min_size:             u64::from_le_bytes( *min_size ),
+            vol_max_cut_ratio:    u64::from_le_bytes( *vol_max_cut_ratio ),
+            amount_wave:          u64::from_le_bytes( *amount_wave ),
+            coin_lot_size:        u64::from_le_bytes( *coin_lot_size ),
+            pc_lot_size:          u64::from_le_bytes( *pc_lot_size ),
+            min_price_multiplier: u64::from_le_bytes( *min_price_multiplier ),
+            max_price_multiplier: u64::from_le_bytes( *max_price_multiplier ),
+            sys_decimal_value:    u64::from_le_bytes( *sys_decimal_value ),
+            fees:                 Fees::unpack_from_slice( fees )?,
+            out_put:              OutPutData::unpack_from_slice( out_put )?,
+            token_coin:           Pubkey::new_from_array( *token_coin ),
+            token_pc:             Pubkey::new_from_array( *token_pc ),
+            coin_mint:            Pubkey::new_from_array( *coin_mint ),
+            pc_mint:              Pubkey::new_from_array( *pc_mint ),
+            lp_mint:              Pubkey::new_from_array( *lp_mint ),
+            open_orders:          Pubkey::new_from_array( *open_orders ),
+            market:               Pubkey::new_from_array( *market ),
+            serum_dex:            Pubkey::new_from_array( *serum_dex ),
+            target_orders:        Pubkey::new_from_array( *target_orders ),
+            withdraw_queue:       Pubkey::new_from_array( *withdraw_queue ),
+            token_temp_lp:        Pubkey::new_from_array( *token_temp_lp ),
+            amm_owner:            Pubkey::new_from_array( *amm_owner ),
+            pnl_owner:            Pubkey::new_from_array( *pnl_owner ),
+        } )
     }
+}
+
+/// The part of a serum open orders account the amm uses to park liquidity.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OpenOrdersInfo {
+    pub native_coin_free:  u64,
+    pub native_coin_total: u64,
+    pub native_pc_free:    u64,
+    pub native_pc_total:   u64,
+}
 
-    #[inline]
-    pub fn check_status(&self) -> Result<bool, ProgramError> {
-        if self.status == AmmStatus::Uninitialized as u64 {
-            Err(AmmError::InvalidStatus.into())
+impl OpenOrdersInfo {
+    /// Decodes a serum open orders account; the account data is framed by 5 bytes of
+    /// "serum" head padding, followed by account flags, market and owner.
+    pub fn unpack_from_slice( input: &[ u8 ] ) -> Result<OpenOrdersInfo, ProgramError> {
+        if input.len( ) < 109 {
+            return Err( ProgramError::InvalidAccountData );
         }
-        else {
-            Ok(true)
+        let input = array_ref![ input, 0, 109 ];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (
+            _head,
+            _account_flags,
+            _market,
+            _owner,
+            native_coin_free,
+            native_coin_total,
+            native_pc_free,
+            native_pc_total,
+        ) = array_refs![ input, 5, 8, 32, 32, 8, 8, 8, 8 ];
+        Ok( Self {
+            native_coin_free:  u64::from_le_bytes( *native_coin_free ),
+            native_coin_total: u64::from_le_bytes( *native_coin_total ),
+            native_pc_free:    u64::from_le_bytes( *native_pc_free ),
+            native_pc_total:   u64::from_le_bytes( *native_pc_total ),
+        } )
+    }
+}
+
+/// The parts of a cpmm pool state needed to price and route swaps.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CpmmPoolInfo {
+    pub token_vaults:   [ Pubkey; 2 ],
+    pub token_mints:    [ Pubkey; 2 ],
+    /// owners of the mints, spl token or token 2022
+    pub token_programs: [ Pubkey; 2 ],
+    /// protocol and fund fees sitting in the vaults until they are collected
+    pub pending_fees:   [ u64; 2 ],
+}
+
+impl CpmmPoolInfo {
+    /// Decodes a cpmm pool state; the account data starts with the 8 byte anchor
+    /// discriminator, followed by the amm config and the pool creator.
+    pub fn unpack_from_slice( input: &[ u8 ] ) -> Result<CpmmPoolInfo, ProgramError> {
+        if input.len( ) < 373 {
+            return Err( ProgramError::InvalidAccountData );
         }
+        let input = array_ref![ input, 0, 373 ];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (
+            _discriminator,
+            _amm_config,
+            _pool_creator,
+            token_0_vault,
+            token_1_vault,
+            _lp_mint,
+            token_0_mint,
+            token_1_mint,
+            token_0_program,
+            token_1_program,
+            _observation_key,
+            _auth_bump,
+            _status,
+            _lp_mint_decimals,
+            _mint_0_decimals,
+            _mint_1_decimals,
+            _lp_supply,
+            protocol_fees_token_0,
+            protocol_fees_token_1,
+            fund_fees_token_0,
+            fund_fees_token_1,
+        ) = array_refs![ input, 8, 32, 32, 32, 32, 32, 32, 32, 32, 32, 32, 1, 1, 1, 1, 1, 8, 8, 8, 8, 8 ];
+        let pending = |protocol: &[ u8; 8 ], fund: &[ u8; 8 ]| {
+            u64::from_le_bytes( *protocol ).saturating_add( u64::from_le_bytes( *fund ) )
+        };
+        Ok( Self {
+            token_vaults:   [ Pubkey::new_from_array( *token_0_vault ), Pubkey::new_from_array( *token_1_vault ) ],
+            token_mints:    [ Pubkey::new_from_array( *token_0_mint ), Pubkey::new_from_array( *token_1_mint ) ],
+            token_programs: [ Pubkey::new_from_array( *token_0_program ),
+                              Pubkey::new_from_array( *token_1_program ) ],
+            pending_fees:   [ pending( protocol_fees_token_0, fund_fees_token_0 ),
+                              pending( protocol_fees_token_1, fund_fees_token_1 ) ],
+        } )
     }
 }
@@ -0,0 +1,112 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{ BufRead, BufReader, BufWriter, Write },
+    path::Path,
+};
+use solana_client::{
+    rpc_response::{
+        Response as RpcResponse
+    },
+};
+use solana_account_decoder::{
+    UiAccount
+};
+use serde::{ Serialize, Deserialize };
+use log::{ warn, error };
+
+// Structs
+
+/* A single line of a recording; a recording starts with Init, followed by the updates in
+ * the order they were received. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RecordEntry {
+    Init {
+        money:         u64, // balance of the first start currency
+        token_amounts: Vec<[ ( f64, u8 ); 2 ]>, // per pool, as in TokenPrice
+        #[serde(default)]
+        balances:      Vec<u64>, // per currency, as in Printer; empty in older recordings
+    },
+    Update( RpcResponse<( usize, usize, UiAccount )> ),
+}
+
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+/* Highest yield observed by the print loop, kept across restarts. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AthState {
+    pub cycles:   usize, // number of cycles at the time, ath_cyc is meaningless otherwise
+    pub ath:      i128,
+    pub ath_cyc:  usize,
+    pub ath_date: u64,   // seconds since the epoch
+}
+
+// Implementations
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>( path: P ) -> Result<Self, Box<dyn Error>> {
+        let file = File::create( path )?;
+        Ok( Recorder {
+            writer: BufWriter::new( file )
+        } )
+    }
+
+    pub fn record( &mut self, entry: &RecordEntry ) {
+        if let Err( err ) = self.write( entry ) {
+            error!( "Recording update failed: {:?}", err );
+        }
+    }
+
+    fn write( &mut self, entry: &RecordEntry ) -> Result<(), Box<dyn Error>> {
+        serde_json::to_writer( &mut self.writer, entry )?;
+        writeln!( self.writer )?;
+        // flush right away, the print loop only ends by getting killed
+        self.writer.flush( )?;
+        Ok( ( ) )
+    }
+}
+
+impl AthState {
+    /* Loads the state, unless there is none or it belongs to a different set of cycles */
+    pub fn load<P: AsRef<Path>>( path: P, cycles: usize ) -> Option<Self> {
+        let file = File::open( path ).ok( )?;
+        match serde_json::from_reader::<_, AthState>( BufReader::new( file ) ) {
+            Ok( state ) if state.cycles == cycles && state.ath_cyc < cycles => { Some( state ) },
+            Ok( _ ) => {
+                warn!( "Ignoring ATH state of a different set of cycles." );
+                None
+            },
+            Err( err ) => {
+                warn!( "Ignoring malformed ATH state: {:?}", err );
+                None
+            }
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>( &self, path: P ) {
+        if let Err( err ) = self.write( path ) {
+            error!( "Saving ATH state failed: {:?}", err );
+        }
+    }
+
+    fn write<P: AsRef<Path>>( &self, path: P ) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new( File::create( path )? );
+        serde_json::to_writer( &mut writer, self )?;
+        writer.flush( )?;
+        Ok( ( ) )
+    }
+}
+
+pub fn read_records<P: AsRef<Path>>( path: P ) -> Result<Vec<RecordEntry>, Box<dyn Error>> {
+    let file = File::open( path )?;
+    let reader = BufReader::new( file );
+    let mut res = Vec::new( );
+    for line in reader.lines( ) {
+        let line = line?;
+        if line.trim( ).is_empty( ) { continue; }
+        res.push( serde_json::from_str( &line )? );
+    }
+    Ok( res )
+}
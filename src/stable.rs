@@ -1,26 +1,18 @@
 use std::{
-    sync::mpsc::channel,
+    sync::{
+        Arc,
+        atomic::Ordering,
+        mpsc::channel,
+    },
     thread::sleep,
-    time::Duration,
+    time::{ Duration, Instant },
 };
 use solana_sdk::{
     signature::{ Signer },
-    commitment_config::CommitmentConfig,
-    signer::keypair::Keypair,
+    compute_budget::ComputeBudgetInstruction,
 };
 use solana_client::{
-    rpc_config::{
-        RpcAccountInfoConfig,
-    },
-    rpc_response::{
-        Response as RpcResponse
-    },
-};
-use solana_rpc::{
-    rpc_pubsub::gen_client::Client as PubsubClient,
-};
-use solana_account_decoder::{
-    UiAccount, UiAccountEncoding
+    client_error::{ Result as ClientResult },
 };
 use spl_token::{
     solana_program::{
@@ -28,49 +20,88 @@ use spl_token::{
     },
 };
 
-use jsonrpc_core::futures::StreamExt;
-use jsonrpc_client_transports::transports::ws;
-use tokio::runtime::Runtime;
+use tokio::{
+    runtime::Runtime,
+    sync::mpsc::unbounded_channel,
+};
+use log::{ debug, info, warn, error, log_enabled, Level };
 
 use crate::{
     communication::*,
     config::*,
+    metrics::*,
     price::*,
+    subscription::*,
 };
 
+// Seconds between two reads of the currencies' oracle prices
+const ORACLE_REFRESH_INTERVAL: u64 = 30;
+
 // Structs
 
 pub struct StablePrinter {
     pub money: u64,
     pub current_currency: usize,
-    pub debug: bool,
     pub currencies: Vec<Currency>,
-    pub pools: Vec<Pool>
+    pub pools: Vec<Pool>,
+    pub metrics: Arc<Metrics>,
+    balances: Vec<Option<( u64, Instant )>>, // per currency, with the time it was fetched
+    prices:   Vec<f64>, // per currency, value of one unit in USD
+    prices_read: Option<Instant>, // last time the oracles were read
+    pub extra_signer_seed: Option<[ u8; 32 ]>, // fixed extra signer, for reproducible transactions
 }
 
 // Implementations
 
 impl StablePrinter {
     pub fn init( comm: &Communication, currencies: &Vec<Currency>, pools: &Vec<Pool>,
-                 debug: bool ) -> Self {
+                 config: &Config ) -> Self {
         let mut res = StablePrinter {
             money:            0,
             current_currency: pools.len( ) + 1,
-            debug:            debug,
             currencies:       currencies.clone( ),
             pools:            pools.clone( ),
+            metrics:          Arc::new( Metrics::default( ) ),
+            balances:         vec![ None; currencies.len( ) ],
+            prices:           currencies.iter( ).map( |c| c.usd_price.unwrap_or( 1.0 ) ).collect( ),
+            prices_read:      None,
+            extra_signer_seed: None,
         };
-        res.recompute_balance( comm );
+        if let Err( err ) = res.recompute_balance( comm, config ) {
+            error!( "{:?}", err );
+            std::process::exit( 1 )
+        }
         res
     }
 
-    pub fn recompute_balance( &mut self, comm: &Communication ) {
+    /* Refetches the balances that are unknown, older than stable_balance_ttl, or of the
+     * currently held currency, and moves to the currency with the most money. */
+    pub fn recompute_balance( &mut self, comm: &Communication, config: &Config ) -> ClientResult<()> {
+        self.refresh_prices( comm );
+        let now = Instant::now( );
+        for i in 0 .. self.currencies.len( ) {
+            let stale = match self.balances[ i ] {
+                Some( ( _, fetched ) ) => {
+                    i == self.current_currency
+                        || now.duration_since( fetched ).as_secs( ) >= config.stable_balance_ttl
+                },
+                None => { true }
+            };
+            if stale {
+                let money = comm.get_current_balance_for_currency( &self.currencies[ i ] )?;
+                self.balances[ i ] = Some( ( money, now ) );
+            }
+        }
+
         let mut max_money = 0;
+        let mut max_value = 0.0;
         let mut argmax = self.pools.len( ) + 1;
         let mut i = 0;
-        for c in &self.currencies {
-            let money = comm.get_current_balance_for_currency( c );
-            if money > max_money {
+        for b in &self.balances {
+            let money = b.map( |( money, _ )| money ).unwrap_or( 0 );
+            let value = money as f64 / pow10( self.currencies[ i ].decimals as usize ) * self.prices[ i ];
+            if value > max_value {
+                max_value = value;
                 max_money = money;
                 argmax = i;
             }
@@ -79,99 +110,47 @@ impl StablePrinter {
 
         self.current_currency = argmax;
         self.money = max_money;
+        self.metrics.balance.store( max_money, Ordering::Relaxed );
+        Ok( ( ) )
     }
 
     pub fn run( &mut self, comm: &Communication, comm_send: &Communication,
-                config: &Config, simulate: bool ) {
+                config: &Config, simulate: bool, once: bool ) {
         // check if rpc is good
-        comm_send.get_blockhash( );
+        comm_send.get_blockhash( ).expect( "RPC is garbage" );
 
         // initialize pool prizes
-        let mut pool_prices = Vec::new( );
-        for p in &self.pools {
-            pool_prices.push( PoolPrice::init( comm, &p ) );
-        }
-
-        // set up subscriptions
+        let ( mut pool_prices, mut raydium_accounts ) = PoolPrice::init_all( comm, &self.pools, &self.currencies )
+            .unwrap_or_else( |err| {
+                error!( "Initializing pool prices failed: {}", err );
+                std::process::exit( 1 )
+            } );
+
+        // set up subscriptions; the pools never change here, so the subscription id of a pool
+        // is its index
         let ( account_sender, account_receiver )
-            = channel::<RpcResponse<( usize, usize, UiAccount )>>( );
-
-        let config_clone = config.clone( );
-        let pools_clone = self.pools.clone( );
+            = channel::<AccountUpdate>( );
+        let ( command_sender, command_receiver )
+            = unbounded_channel::<SubscriptionCommand>( );
 
         // Create the pub sub runtime
         let rt = Runtime::new( ).unwrap( );
-        rt.spawn( async move {
-            let connect = ws::try_connect::<PubsubClient>( &config_clone.cluster_url ).unwrap( );
-            let client = connect.await.unwrap( );
-
-            // Subscribe to account notifications
-
-            let mut idx = 0;
-            for p in pools_clone {
-                match p {
-                    Pool::Swap( pool ) => {
-                        for i in 0 ..= 1 {
-                            let account_sender = account_sender.clone( );
-                            let mut client_sub = client
-                                .account_subscribe(
-                                    pool.get_currency( i ).account.to_string( ),
-                                    Some( RpcAccountInfoConfig {
-                                        commitment: Some( CommitmentConfig::confirmed( ) ),
-                                        encoding: Some( UiAccountEncoding::Base64Zstd ),
-                                        ..RpcAccountInfoConfig::default( )
-                                    } ),
-                                    ).unwrap_or_else( |err| panic!( "acct sub err: {:#?}", err ) );
-                            tokio::spawn( async move {
-                                loop {
-                                    match client_sub.next( ).await {
-                                        Some( response_ab ) => {
-                                            let response = response_ab.unwrap( );
-                                            let n_response = solana_client::rpc_response::Response{
-                                                context: response.context,
-                                                value: ( idx, i, response.value )
-                                            };
-                                            account_sender.send( n_response ).unwrap( );
-                                        }
-                                        None => { }
-                                    }
-                                }
-                            } );
-                        }
-                    },
-                    Pool::Raydium( _ ) => { }
-                }
-                idx = idx + 1;
-            }
-        } );
+        update_source( config ).spawn( &rt, command_receiver, account_sender );
+        for ( sid, p ) in self.pools.iter( ).enumerate( ) {
+            command_sender.send( SubscriptionCommand::Subscribe( sid, p.clone( ) ) ).unwrap( );
+        }
 
-        println!( "Initiating print sequence." );
+        info!( "Initiating print sequence." );
 
         // TODO: add counter for scheduled abort to reset pool information to counteract skew
         loop {
             // Get all updates from the channel
             loop {
                 match account_receiver.try_recv( ) {
-                    Ok( solana_client::rpc_response::Response{ value: ( pool, tkn, result ), ..} ) => {
+                    Ok( AccountUpdate{ value: ( pool, tkn, result ), ..} ) => {
                         // update / recalculate costs
-                        match self.pools[ pool ] {
-                            Pool::Swap( _ ) => {
-                                pool_prices[ pool ].token_price[ tkn ].update(
-                                    &self.pools[ pool ].get_currency( tkn ), &result );
-
-                                if pool_prices[ pool ].token_updated[ 1 - tkn ] {
-                                    pool_prices[ pool ].token_updated[ tkn ] = false;
-                                    pool_prices[ pool ].token_updated[ 1 - tkn ] = false;
-                                    pool_prices[ pool ].sanity = true;
-                                } else {
-                                    pool_prices[ pool ].token_updated[ tkn ] = true;
-                                    pool_prices[ pool ].sanity = false;
-                                }
-                            },
-                            Pool::Raydium( _ ) => {
-                                // TODO
-                            }
-                        }
+                        pool_prices[ pool ].update( &self.pools[ pool ], &mut raydium_accounts[ pool ],
+                                                    tkn, &result );
                     },
                     Err( _err ) => {
                         // nothing new anymore
@@ -185,97 +164,145 @@ impl StablePrinter {
             // - if so, compute/check if using pool yields
             // - if so, pick highest yielding pool and swap
 
-            self.recompute_balance( comm );
+            if let Err( err ) = self.recompute_balance( comm, config ) {
+                error!( "Fetching balance failed: {:?}", err );
+                continue;
+            }
 
             let gamble_money = self.get_gamble_money( config );
             let curr_a = &self.currencies[ self.current_currency ];
             let decs_a = curr_a.decimals as usize;
 
-            let mut max_value = gamble_money;
-            let mut max_value_n = gamble_money;
-            let mut arg_max = self.pools.len( );
-            let mut arg_max_dir = 2;
+            let mut max_value_n = gamble_money as u128;
+            // hops of the best move: ( pool, direction, minimum out in units of the output token )
+            let mut best_hops: Vec<( usize, Direction, u128 )> = Vec::new( );
 
-            if self.debug {
-                println!( "Balance: {} {}",
-                          ( gamble_money as f64 ) / POWERS_OF_TEN[ decs_a ],
-                          self.currencies[ self.current_currency ].name );
+            debug!( "Balance: {} {}",
+                    ( gamble_money as f64 ) / pow10( decs_a ),
+                    self.currencies[ self.current_currency ].name );
+            if log_enabled!( Level::Debug ) {
+                self.log_yield_matrix( &pool_prices, gamble_money, config );
             }
 
             for i in 0 .. self.pools.len( ) {
-                for w in 0 ..= 1 {
+                for &w in Direction::BOTH.iter( ) {
                     if self.pools[ i ].get_currency( w ).currency_idx == self.current_currency {
                         // compute yield if this pool is used
-                        let pool_price = pool_prices[ i ];
-                        if !pool_price.sanity { continue; }
-
-                        let curr_b = self.pools[ i ].get_currency( 1 - w );
-                        let decs_b = self.currencies[ curr_b.currency_idx ].decimals as usize;
-
-                        let ( toys_out, _ ) = pool_price.swap( gamble_money as u128,
-                                                               w, &self.pools[ i ] );
-                        let mut toys_out = ( toys_out as f64 * ( 1.0 - config.slippage ) ) as u128;
-                        let toys_out_n = toys_out;
-
-                        if decs_a != decs_b {
-                            toys_out = ( ( toys_out as f64 ) / POWERS_OF_TEN[ decs_a ]
-                                         * POWERS_OF_TEN[ decs_b ] ) as u128;
+                        let toys_out = match self.predict_hop( &pool_prices, i, w, gamble_money as u128,
+                                                               config ) {
+                            Some( toys_out ) => { toys_out },
+                            None => { continue; }
+                        };
+
+                        let curr_b = self.pools[ i ].get_currency( w.flip( ) ).currency_idx;
+                        let toys_out_n = self.revalue( toys_out, curr_b, self.current_currency );
+
+                        if toys_out_n > max_value_n {
+                            max_value_n = toys_out_n;
+                            best_hops = vec![ ( i, w, toys_out ) ];
                         }
 
-                        if toys_out_n > max_value_n as u128 {
-                            max_value_n = toys_out_n as u64;
-                            max_value = toys_out as u64;
-                            arg_max = i;
-                            arg_max_dir = w;
-                        }
+                        debug!( "{}: {} {}", i,
+                                ( toys_out as f64 ) / pow10( self.currencies[ curr_b ].decimals as usize ),
+                                self.currencies[ curr_b ].name );
+
+                        if !config.stable_two_hop { continue; }
+
+                        // continue with a second swap within the same transaction
+                        for j in 0 .. self.pools.len( ) {
+                            if j == i { continue; }
+                            for &v in Direction::BOTH.iter( ) {
+                                if self.pools[ j ].get_currency( v ).currency_idx != curr_b { continue; }
+                                let curr_c = self.pools[ j ].get_currency( v.flip( ) ).currency_idx;
+                                if curr_c == curr_b { continue; }
+
+                                let toys_out_2 = match self.predict_hop( &pool_prices, j, v, toys_out,
+                                                                         config ) {
+                                    Some( toys_out_2 ) => { toys_out_2 },
+                                    None => { continue; }
+                                };
+                                let toys_out_2_n = self.revalue( toys_out_2, curr_c, self.current_currency );
+
+                                if toys_out_2_n > max_value_n {
+                                    max_value_n = toys_out_2_n;
+                                    best_hops = vec![ ( i, w, toys_out ), ( j, v, toys_out_2 ) ];
+                                }
 
-                        if self.debug {
-                            println!( "{}: {} {}", i,
-                                      ( toys_out as f64 ) / POWERS_OF_TEN[ decs_b ],
-                                      self.currencies[ curr_b.currency_idx ].name );
+                                debug!( "{} {}: {} {}", i, j,
+                                        ( toys_out_2 as f64 ) / pow10( self.currencies[ curr_c ].decimals as usize ),
+                                        self.currencies[ curr_c ].name );
+                            }
                         }
                     }
                 }
             }
 
-            if arg_max < self.pools.len( )
-                &&  max_value_n > ( ( gamble_money as f64 ) * config.minimum_gain_p ) as u64 {
+            if !best_hops.is_empty( )
+                &&  max_value_n > ( ( gamble_money as f64 ) * config.minimum_gain_p ) as u128 {
                 // enough profit, execute
-                if self.debug {
-                    let curr_b = self.pools[ arg_max ].get_currency( 1 - arg_max_dir );
-                    println!( "Executing swap to {}.",
-                              self.currencies[ curr_b.currency_idx ].name );
-                }
-
-                let extra_signer = Keypair::new( );
-                let hash = comm_send.get_blockhash( );
+                let ( lst_pool, lst_dir, _ ) = best_hops[ best_hops.len( ) - 1 ];
+                let curr_b = self.pools[ lst_pool ].get_currency( lst_dir.flip( ) );
+                info!( "Executing {} swap(s) to {}.", best_hops.len( ),
+                       self.currencies[ curr_b.currency_idx ].name );
+
+                let extra_signer = extra_signer( self.extra_signer_seed );
+                let hash = match comm_send.get_blockhash( ) {
+                    Ok( hash ) => { hash },
+                    Err( err ) => {
+                        error!( "Fetching blockhash failed: {:?}", err );
+                        continue;
+                    }
+                };
                 let mut instructions: Vec<Instruction> = Vec::new( );
 
-                if !self.pools[ arg_max ].swap( &mut instructions, &comm_send.wallet.pubkey( ),
-                                                &extra_signer.pubkey( ),
-                                                gamble_money as u128,
-                                                max_value as u128,
-                                                arg_max_dir,
-                                                config,
-                                                &self.currencies ) {
-                    if self.debug {
-                        println!( "Creating tx failed." );
+                // compute budget; needs to precede the swap instructions
+                if let Some( limit ) = config.compute_unit_limit {
+                    instructions.push( ComputeBudgetInstruction::set_compute_unit_limit( limit ) );
+                }
+
+                // each swap spends what the previous one has to yield at least
+                let mut toys_in = gamble_money as u128;
+                let mut created = true;
+                for ( pool, dir, toys_out ) in &best_hops {
+                    if !self.pools[ *pool ].swap( &mut instructions, &comm_send.wallet.pubkey( ),
+                                                  &extra_signer.pubkey( ),
+                                                  toys_in,
+                                                  *toys_out,
+                                                  *dir,
+                                                  &pool_prices[ *pool ],
+                                                  config,
+                                                  &self.currencies ) {
+                        created = false;
+                        break;
                     }
+                    toys_in = *toys_out;
+                }
+                if !created {
+                    warn!( "Creating tx failed." );
+                    if once { return; }
                     continue;
                 }
 
+                // whatever happened, the balances involved can't be trusted anymore
+                self.balances[ self.current_currency ] = None;
+                for ( pool, dir, _ ) in &best_hops {
+                    let curr = self.pools[ *pool ].get_currency( dir.flip( ) ).currency_idx;
+                    self.balances[ curr ] = None;
+                }
+
                 let signers = vec![ &comm.wallet ];
 
-                match comm_send.send_transaction( &instructions, &signers, simulate, hash ) {
+                match comm_send.send_transaction( &instructions, &signers, simulate, hash, None ) {
                     Ok( _ ) => {
-                        if self.debug {
-                            println!( "===== transaction completed =====" );
-                        }
+                        self.metrics.tx_sent( true );
+                        debug!( "===== transaction completed =====" );
                     },
                     Err( err ) => {
-                        if self.debug {
-                            println!( "Error: {:?}", err );
+                        // a clean simulation ends up here too, it sent nothing
+                        if !simulate {
+                            self.metrics.tx_sent( false );
                         }
+                        warn!( "Sending transaction failed: {:?}", err );
                     }
                 }
 
@@ -283,40 +310,117 @@ impl StablePrinter {
                 sleep( Duration::from_millis( 1000 ) );
             }
 
-            if self.debug {
-                println!( "Waiting for updates.." );
+            // a single pass is all that was asked for
+            if once {
+                return;
             }
 
+            debug!( "Waiting for updates.." );
+
             match account_receiver.recv( ) {
-                Ok( solana_client::rpc_response::Response{ value: ( pool, tkn, result ), ..} ) => {
+                Ok( AccountUpdate{ value: ( pool, tkn, result ), ..} ) => {
                     // update / recalculate costs
-                    match self.pools[ pool ] {
-                        Pool::Swap( _ ) => {
-                            pool_prices[ pool ].token_price[ tkn ].update(
-                                &self.pools[ pool ].get_currency( tkn ), &result );
-
-                            if pool_prices[ pool ].token_updated[ 1 - tkn ] {
-                                pool_prices[ pool ].token_updated[ tkn ] = false;
-                                pool_prices[ pool ].token_updated[ 1 - tkn ] = false;
-                                pool_prices[ pool ].sanity = true;
-                            } else {
-                                pool_prices[ pool ].token_updated[ tkn ] = true;
-                                pool_prices[ pool ].sanity = false;
-                            }
-                        },
-                        Pool::Raydium( _ ) => {
-                            // TODO
-                        }
-                    }
+                    pool_prices[ pool ].update( &self.pools[ pool ], &mut raydium_accounts[ pool ],
+                                                tkn, &result );
                 },
                 Err( err ) => {
-                    println!( "Error: {:?}; reinit", err.to_string( ) );
+                    error!( "Error: {:?}; reinit", err.to_string( ) );
                     std::process::exit( 1 )
                 }
             }
         }
     }
 
+    /* Predicts the output of swapping toys_in on the given pool, after slippage and in units
+     * of the output token. None if the pool's price can't be trusted right now. */
+    fn predict_hop( &self, pool_prices: &Vec<PoolPrice>, pool: usize, dir: Direction, toys_in: u128,
+                    config: &Config ) -> Option<u128> {
+        let pool_price = pool_prices[ pool ];
+        if !pool_price.sanity { return None; }
+
+        let ( toys_out, _ ) = pool_price.swap( toys_in, dir, &self.pools[ pool ] );
+        let toys_out = ( toys_out as f64 * ( 1.0 - config.slippage ) ) as u128;
+
+        Some( self.rescale( toys_out, self.pools[ pool ].get_currency( dir ).currency_idx,
+                            self.pools[ pool ].get_currency( dir.flip( ) ).currency_idx ) )
+    }
+
+    /* Logs the best single swap rate between every pair of currencies, for the current balance
+     * and normalized to the decimals of the source; chains of swaps multiply their entries. */
+    fn log_yield_matrix( &self, pool_prices: &Vec<PoolPrice>, gamble_money: u64, config: &Config ) {
+        let n = self.currencies.len( );
+        let mut rates = vec![ vec![ None; n ]; n ];
+        for i in 0 .. self.pools.len( ) {
+            for &w in Direction::BOTH.iter( ) {
+                let from = self.pools[ i ].get_currency( w ).currency_idx;
+                let to = self.pools[ i ].get_currency( w.flip( ) ).currency_idx;
+                let toys_in = self.rescale( gamble_money as u128, self.current_currency, from );
+                if toys_in == 0 { continue; }
+                let toys_out = match self.predict_hop( pool_prices, i, w, toys_in, config ) {
+                    Some( toys_out ) => { toys_out },
+                    None => { continue; }
+                };
+                let rate = self.rescale( toys_out, to, from ) as f64 / toys_in as f64;
+                if rates[ from ][ to ].map_or( true, |r| rate > r ) {
+                    rates[ from ][ to ] = Some( rate );
+                }
+            }
+        }
+
+        debug!( "Best rates (row to column): {}",
+                self.currencies.iter( ).map( |c| c.name.as_str( ) ).collect::<Vec<_>>( ).join( " " ) );
+        for from in 0 .. n {
+            let row: Vec<String> = rates[ from ].iter( ).map( |r| match r {
+                Some( r ) => { format!( "{:.6}", r ) },
+                None => { "-".to_string( ) }
+            } ).collect( );
+            debug!( "{}: {}", self.currencies[ from ].name, row.join( " " ) );
+        }
+    }
+
+    /* Converts an amount of toys between the decimals of two currencies */
+    fn rescale( &self, toys: u128, from: usize, to: usize ) -> u128 {
+        rescale_toys( toys, self.currencies[ from ].decimals as usize,
+                      self.currencies[ to ].decimals as usize )
+    }
+
+    /* Converts toys of one currency into the toys of another worth the same, by the
+     * reference prices; a depegged currency is worth less than its unit count suggests. */
+    fn revalue( &self, toys: u128, from: usize, to: usize ) -> u128 {
+        let toys = self.rescale( toys, from, to );
+        if self.prices[ from ] == self.prices[ to ] {
+            return toys;
+        }
+        ( toys as f64 * self.prices[ from ] / self.prices[ to ] ) as u128
+    }
+
+    /* Rereads the prices of currencies with an oracle once they are older than
+     * ORACLE_REFRESH_INTERVAL; a price that can't be read keeps its previous value. */
+    fn refresh_prices( &mut self, comm: &Communication ) {
+        if self.prices_read.map_or( false, |t| t.elapsed( ) < Duration::from_secs( ORACLE_REFRESH_INTERVAL ) ) {
+            return;
+        }
+        self.prices_read = Some( Instant::now( ) );
+        for i in 0 .. self.currencies.len( ) {
+            let oracle = match self.currencies[ i ].oracle {
+                Some( oracle ) => { oracle },
+                None => { continue; }
+            };
+            match comm.get_oracle_price( &oracle ) {
+                Ok( price ) if price > 0.0 => {
+                    debug!( "{} is at {} USD.", self.currencies[ i ].name, price );
+                    self.prices[ i ] = price;
+                },
+                Ok( price ) => {
+                    warn!( "Ignoring oracle price {} of {}.", price, self.currencies[ i ].name );
+                },
+                Err( err ) => {
+                    warn!( "Reading oracle price of {} failed: {:?}", self.currencies[ i ].name, err );
+                }
+            }
+        }
+    }
+
     fn get_gamble_money( &self, config: &Config ) -> u64 {
         return ( self.money as f64 * config.safety_percentage ) as u64;
     }
@@ -0,0 +1,446 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::mpsc::Sender,
+    time::Duration,
+};
+use solana_sdk::{
+    account::Account as SdkAccount,
+    commitment_config::CommitmentConfig,
+    pubkey::Pubkey,
+};
+use solana_client::{
+    rpc_config::{
+        RpcAccountInfoConfig,
+    },
+    rpc_response::{
+        Response as RpcResponse, RpcResponseContext
+    },
+};
+use solana_rpc::{
+    rpc_pubsub::gen_client::Client as PubsubClient,
+};
+use solana_account_decoder::{
+    UiAccount, UiAccountEncoding
+};
+
+use jsonrpc_core::futures::{
+    StreamExt,
+    channel::mpsc::{ unbounded, UnboundedSender },
+};
+use jsonrpc_client_transports::transports::ws;
+use tokio::{
+    runtime::Runtime,
+    sync::mpsc::UnboundedReceiver,
+    task::JoinHandle,
+};
+use tonic::{
+    Streaming,
+    codec::ProstCodec,
+    codegen::http::uri::PathAndQuery,
+    transport::{ ClientTlsConfig, Endpoint },
+};
+use log::{ info, warn, error };
+
+use crate::{
+    config::*,
+};
+
+use geyser::*;
+
+// Seconds before a broken grpc stream is opened again
+const GRPC_RECONNECT_DELAY: u64 = 5;
+
+// Structs
+
+/* An account update, tagged with the pool's subscription id and the kind PoolPrice::update
+ * expects */
+pub type AccountUpdate = RpcResponse<( usize, usize, UiAccount )>;
+
+/* Tells the subscription task which pools to follow, by subscription id */
+#[derive(Debug)]
+pub enum SubscriptionCommand {
+    Subscribe( usize, Pool ),
+    Unsubscribe( usize ),
+    Reconnect( String ), // the read endpoint failed over to this url
+}
+
+/* Where the print loop gets its account updates from; the updates look the same whatever
+ * the source. */
+pub trait UpdateSource {
+    /* Follows the pools as commanded, sending their updates to sender, until the command
+     * sender is dropped. */
+    fn spawn( self: Box<Self>, rt: &Runtime, commands: UnboundedReceiver<SubscriptionCommand>,
+              sender: Sender<AccountUpdate> );
+}
+
+/* account_subscribe on the cluster's json rpc websocket, one subscription per account */
+pub struct WebsocketSource {
+    cluster_url: String,
+    commitment:  CommitmentConfig,
+}
+
+/* A yellowstone geyser grpc stream carrying the updates of all accounts */
+pub struct GrpcSource {
+    url:        String,
+    token:      Option<String>, // sent as x-token
+    commitment: CommitmentConfig,
+}
+
+/* The parts of the yellowstone geyser protocol (geyser.proto) needed to follow accounts;
+ * fields left out are skipped when decoding. */
+mod geyser {
+    use std::collections::HashMap;
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeRequest {
+        #[prost(map = "string, message", tag = "1")]
+        pub accounts:   HashMap<String, SubscribeRequestFilterAccounts>,
+        #[prost(enumeration = "CommitmentLevel", optional, tag = "6")]
+        pub commitment: Option<i32>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeRequestFilterAccounts {
+        #[prost(string, repeated, tag = "2")]
+        pub account: Vec<String>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum CommitmentLevel {
+        Processed = 0,
+        Confirmed = 1,
+        Finalized = 2,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeUpdate {
+        #[prost(string, repeated, tag = "1")]
+        pub filters:      Vec<String>, // names of the filters the update matched
+        #[prost(oneof = "UpdateOneof", tags = "2")]
+        pub update_oneof: Option<UpdateOneof>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum UpdateOneof {
+        #[prost(message, tag = "2")]
+        Account( SubscribeUpdateAccount ),
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeUpdateAccount {
+        #[prost(message, optional, tag = "1")]
+        pub account: Option<SubscribeUpdateAccountInfo>,
+        #[prost(uint64, tag = "2")]
+        pub slot:    u64,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeUpdateAccountInfo {
+        #[prost(bytes = "vec", tag = "1")]
+        pub pubkey:     Vec<u8>,
+        #[prost(uint64, tag = "2")]
+        pub lamports:   u64,
+        #[prost(bytes = "vec", tag = "3")]
+        pub owner:      Vec<u8>,
+        #[prost(bool, tag = "4")]
+        pub executable: bool,
+        #[prost(uint64, tag = "5")]
+        pub rent_epoch: u64,
+        #[prost(bytes = "vec", tag = "6")]
+        pub data:       Vec<u8>,
+    }
+}
+
+// Implementations
+
+/* The source selected by subscription_mode */
+pub fn update_source( config: &Config ) -> Box<dyn UpdateSource> {
+    let commitment = config.commitment.unwrap_or( CommitmentConfig::confirmed( ) );
+    match config.subscription_mode {
+        SubscriptionMode::Websocket => {
+            Box::new( WebsocketSource{ cluster_url: config.cluster_url.clone( ), commitment: commitment } )
+        },
+        SubscriptionMode::Grpc => {
+            // the config makes sure there is a url in grpc mode
+            Box::new( GrpcSource{ url: config.grpc_url.clone( ).unwrap_or_default( ),
+                                  token: config.grpc_token.clone( ), commitment: commitment } )
+        }
+    }
+}
+
+/* The accounts the pool's price depends on, with the kinds PoolPrice::update expects: token
+ * vaults 0 and 1 (all there is for swap pools); for cpmm pools the pool state 2, which holds
+ * the fees yet to be collected from the vaults; for raydium the amm 2, open orders 3 and serum
+ * market 4; for whirlpools only the whirlpool account 2, which holds liquidity and price. */
+pub fn pool_accounts( pool: &Pool ) -> Vec<( usize, Pubkey )> {
+    match pool {
+        Pool::Swap( _ ) => {
+            Direction::BOTH.iter( ).map( |d| ( d.index( ), pool.get_currency( *d ).account ) ).collect( )
+        },
+        Pool::RaydiumCpmm( pool ) => {
+            vec![
+                ( 0, pool.get_currency( Direction::AtoB ).account ),
+                ( 1, pool.get_currency( Direction::BtoA ).account ),
+                ( 2, pool.account ),
+            ]
+        },
+        Pool::Raydium( pool ) => {
+            vec![
+                ( 0, pool.get_currency( Direction::AtoB ).account ),
+                ( 1, pool.get_currency( Direction::BtoA ).account ),
+                ( 2, pool.account ),
+                ( 3, pool.open_orders ),
+                ( 4, pool.serum_market ),
+            ]
+        },
+        Pool::Whirlpool( pool ) => {
+            vec![ ( 2, pool.account ) ]
+        }
+    }
+}
+
+/* Forwards updates of the account, tagged with the subscription id and kind, to the sender */
+fn subscribe_account( client: &PubsubClient, account: &Pubkey, sid: usize, kind: usize,
+                      commitment: CommitmentConfig, sender: &Sender<AccountUpdate> ) -> JoinHandle<()> {
+    let account_sender = sender.clone( );
+    let mut client_sub = client
+        .account_subscribe(
+            account.to_string( ),
+            Some( RpcAccountInfoConfig {
+                commitment: Some( commitment ),
+                encoding: Some( UiAccountEncoding::Base64Zstd ),
+                ..RpcAccountInfoConfig::default( )
+            } ),
+            ).unwrap_or_else( |err| panic!( "acct sub err: {:#?}", err ) );
+    tokio::spawn( async move {
+        loop {
+            match client_sub.next( ).await {
+                Some( response_ab ) => {
+                    let response = response_ab.unwrap( );
+                    let n_response = solana_client::rpc_response::Response{
+                        context: response.context,
+                        value: ( sid, kind, response.value )
+                    };
+                    account_sender.send( n_response ).unwrap( );
+                }
+                None => { }
+            }
+        }
+    } )
+}
+
+impl UpdateSource for WebsocketSource {
+    fn spawn( self: Box<Self>, rt: &Runtime, mut commands: UnboundedReceiver<SubscriptionCommand>,
+              sender: Sender<AccountUpdate> ) {
+        rt.spawn( async move {
+            let connect = ws::try_connect::<PubsubClient>( &self.cluster_url ).unwrap( );
+            let mut client = connect.await.unwrap( );
+            let subscribe = |client: &PubsubClient, sid: usize, pool: &Pool| -> Vec<JoinHandle<()>> {
+                pool_accounts( pool ).iter( ).map( |( kind, account )| {
+                    subscribe_account( client, account, sid, *kind, self.commitment, &sender )
+                } ).collect( )
+            };
+
+            // Subscribe to account notifications; the pools are kept to subscribe again
+            // after reconnecting
+            let mut pools: HashMap<usize, Pool> = HashMap::new( );
+            let mut subscriptions: HashMap<usize, Vec<JoinHandle<()>>> = HashMap::new( );
+            while let Some( command ) = commands.recv( ).await {
+                match command {
+                    SubscriptionCommand::Subscribe( sid, pool ) => {
+                        subscriptions.insert( sid, subscribe( &client, sid, &pool ) );
+                        pools.insert( sid, pool );
+                    },
+                    SubscriptionCommand::Unsubscribe( sid ) => {
+                        for handle in subscriptions.remove( &sid ).unwrap_or_default( ) {
+                            handle.abort( );
+                        }
+                        pools.remove( &sid );
+                    },
+                    SubscriptionCommand::Reconnect( url ) => {
+                        let connected = match ws::try_connect::<PubsubClient>( &url ) {
+                            Ok( connect ) => { connect.await },
+                            Err( err ) => { Err( err ) }
+                        };
+                        match connected {
+                            Ok( new_client ) => {
+                                info!( "Resubscribing {} pools at {}.", pools.len( ), url );
+                                client = new_client;
+                                for ( _, handles ) in subscriptions.drain( ) {
+                                    for handle in handles {
+                                        handle.abort( );
+                                    }
+                                }
+                                for ( sid, pool ) in &pools {
+                                    subscriptions.insert( *sid, subscribe( &client, *sid, pool ) );
+                                }
+                            },
+                            Err( err ) => {
+                                error!( "Cannot connect to {}, keeping the subscriptions: {:?}", url, err );
+                            }
+                        }
+                    }
+                }
+            }
+        } );
+    }
+}
+
+impl GrpcSource {
+    /* Applies the command to the filters, which are named <sid>/<kind> so updates can be
+     * traced back to their pool. Returns false once the commands ended. */
+    fn apply( command: Option<SubscriptionCommand>,
+              filters: &mut HashMap<String, SubscribeRequestFilterAccounts> ) -> bool {
+        match command {
+            Some( SubscriptionCommand::Subscribe( sid, pool ) ) => {
+                for ( kind, account ) in pool_accounts( &pool ) {
+                    filters.insert( format!( "{}/{}", sid, kind ),
+                                    SubscribeRequestFilterAccounts{ account: vec![ account.to_string( ) ] } );
+                }
+                true
+            },
+            Some( SubscriptionCommand::Unsubscribe( sid ) ) => {
+                let prefix = format!( "{}/", sid );
+                filters.retain( |name, _| !name.starts_with( &prefix ) );
+                true
+            },
+            // the stream is restarted by the caller; the url is the read endpoint's, not ours
+            Some( SubscriptionCommand::Reconnect( _ ) ) => { true },
+            None => { false }
+        }
+    }
+
+    fn request( &self, filters: &HashMap<String, SubscribeRequestFilterAccounts> ) -> SubscribeRequest {
+        let commitment = if self.commitment.is_finalized( ) {
+            CommitmentLevel::Finalized
+        } else if self.commitment.is_confirmed( ) {
+            CommitmentLevel::Confirmed
+        } else {
+            CommitmentLevel::Processed
+        };
+        SubscribeRequest {
+            accounts:   filters.clone( ),
+            commitment: Some( commitment as i32 ),
+        }
+    }
+
+    /* Opens the stream with the given filters; every request sent later replaces them */
+    async fn connect( &self, filters: &HashMap<String, SubscribeRequestFilterAccounts> )
+        -> Result<( UnboundedSender<SubscribeRequest>, Streaming<SubscribeUpdate> ), Box<dyn Error + Send + Sync>> {
+        let mut endpoint = Endpoint::from_shared( self.url.clone( ) )?;
+        if self.url.starts_with( "https" ) {
+            endpoint = endpoint.tls_config( ClientTlsConfig::new( ) )?;
+        }
+        let mut client = tonic::client::Grpc::new( endpoint.connect( ).await? );
+        client.ready( ).await?;
+
+        let ( request_sender, request_receiver ) = unbounded( );
+        request_sender.unbounded_send( self.request( filters ) )?;
+        let mut request = tonic::Request::new( request_receiver );
+        if let Some( token ) = &self.token {
+            request.metadata_mut( ).insert( "x-token", token.parse( )? );
+        }
+        let response = client.streaming( request, PathAndQuery::from_static( "/geyser.Geyser/Subscribe" ),
+                                         ProstCodec::default( ) ).await?;
+        Ok( ( request_sender, response.into_inner( ) ) )
+    }
+
+    /* Passes an account update on, once for every subscription it matched */
+    fn forward( update: SubscribeUpdate, sender: &Sender<AccountUpdate> ) {
+        let ( info, slot ) = match update.update_oneof {
+            Some( UpdateOneof::Account( SubscribeUpdateAccount{ account: Some( info ), slot } ) ) => {
+                ( info, slot )
+            },
+            _ => { return; }
+        };
+        if info.pubkey.len( ) != 32 || info.owner.len( ) != 32 {
+            warn!( "Ignoring grpc update with a malformed pubkey." );
+            return;
+        }
+        let pubkey = Pubkey::new( &info.pubkey );
+        let account = SdkAccount {
+            lamports:   info.lamports,
+            data:       info.data,
+            owner:      Pubkey::new( &info.owner ),
+            executable: info.executable,
+            rent_epoch: info.rent_epoch,
+        };
+        let ui_account = UiAccount::encode( &pubkey, &account, UiAccountEncoding::Base64, None, None );
+
+        for name in &update.filters {
+            let ids: Vec<usize> = name.split( '/' ).filter_map( |s| s.parse( ).ok( ) ).collect( );
+            if let [ sid, kind ] = ids[ .. ] {
+                sender.send( RpcResponse{
+                    context: RpcResponseContext::new( slot ),
+                    value: ( sid, kind, ui_account.clone( ) )
+                } ).unwrap( );
+            }
+        }
+    }
+}
+
+impl UpdateSource for GrpcSource {
+    fn spawn( self: Box<Self>, rt: &Runtime, mut commands: UnboundedReceiver<SubscriptionCommand>,
+              sender: Sender<AccountUpdate> ) {
+        rt.spawn( async move {
+            let mut filters: HashMap<String, SubscribeRequestFilterAccounts> = HashMap::new( );
+            // wait for the first pools, an empty request would follow nothing
+            if !Self::apply( commands.recv( ).await, &mut filters ) {
+                return;
+            }
+
+            loop {
+                while let Ok( command ) = commands.try_recv( ) {
+                    Self::apply( Some( command ), &mut filters );
+                }
+                match self.connect( &filters ).await {
+                    Ok( ( request_sender, mut stream ) ) => {
+                        info!( "Streaming {} accounts from {}.", filters.len( ), self.url );
+                        loop {
+                            tokio::select! {
+                                command = commands.recv( ) => {
+                                    let mut reconnect = matches!( command, Some( SubscriptionCommand::Reconnect( _ ) ) );
+                                    if !Self::apply( command, &mut filters ) {
+                                        return;
+                                    }
+                                    while let Ok( command ) = commands.try_recv( ) {
+                                        reconnect = reconnect || matches!( command, SubscriptionCommand::Reconnect( _ ) );
+                                        Self::apply( Some( command ), &mut filters );
+                                    }
+                                    if reconnect {
+                                        // a stream can stall without failing
+                                        warn!( "Restarting the grpc stream." );
+                                        break;
+                                    }
+                                    if request_sender.unbounded_send( self.request( &filters ) ).is_err( ) {
+                                        warn!( "Grpc stream closed, reconnecting." );
+                                        break;
+                                    }
+                                },
+                                update = stream.message( ) => {
+                                    match update {
+                                        Ok( Some( update ) ) => { Self::forward( update, &sender ); },
+                                        Ok( None ) => {
+                                            warn!( "Grpc stream ended, reconnecting." );
+                                            break;
+                                        },
+                                        Err( status ) => {
+                                            error!( "Grpc stream failed: {}, reconnecting.", status );
+                                            break;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    },
+                    Err( err ) => {
+                        error!( "Connecting to grpc endpoint {} failed: {}", self.url, err );
+                    }
+                }
+                tokio::time::sleep( Duration::from_secs( GRPC_RECONNECT_DELAY ) ).await;
+            }
+        } );
+    }
+}
@@ -0,0 +1,188 @@
+//! Orca whirlpool (concentrated liquidity) state and instructions
+
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use arrayref::{array_ref, array_refs};
+
+/// Lowest and highest sqrt price (Q64.64) the whirlpool program accepts as a swap limit
+pub const MIN_SQRT_PRICE: u128 = 4295048016;
+pub const MAX_SQRT_PRICE: u128 = 79226673515401279992447579055;
+
+/// Anchor discriminator of the swap instruction, sha256( "global:swap" )[ ..8 ]
+const SWAP_DISCRIMINATOR: [ u8; 8 ] = [ 248, 198, 158, 145, 225, 117, 135, 200 ];
+
+/// Number of initializable ticks a tick array account holds
+pub const TICK_ARRAY_SIZE: i32 = 88;
+
+/// The parts of a whirlpool account needed to price swaps within the current tick range.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WhirlpoolInfo {
+    pub tick_spacing:       u16,
+    /// hundredths of a basis point
+    pub fee_rate:           u16,
+    pub liquidity:          u128,
+    /// Q64.64
+    pub sqrt_price:         u128,
+    pub tick_current_index: i32,
+}
+
+impl WhirlpoolInfo {
+    /// Decodes a whirlpool account; the account data starts with the 8 byte anchor
+    /// discriminator, followed by the whirlpools config, bump and tick spacing.
+    pub fn unpack_from_slice( input: &[ u8 ] ) -> Result<WhirlpoolInfo, ProgramError> {
+        if input.len( ) < 85 {
+            return Err( ProgramError::InvalidAccountData );
+        }
+        let input = array_ref![ input, 0, 85 ];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (
+            _discriminator,
+            _whirlpools_config,
+            _whirlpool_bump,
+            tick_spacing,
+            _tick_spacing_seed,
+            fee_rate,
+            _protocol_fee_rate,
+            liquidity,
+            sqrt_price,
+            tick_current_index,
+        ) = array_refs![ input, 8, 32, 1, 2, 2, 2, 2, 16, 16, 4 ];
+        let tick_spacing = u16::from_le_bytes( *tick_spacing );
+        if tick_spacing == 0 {
+            return Err( ProgramError::InvalidAccountData );
+        }
+        Ok( Self {
+            tick_spacing,
+            fee_rate:           u16::from_le_bytes( *fee_rate ),
+            liquidity:          u128::from_le_bytes( *liquidity ),
+            sqrt_price:         u128::from_le_bytes( *sqrt_price ),
+            tick_current_index: i32::from_le_bytes( *tick_current_index ),
+        } )
+    }
+
+    /// Reserves of a constant product pool quoting like this whirlpool as long as the
+    /// price stays within the current tick range, in toys of token a and b. Predictions
+    /// based on them are off once a swap crosses into the next initialized tick.
+    pub fn virtual_reserves( &self ) -> Option<[ u64; 2 ]> {
+        if self.liquidity == 0 || self.sqrt_price == 0 {
+            return None;
+        }
+        let sqrt_price = self.sqrt_price as f64 / 18446744073709551616.0; // 2^64
+        let liquidity = self.liquidity as f64;
+
+        let res = [ liquidity / sqrt_price, liquidity * sqrt_price ];
+        if res[ 0 ] >= u64::MAX as f64 || res[ 1 ] >= u64::MAX as f64 {
+            return None;
+        }
+        Some( [ res[ 0 ] as u64, res[ 1 ] as u64 ] )
+    }
+
+    /// Start tick indices of the three tick arrays a swap from the current tick may cross,
+    /// in the order the swap instruction expects them. Swaps from b to a start looking one
+    /// tick spacing ahead, since a current tick right below an array boundary already
+    /// trades in the next array.
+    pub fn tick_array_starts( &self, a_to_b: bool ) -> [ i32; 3 ] {
+        let ( shift, step ) = if a_to_b { ( 0, -1 ) } else { ( self.tick_spacing as i32, 1 ) };
+        let start = |offset: i32| {
+            tick_array_start_index( self.tick_current_index + shift, self.tick_spacing, offset * step )
+        };
+        [ start( 0 ), start( 1 ), start( 2 ) ]
+    }
+
+    /// Addresses of the tick arrays of tick_array_starts.
+    pub fn tick_arrays( &self, program_id: &Pubkey, whirlpool: &Pubkey, a_to_b: bool ) -> [ Pubkey; 3 ] {
+        let starts = self.tick_array_starts( a_to_b );
+        [ tick_array_address( program_id, whirlpool, starts[ 0 ] ),
+          tick_array_address( program_id, whirlpool, starts[ 1 ] ),
+          tick_array_address( program_id, whirlpool, starts[ 2 ] ) ]
+    }
+}
+
+/// Start tick index of the tick array holding the given tick, moved by offset arrays.
+pub fn tick_array_start_index( tick_index: i32, tick_spacing: u16, offset: i32 ) -> i32 {
+    let ticks_in_array = TICK_ARRAY_SIZE * tick_spacing as i32;
+    ( tick_index.div_euclid( ticks_in_array ) + offset ) * ticks_in_array
+}
+
+/// Address of the whirlpool's tick array starting at the given tick index; the program
+/// derives it from the decimal representation of the index.
+pub fn tick_array_address( program_id: &Pubkey, whirlpool: &Pubkey, start_tick_index: i32 ) -> Pubkey {
+    Pubkey::find_program_address( &[ b"tick_array", whirlpool.as_ref( ),
+                                     start_tick_index.to_string( ).as_bytes( ) ], program_id ).0
+}
+
+/// Creates a whirlpool 'swap' instruction for an exact amount in.
+pub fn swap(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    token_authority: &Pubkey,
+    whirlpool: &Pubkey,
+    token_owner_account_a: &Pubkey,
+    token_vault_a: &Pubkey,
+    token_owner_account_b: &Pubkey,
+    token_vault_b: &Pubkey,
+    tick_arrays: &[ Pubkey; 3 ],
+    oracle: &Pubkey,
+
+    amount_in: u64,
+    minimum_amount_out: u64,
+    a_to_b: bool,
+) -> Result<Instruction, ProgramError> {
+    let mut data = Vec::with_capacity( 42 );
+    data.extend_from_slice( &SWAP_DISCRIMINATOR );
+    data.extend_from_slice( &amount_in.to_le_bytes( ) );
+    data.extend_from_slice( &minimum_amount_out.to_le_bytes( ) );
+    // no price limit besides the minimum out
+    let sqrt_price_limit = if a_to_b { MIN_SQRT_PRICE } else { MAX_SQRT_PRICE };
+    data.extend_from_slice( &sqrt_price_limit.to_le_bytes( ) );
+    data.push( 1 ); // amount_specified_is_input
+    data.push( a_to_b as u8 );
+
+    let accounts = vec![
+        AccountMeta::new_readonly( *token_program_id, false ),
+        AccountMeta::new_readonly( *token_authority, true ),
+        AccountMeta::new( *whirlpool, false ),
+        AccountMeta::new( *token_owner_account_a, false ),
+        AccountMeta::new( *token_vault_a, false ),
+        AccountMeta::new( *token_owner_account_b, false ),
+        AccountMeta::new( *token_vault_b, false ),
+        AccountMeta::new( tick_arrays[ 0 ], false ),
+        AccountMeta::new( tick_arrays[ 1 ], false ),
+        AccountMeta::new( tick_arrays[ 2 ], false ),
+        AccountMeta::new_readonly( *oracle, false ),
+    ];
+
+    Ok( Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    } )
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn at_tick( tick_current_index: i32 ) -> WhirlpoolInfo {
+        WhirlpoolInfo{ tick_spacing: 64, tick_current_index, ..WhirlpoolInfo::default( ) }
+    }
+
+    #[test]
+    fn tick_arrays_follow_the_current_tick( ) {
+        // 88 ticks of spacing 64 per array
+        assert_eq!( at_tick( -100 ).tick_array_starts( true ), [ -5632, -11264, -16896 ] );
+        assert_eq!( at_tick( -100 ).tick_array_starts( false ), [ -5632, 0, 5632 ] );
+        // right below a boundary, swaps upwards already start in the next array
+        assert_eq!( at_tick( 5600 ).tick_array_starts( true ), [ 0, -5632, -11264 ] );
+        assert_eq!( at_tick( 5600 ).tick_array_starts( false ), [ 5632, 11264, 16896 ] );
+
+        let ( program, whirlpool ) = ( Pubkey::new_unique( ), Pubkey::new_unique( ) );
+        let arrays = at_tick( -100 ).tick_arrays( &program, &whirlpool, false );
+        assert_eq!( arrays[ 1 ], tick_array_address( &program, &whirlpool, 0 ) );
+        assert_ne!( arrays[ 0 ], arrays[ 1 ] );
+    }
+}